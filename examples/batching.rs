@@ -0,0 +1,116 @@
+use anyhow::Result;
+use klystron::{
+    DrawType, Engine, FramePacket, HeadlessBackend, Material, Matrix4, Mesh, Object,
+    PerspectiveCamera, Vertex, INSTANCED_VERT, UNLIT_FRAG, UNLIT_VERT,
+};
+use nalgebra::Vector3;
+use std::time::Instant;
+
+/// Objects drawn each frame
+const OBJECTS: usize = 12_000;
+const FRAMES: u32 = 100;
+
+/// Time frames of many objects, spread over a few meshes and materials in no particular order,
+/// first as a baseline with batching off (packet order, a draw call per object) and then with it
+/// on (sorted by material and mesh, consecutive objects merged into instanced draws).
+///
+/// Only materials whose vertex shader reads the instance buffer at binding 2, like
+/// `INSTANCED_VERT`, are batched; the engine checks this when the material is added. Objects
+/// using `UNLIT_VERT` still take a draw call each, and only gain from sorting and from recording
+/// on several threads.
+fn main() -> Result<()> {
+    let mut engine = HeadlessBackend::new("Batching benchmark", 640, 480)?;
+
+    let meshes = (1..=4)
+        .map(|size| {
+            let (vertices, indices) = cube(size as f32 * 0.25);
+            engine.add_mesh(&vertices, &indices)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let runs = [
+        ("unlit", UNLIT_VERT, 1),
+        ("unlit", UNLIT_VERT, 4),
+        ("instanced", INSTANCED_VERT, 1),
+    ];
+    for (name, vertex, threads) in runs.iter() {
+        engine.set_recording_threads(*threads)?;
+        let materials = (0..2)
+            .map(|_| engine.add_material(vertex, UNLIT_FRAG, DrawType::Triangles.into()))
+            .collect::<Result<Vec<_>>>()?;
+        let packet = scene(&materials, &meshes);
+        let camera = PerspectiveCamera::default();
+
+        for &batching in [false, true].iter() {
+            engine.set_draw_batching(batching);
+
+            // Warm up, so pipeline creation and first uploads aren't timed
+            engine.next_frame(&packet, &camera)?;
+
+            let start = Instant::now();
+            for _ in 0..FRAMES {
+                engine.next_frame(&packet, &camera)?;
+            }
+            let per_frame = start.elapsed() / FRAMES;
+
+            let stats = engine.frame_stats();
+            let mode = if batching { "batched" } else { "unbatched" };
+            println!(
+                "{:>9} {:>9}, {} thread(s): {:>8.2?} per frame, {} drawn, {} culled, {} draws",
+                name, mode, threads, per_frame, stats.drawn, stats.culled, stats.draw_calls
+            );
+        }
+
+        for material in materials {
+            engine.remove_material(material)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A grid of objects, shuffled so that neighbours rarely share a material or mesh
+fn scene(materials: &[Material], meshes: &[Mesh]) -> FramePacket {
+    let side = (OBJECTS as f32).sqrt().ceil() as usize;
+    let objects = (0..OBJECTS)
+        .map(|i| {
+            // Cheap scramble of the object's index
+            let hash = (i as u32).wrapping_mul(2654435761) >> 16;
+            let x = (i % side) as f32 / side as f32 * 16. - 8.;
+            let z = (i / side) as f32 / side as f32 * 16. - 8.;
+            Object {
+                material: materials[hash as usize % materials.len()],
+                mesh: meshes[(hash as usize >> 4) % meshes.len()],
+                transform: Matrix4::new_translation(&Vector3::new(x, 0., z))
+                    * Matrix4::new_scaling(0.05),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    FramePacket {
+        objects,
+        ..Default::default()
+    }
+}
+
+fn cube(size: f32) -> (Vec<Vertex>, Vec<u16>) {
+    let s = size;
+    let vertices = vec![
+        Vertex::new([-s, -s, -s], [0.0, 1.0, 1.0]),
+        Vertex::new([s, -s, -s], [1.0, 0.0, 1.0]),
+        Vertex::new([s, s, -s], [1.0, 1.0, 0.0]),
+        Vertex::new([-s, s, -s], [0.0, 1.0, 1.0]),
+        Vertex::new([-s, -s, s], [1.0, 0.0, 1.0]),
+        Vertex::new([s, -s, s], [1.0, 1.0, 0.0]),
+        Vertex::new([s, s, s], [0.0, 1.0, 1.0]),
+        Vertex::new([-s, s, s], [1.0, 0.0, 1.0]),
+    ];
+
+    let indices = vec![
+        3, 1, 0, 2, 1, 3, 2, 5, 1, 6, 5, 2, 6, 4, 5, 7, 4, 6, 7, 0, 4, 3, 0, 7, 7, 2, 3, 6, 2, 7,
+        0, 5, 4, 1, 5, 0,
+    ];
+
+    (vertices, indices)
+}
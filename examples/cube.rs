@@ -16,7 +16,7 @@ impl App for MyApp {
     type Args = ();
 
     fn new(engine: &mut dyn Engine, _args: Self::Args) -> Result<Self> {
-        let material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Triangles)?;
+        let material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Triangles.into())?;
 
         let (vertices, indices) = rainbow_cube();
         let mesh = engine.add_mesh(&vertices, &indices)?;
@@ -34,11 +34,13 @@ impl App for MyApp {
             material: self.material,
             mesh: self.mesh,
             transform,
+            ..Default::default()
         };
         engine.update_time_value(self.time)?;
         self.time += 0.01;
         Ok(FramePacket {
             objects: vec![object],
+            ..Default::default()
         })
     }
 }
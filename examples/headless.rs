@@ -0,0 +1,49 @@
+use anyhow::Result;
+use klystron::{
+    DrawType, Engine, FramePacket, HeadlessBackend, Matrix4, Object, PerspectiveCamera, Vertex,
+    UNLIT_FRAG, UNLIT_VERT,
+};
+
+fn main() -> Result<()> {
+    let mut engine = HeadlessBackend::new("Headless example", 640, 480)?;
+
+    let material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Triangles.into())?;
+    let (vertices, indices) = rainbow_cube();
+    let mesh = engine.add_mesh(&vertices, &indices)?;
+
+    let packet = FramePacket {
+        objects: vec![Object {
+            material,
+            mesh,
+            transform: Matrix4::identity(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    engine.next_frame(&packet, &PerspectiveCamera::default())?;
+    let capture = engine.read_frame()?;
+    capture.save_png("headless.png")?;
+
+    Ok(())
+}
+
+fn rainbow_cube() -> (Vec<Vertex>, Vec<u16>) {
+    let vertices = vec![
+        Vertex::new([-1.0, -1.0, -1.0], [0.0, 1.0, 1.0]),
+        Vertex::new([1.0, -1.0, -1.0], [1.0, 0.0, 1.0]),
+        Vertex::new([1.0, 1.0, -1.0], [1.0, 1.0, 0.0]),
+        Vertex::new([-1.0, 1.0, -1.0], [0.0, 1.0, 1.0]),
+        Vertex::new([-1.0, -1.0, 1.0], [1.0, 0.0, 1.0]),
+        Vertex::new([1.0, -1.0, 1.0], [1.0, 1.0, 0.0]),
+        Vertex::new([1.0, 1.0, 1.0], [0.0, 1.0, 1.0]),
+        Vertex::new([-1.0, 1.0, 1.0], [1.0, 0.0, 1.0]),
+    ];
+
+    let indices = vec![
+        3, 1, 0, 2, 1, 3, 2, 5, 1, 6, 5, 2, 6, 4, 5, 7, 4, 6, 7, 0, 4, 3, 0, 7, 7, 2, 3, 6, 2, 7,
+        0, 5, 4, 1, 5, 0,
+    ];
+
+    (vertices, indices)
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use bytemuck::offset_of;
+use klystron::{
+    runtime_3d::{launch, App},
+    Cull, Engine, EngineExt, FramePacket, Indices, Material, MaterialDesc, Matrix4, Mesh,
+    MeshUsage, Object, VertexAttribute, VertexFormat, VertexLayout, VertexType,
+};
+
+const SCALAR_VERT: &[u8] = include_bytes!("../shaders/scalar.vert.spv");
+const SCALAR_FRAG: &[u8] = include_bytes!("../shaders/scalar.frag.spv");
+
+const SIDE: usize = 128;
+
+/// A 2D position with a scalar value to be colormapped
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Sample {
+    pos: [f32; 2],
+    value: f32,
+}
+
+unsafe impl bytemuck::Zeroable for Sample {}
+unsafe impl bytemuck::Pod for Sample {}
+
+impl VertexType for Sample {
+    fn layout() -> VertexLayout {
+        VertexLayout {
+            stride: std::mem::size_of::<Self>() as u32,
+            attributes: vec![
+                VertexAttribute {
+                    location: 0,
+                    format: VertexFormat::Vec2,
+                    offset: offset_of!(Self, pos) as u32,
+                },
+                VertexAttribute {
+                    location: 1,
+                    format: VertexFormat::Float,
+                    offset: offset_of!(Self, value) as u32,
+                },
+            ],
+        }
+    }
+}
+
+struct ScalarField {
+    material: Material,
+    mesh: Mesh,
+    samples: Vec<Sample>,
+    time: f32,
+}
+
+impl App for ScalarField {
+    const NAME: &'static str = "Scalar field";
+
+    type Args = ();
+
+    fn new(engine: &mut dyn Engine, _args: Self::Args) -> Result<Self> {
+        let material = engine.add_material(
+            SCALAR_VERT,
+            SCALAR_FRAG,
+            MaterialDesc {
+                vertex_layout: Sample::layout(),
+                cull: Cull::None,
+                ..Default::default()
+            },
+        )?;
+
+        let samples = field(0.0);
+        let indices = grid_indices();
+        let mesh = engine.add_custom_mesh(&samples, Indices::U32(&indices), MeshUsage::Dynamic)?;
+
+        Ok(Self {
+            material,
+            mesh,
+            samples,
+            time: 0.0,
+        })
+    }
+
+    fn next_frame(&mut self, engine: &mut dyn Engine) -> Result<FramePacket> {
+        self.time += 0.01;
+        self.samples = field(self.time);
+        engine.update_custom_mesh_vertices(self.mesh, 0, &self.samples)?;
+
+        Ok(FramePacket {
+            objects: vec![Object {
+                material: self.material,
+                mesh: self.mesh,
+                transform: Matrix4::identity(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+}
+
+fn main() -> Result<()> {
+    let vr = std::env::args().skip(1).next().is_some();
+    launch::<ScalarField>(vr, ())
+}
+
+/// Samples of an animated interference pattern over a grid spanning -1..1
+fn field(time: f32) -> Vec<Sample> {
+    let mut samples = Vec::with_capacity(SIDE * SIDE);
+    for i in 0..SIDE {
+        for j in 0..SIDE {
+            let x = i as f32 / (SIDE - 1) as f32 * 2. - 1.;
+            let y = j as f32 / (SIDE - 1) as f32 * 2. - 1.;
+            let r1 = ((x - 0.3).powi(2) + y.powi(2)).sqrt();
+            let r2 = ((x + 0.3).powi(2) + y.powi(2)).sqrt();
+            let value = ((r1 * 20. - time * 3.).sin() + (r2 * 20. - time * 3.).sin()) / 4. + 0.5;
+            samples.push(Sample { pos: [x, y], value });
+        }
+    }
+    samples
+}
+
+/// Two triangles per grid cell
+fn grid_indices() -> Vec<u32> {
+    let mut indices = Vec::with_capacity((SIDE - 1) * (SIDE - 1) * 6);
+    let idx = |i: usize, j: usize| (i * SIDE + j) as u32;
+    for i in 0..SIDE - 1 {
+        for j in 0..SIDE - 1 {
+            indices.extend_from_slice(&[
+                idx(i, j),
+                idx(i + 1, j),
+                idx(i + 1, j + 1),
+                idx(i, j),
+                idx(i + 1, j + 1),
+                idx(i, j + 1),
+            ]);
+        }
+    }
+    indices
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use klystron::{
+    runtime_3d::{launch, App},
+    Background, DrawType, Engine, FramePacket, InstancedObject, Material, Matrix4, Mesh, Vertex,
+    INSTANCED_VERT, UNLIT_FRAG,
+};
+use nalgebra::Vector3;
+
+const SIDE: usize = 40;
+
+const FOG_FRAG: &[u8] = include_bytes!("../shaders/fog.frag.spv");
+const FOG_COLOR: [f32; 4] = [0.2, 0.2, 0.25, 1.];
+
+struct Scatter {
+    material: Material,
+    mesh: Mesh,
+    time: f32,
+}
+
+impl App for Scatter {
+    const NAME: &'static str = "Scatter";
+
+    type Args = ();
+
+    fn new(engine: &mut dyn Engine, _args: Self::Args) -> Result<Self> {
+        // Small cubes alias badly without multisampling
+        let samples = engine.max_msaa_samples().min(4);
+        engine.set_msaa_samples(samples)?;
+
+        let material =
+            engine.add_material(INSTANCED_VERT, UNLIT_FRAG, DrawType::Triangles.into())?;
+
+        // Fade distant cubes into the bottom of the background
+        let fog = engine.add_post_effect(FOG_FRAG)?;
+        let mut params = [0.0; 16];
+        params[..4].copy_from_slice(&FOG_COLOR);
+        params[4] = 0.8;
+        engine.set_post_effect_params(fog, params)?;
+
+        let (vertices, indices) = cube();
+        let mesh = engine.add_mesh(&vertices, &indices)?;
+
+        Ok(Self {
+            mesh,
+            material,
+            time: 0.0,
+        })
+    }
+
+    fn next_frame(&mut self, engine: &mut dyn Engine) -> Result<FramePacket> {
+        let mut transforms = Vec::with_capacity(SIDE * SIDE);
+        let mut colors = Vec::with_capacity(SIDE * SIDE);
+        for i in 0..SIDE {
+            for j in 0..SIDE {
+                let x = i as f32 / SIDE as f32 * 2. - 1.;
+                let z = j as f32 / SIDE as f32 * 2. - 1.;
+                let y = ((x * 5. + self.time).sin() + (z * 3. + self.time).cos()) * 0.1;
+                transforms.push(
+                    Matrix4::new_translation(&Vector3::new(x, y, z)) * Matrix4::new_scaling(0.01),
+                );
+                colors.push([x * 0.5 + 0.5, y * 5. + 0.5, z * 0.5 + 0.5, 1.]);
+            }
+        }
+
+        engine.update_time_value(self.time)?;
+        self.time += 0.01;
+
+        Ok(FramePacket {
+            instanced: vec![InstancedObject {
+                material: self.material,
+                mesh: self.mesh,
+                transforms,
+                colors: Some(colors),
+                params: [0.0; 16],
+            }],
+            background: Background::Gradient {
+                top: [0.05, 0.05, 0.1, 1.],
+                bottom: FOG_COLOR,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+fn main() -> Result<()> {
+    let vr = std::env::args().skip(1).next().is_some();
+    launch::<Scatter>(vr, ())
+}
+
+fn cube() -> (Vec<Vertex>, Vec<u16>) {
+    let color = [1.; 3];
+    let vertices = vec![
+        Vertex::new([-1.0, -1.0, -1.0], color),
+        Vertex::new([1.0, -1.0, -1.0], color),
+        Vertex::new([1.0, 1.0, -1.0], color),
+        Vertex::new([-1.0, 1.0, -1.0], color),
+        Vertex::new([-1.0, -1.0, 1.0], color),
+        Vertex::new([1.0, -1.0, 1.0], color),
+        Vertex::new([1.0, 1.0, 1.0], color),
+        Vertex::new([-1.0, 1.0, 1.0], color),
+    ];
+
+    let indices = vec![
+        3, 1, 0, 2, 1, 3, 2, 5, 1, 6, 5, 2, 6, 4, 5, 7, 4, 6, 7, 0, 4, 3, 0, 7, 7, 2, 3, 6, 2, 7,
+        0, 5, 4, 1, 5, 0,
+    ];
+
+    (vertices, indices)
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use klystron::{
+    runtime_3d::{launch, App},
+    ComputeMaterial, Dispatch, DrawType, Engine, FramePacket, Indices, Material, Mesh, Object,
+    StorageBuffer, Vertex, VertexType, UNLIT_FRAG, UNLIT_VERT,
+};
+
+/// Cells along each side of the grid
+const SIDE: usize = 128;
+/// Workgroup size of the compute shader
+const GROUP_SIZE: usize = 64;
+
+const WAVES_COMP: &[u8] = include_bytes!("../shaders/waves.comp.spv");
+
+struct Waves {
+    material: Material,
+    mesh: Mesh,
+    simulation: ComputeMaterial,
+    vertices: StorageBuffer,
+    cells: StorageBuffer,
+    frame: u32,
+}
+
+impl App for Waves {
+    const NAME: &'static str = "Waves";
+
+    type Args = ();
+
+    fn new(engine: &mut dyn Engine, _args: Self::Args) -> Result<Self> {
+        let material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Triangles.into())?;
+        let simulation = engine.add_compute_material(WAVES_COMP)?;
+
+        // The simulation writes every vertex on the first frame, and owns them from then on
+        let initial = vec![Vertex::default(); SIDE * SIDE];
+        let vertices = engine.add_storage_buffer(bytemuck::cast_slice(&initial))?;
+        // Height and vertical velocity of each cell, at rest
+        let cells = engine.add_storage_buffer(&vec![0; SIDE * SIDE * 8])?;
+
+        let indices = grid_indices();
+        let mesh = engine.add_storage_mesh(&Vertex::layout(), vertices, Indices::U16(&indices))?;
+
+        Ok(Self {
+            material,
+            mesh,
+            simulation,
+            vertices,
+            cells,
+            frame: 0,
+        })
+    }
+
+    fn next_frame(&mut self, _engine: &mut dyn Engine) -> Result<FramePacket> {
+        // A drop lands somewhere new every second or so
+        let drop = match self.frame % 60 {
+            0 => {
+                let t = self.frame as f32 * 0.37;
+                [t.sin() * 0.7, (t * 1.3).cos() * 0.7, 0.05]
+            }
+            _ => [0.0; 3],
+        };
+        self.frame += 1;
+
+        let step = |pass: f32, drop: [f32; 3]| {
+            let mut params = [0.0; 16];
+            params[..4].copy_from_slice(&[SIDE as f32, pass, 0.995, 0.2]);
+            params[4..7].copy_from_slice(&drop);
+            Dispatch {
+                material: self.simulation,
+                buffers: vec![self.vertices, self.cells],
+                groups: [((SIDE * SIDE + GROUP_SIZE - 1) / GROUP_SIZE) as u32, 1, 1],
+                params,
+            }
+        };
+
+        Ok(FramePacket {
+            objects: vec![Object {
+                material: self.material,
+                mesh: self.mesh,
+                ..Default::default()
+            }],
+            dispatches: vec![step(0.0, drop), step(1.0, [0.0; 3])],
+            ..Default::default()
+        })
+    }
+}
+
+fn main() -> Result<()> {
+    let vr = std::env::args().skip(1).next().is_some();
+    launch::<Waves>(vr, ())
+}
+
+/// Two triangles per square between four cells, facing up
+fn grid_indices() -> Vec<u16> {
+    let mut indices = Vec::with_capacity((SIDE - 1) * (SIDE - 1) * 6);
+    for z in 0..SIDE - 1 {
+        for x in 0..SIDE - 1 {
+            let i = (z * SIDE + x) as u16;
+            let side = SIDE as u16;
+            indices.extend_from_slice(&[i, i + side, i + 1, i + 1, i + side, i + side + 1]);
+        }
+    }
+    indices
+}
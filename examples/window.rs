@@ -14,7 +14,7 @@ impl App2D for MyApp {
     type Args = ();
 
     fn new(engine: &mut WinitBackend, _args: Self::Args) -> Result<Self> {
-        let material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Lines)?;
+        let material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Lines.into())?;
 
         let (vertices, indices) = wire_triangle();
         let mesh = engine.add_mesh(&vertices, &indices)?;
@@ -23,6 +23,7 @@ impl App2D for MyApp {
             mesh,
             transform: Matrix4::identity(),
             material,
+            ..Default::default()
         };
 
         Ok(Self {
@@ -52,6 +53,7 @@ impl App2D for MyApp {
     fn frame(&mut self, _engine: &mut WinitBackend) -> Result<FramePacket> {
         Ok(FramePacket {
             objects: vec![self.object],
+            ..Default::default()
         })
     }
 }
@@ -1,2 +1,11 @@
 glslc -O unlit.frag -o unlit.frag.spv
-glslc -O unlit.vert -o unlit.vert.spv
\ No newline at end of file
+glslc -O unlit.vert -o unlit.vert.spv
+glslc -O instanced.vert -o instanced.vert.spv
+glslc -O scalar.vert -o scalar.vert.spv
+glslc -O scalar.frag -o scalar.frag.spv
+glslc -O gradient.vert -o gradient.vert.spv
+glslc -O gradient.frag -o gradient.frag.spv
+glslc -O post.vert -o post.vert.spv
+glslc -O depth_resolve.frag -o depth_resolve.frag.spv
+glslc -O fog.frag -o fog.frag.spv
+glslc -O waves.comp -o waves.comp.spv
\ No newline at end of file
@@ -6,3 +6,12 @@ compile() {
 
 compile unlit.vert
 compile unlit.frag
+compile instanced.vert
+compile scalar.vert
+compile scalar.frag
+compile gradient.vert
+compile gradient.frag
+compile post.vert
+compile depth_resolve.frag
+compile fog.frag
+compile waves.comp
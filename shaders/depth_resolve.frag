@@ -0,0 +1,19 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_EXT_multiview : require
+
+// Copies the first sample of the multisampled depth buffer, so that post-processing passes
+// can sample it like any other texture
+layout(set = 0, binding = 0) uniform texture2DMSArray depthSamples;
+layout(set = 0, binding = 1) uniform sampler nearestSampler;
+
+layout(location = 0) out float outDepth;
+
+void main() {
+    ivec2 coord = ivec2(gl_FragCoord.xy);
+    outDepth = texelFetch(
+        sampler2DMSArray(depthSamples, nearestSampler),
+        ivec3(coord, gl_ViewIndex),
+        0
+    ).r;
+}
@@ -0,0 +1,34 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_EXT_multiview : require
+
+// Post-processing effect which fades distant geometry into a fog color, and darkens the edges
+// of the screen. Fog color in params[0], fog density in params[1].x
+layout(set = 0, binding = 0) uniform texture2DArray color;
+layout(set = 0, binding = 1) uniform texture2DArray depth;
+layout(set = 0, binding = 2) uniform sampler linearSampler;
+layout(set = 0, binding = 3) uniform sampler nearestSampler;
+
+layout(push_constant) uniform Post {
+    vec4 params[4];
+    float time;
+};
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec3 coord = vec3(uv, gl_ViewIndex);
+    vec4 scene = texture(sampler2DArray(color, linearSampler), coord);
+    float d = texture(sampler2DArray(depth, nearestSampler), coord).r;
+
+    // Depth is nonlinear, so raising it to a high power keeps the fog in the distance
+    float fog = clamp(pow(d, 64.0) * params[1].x, 0.0, 1.0);
+    vec3 fogged = mix(scene.rgb, params[0].rgb, fog);
+
+    vec2 centered = uv * 2.0 - 1.0;
+    float vignette = 1.0 - dot(centered, centered) * 0.25;
+
+    outColor = vec4(fogged * vignette, 1.0);
+}
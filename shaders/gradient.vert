@@ -0,0 +1,19 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Top color in params[0], bottom color in params[1]
+layout(push_constant) uniform Model {
+    mat4 model;
+    vec4 params[4];
+};
+
+layout(location = 0) out vec4 fragColor;
+
+void main() {
+    // A single triangle covering the whole screen
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+
+    // The top of the screen is at uv.y = 0
+    fragColor = mix(params[0], params[1], uv.y);
+}
@@ -0,0 +1,16 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
+#include "klystron.glsl"
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+
+layout(location = 0) out vec3 fragColor;
+
+void main() {
+    Instance instance = instances[gl_InstanceIndex];
+    gl_Position = klystron_camera() * instance.transform * vec4(inPosition, 1.0);
+    fragColor = inColor * instance.color.rgb;
+}
@@ -0,0 +1,54 @@
+// Klystron engine interface, for materials. Include it after `#version` and the multiview
+// extension, which the helpers need:
+//
+//     #version 450
+//     #extension GL_EXT_multiview : require
+//     #extension GL_GOOGLE_include_directive : require
+//     #include "klystron.glsl"
+//
+// The include directive extension is only needed by glslc; the engine's own compiler provides
+// this header itself. Declarations here must match the engine (see `core::CameraUbo`), and
+// KLYSTRON_INTERFACE is bumped along with `core::INTERFACE_VERSION` whenever they change; the
+// engine won't build if the two differ.
+#ifndef KLYSTRON_GLSL
+#define KLYSTRON_GLSL
+
+#define KLYSTRON_INTERFACE 1
+
+// View-projection matrix of each eye; only the first is used outside of VR
+layout(set = 0, binding = 0) uniform CameraUbo {
+    mat4 camera[2];
+};
+
+// Value set with `Engine::update_time_value()`
+layout(set = 0, binding = 1) uniform Animation {
+    float anim;
+};
+
+// Per-instance data of instanced objects, indexed by gl_InstanceIndex
+struct Instance {
+    mat4 transform;
+    vec4 color;
+};
+
+layout(std430, set = 0, binding = 2) readonly buffer Instances {
+    Instance instances[];
+};
+
+// Pushed before each object is drawn. Identity model matrix for instanced objects
+layout(push_constant) uniform Model {
+    mat4 model;
+    vec4 params[4];
+};
+
+// View-projection matrix of the eye being drawn
+mat4 klystron_camera() {
+    return camera[gl_ViewIndex];
+}
+
+// Model-view-projection matrix of the current object
+mat4 klystron_mvp() {
+    return camera[gl_ViewIndex] * model;
+}
+
+#endif
@@ -0,0 +1,16 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in float fragValue;
+
+layout(location = 0) out vec4 outColor;
+
+// Cheap blue-to-yellow colormap over 0..1
+vec3 colormap(float t) {
+    t = clamp(t, 0.0, 1.0);
+    return vec3(t * t, 0.2 + 0.7 * t, 0.8 * (1.0 - t) + 0.1);
+}
+
+void main() {
+    outColor = vec4(colormap(fragValue), 1.0);
+}
@@ -0,0 +1,15 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
+#include "klystron.glsl"
+
+layout(location = 0) in vec2 inPosition;
+layout(location = 1) in float inValue;
+
+layout(location = 0) out float fragValue;
+
+void main() {
+    gl_Position = klystron_mvp() * vec4(inPosition.x, 0.0, inPosition.y, 1.0);
+    fragValue = inValue;
+}
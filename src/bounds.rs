@@ -0,0 +1,196 @@
+use crate::vertex::{VertexFormat, VertexLayout};
+use anyhow::{ensure, Result};
+use nalgebra::{Matrix4, Vector4};
+
+/// Axis-aligned box around the vertex positions of a mesh, in model space. Positions are read
+/// from the `vec3` attribute at location 0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Bounds {
+    /// Bounds of the given vertices, or None if there are none or they have no position. Such
+    /// meshes are never culled. Errors if the position doesn't fit in a vertex.
+    pub fn from_vertices(layout: &VertexLayout, vertices: &[u8]) -> Result<Option<Self>> {
+        let position = layout
+            .attributes
+            .iter()
+            .find(|a| a.location == 0 && a.format == VertexFormat::Vec3);
+        let position = match position {
+            Some(position) if layout.stride > 0 => position,
+            _ => return Ok(None),
+        };
+        ensure!(
+            position.fits_in(layout.stride),
+            "Vertex position at offset {} runs past the end of the {} byte vertex",
+            position.offset,
+            layout.stride
+        );
+        let offset = position.offset as usize;
+
+        let mut bounds: Option<Self> = None;
+        for vertex in vertices.chunks_exact(layout.stride as usize) {
+            let mut point = [0.0; 3];
+            for (axis, bytes) in vertex[offset..offset + 12].chunks_exact(4).enumerate() {
+                point[axis] = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            // A box can't be trusted to hold vertices at infinity
+            if !point.iter().all(|c| c.is_finite()) {
+                return Ok(None);
+            }
+            let around = Self {
+                min: point,
+                max: point,
+            };
+            bounds = Some(bounds.map_or(around, |b| b.union(around)));
+        }
+        Ok(bounds)
+    }
+
+    /// Smallest box containing both boxes
+    pub fn union(self, other: Self) -> Self {
+        let mut out = self;
+        for axis in 0..3 {
+            out.min[axis] = out.min[axis].min(other.min[axis]);
+            out.max[axis] = out.max[axis].max(other.max[axis]);
+        }
+        out
+    }
+
+    /// Whether any part of the box may lie inside the view frustum of a model-view-projection
+    /// matrix. Conservative: some boxes just outside a corner of the frustum pass.
+    pub fn in_frustum(&self, mvp: &Matrix4<f32>) -> bool {
+        let mut corners = [Vector4::zeros(); 8];
+        for (idx, corner) in corners.iter_mut().enumerate() {
+            let pick = |axis: usize| match idx >> axis & 1 {
+                0 => self.min[axis],
+                _ => self.max[axis],
+            };
+            *corner = mvp * Vector4::new(pick(0), pick(1), pick(2), 1.0);
+        }
+
+        // The box is outside if all of its corners are beyond the same clip plane. Near is taken
+        // at z = -w, which holds for both OpenGL and Vulkan depth ranges.
+        let beyond = |plane: fn(&Vector4<f32>) -> bool| corners.iter().all(plane);
+        !(beyond(|c| c.x < -c.w)
+            || beyond(|c| c.x > c.w)
+            || beyond(|c| c.y < -c.w)
+            || beyond(|c| c.y > c.w)
+            || beyond(|c| c.z < -c.w)
+            || beyond(|c| c.z > c.w))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::VertexAttribute;
+    use nalgebra::{Point3, Vector3};
+
+    /// Position at `offset` in a vertex of `stride` bytes
+    fn layout(offset: u32, stride: u32) -> VertexLayout {
+        VertexLayout {
+            stride,
+            attributes: vec![VertexAttribute {
+                location: 0,
+                format: VertexFormat::Vec3,
+                offset,
+            }],
+        }
+    }
+
+    fn bytes(floats: &[f32]) -> Vec<u8> {
+        floats
+            .iter()
+            .flat_map(|f| f.to_ne_bytes().to_vec())
+            .collect()
+    }
+
+    fn unit_cube() -> Bounds {
+        Bounds {
+            min: [-1.; 3],
+            max: [1.; 3],
+        }
+    }
+
+    #[test]
+    fn from_vertices() {
+        // Position after a padding float
+        let vertices = bytes(&[9., -1., 2., 0., 9., 3., -4., 5.]);
+        let bounds = Bounds::from_vertices(&layout(4, 16), &vertices).unwrap();
+        assert_eq!(
+            bounds,
+            Some(Bounds {
+                min: [-1., -4., 0.],
+                max: [3., 2., 5.],
+            })
+        );
+
+        assert_eq!(Bounds::from_vertices(&layout(0, 12), &[]).unwrap(), None);
+        let infinite = bytes(&[0., f32::INFINITY, 0.]);
+        assert_eq!(
+            Bounds::from_vertices(&layout(0, 12), &infinite).unwrap(),
+            None
+        );
+
+        let mut no_position = layout(0, 12);
+        no_position.attributes[0].format = VertexFormat::Vec2;
+        let vertices = bytes(&[1., 2., 3.]);
+        assert_eq!(
+            Bounds::from_vertices(&no_position, &vertices).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn position_past_the_stride_is_an_error() {
+        let vertices = bytes(&[0.; 4]);
+        let e = Bounds::from_vertices(&layout(8, 16), &vertices).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Vertex position at offset 8 runs past the end of the 16 byte vertex"
+        );
+    }
+
+    #[test]
+    fn union() {
+        let other = Bounds {
+            min: [0., -2., 0.],
+            max: [3., 0., 0.5],
+        };
+        let expected = Bounds {
+            min: [-1., -2., -1.],
+            max: [3., 1., 1.],
+        };
+        assert_eq!(unit_cube().union(other), expected);
+        assert_eq!(other.union(unit_cube()), expected);
+    }
+
+    #[test]
+    fn in_frustum() {
+        // Looking down -z from z = 10, with a 90 degree field of view
+        let projection = Matrix4::new_perspective(1., std::f32::consts::FRAC_PI_2, 0.1, 100.);
+        let view = Matrix4::look_at_rh(&Point3::new(0., 0., 10.), &Point3::origin(), &Vector3::y());
+        let camera = projection * view;
+        let visible = |x: f32, y: f32, z: f32| {
+            unit_cube().in_frustum(&(camera * Matrix4::new_translation(&Vector3::new(x, y, z))))
+        };
+
+        assert!(visible(0., 0., 0.));
+        // Straddling the edges of the view, and the camera itself
+        assert!(visible(-10.5, 0., 0.));
+        assert!(visible(0., 10.5, 0.));
+        assert!(visible(0., 0., 10.));
+        assert!(visible(0., 0., -89.5));
+
+        // Entirely to one side, behind the camera, or past the far plane
+        assert!(!visible(-12.5, 0., 0.));
+        assert!(!visible(12.5, 0., 0.));
+        assert!(!visible(0., -12.5, 0.));
+        assert!(!visible(0., 12.5, 0.));
+        assert!(!visible(0., 0., 12.));
+        assert!(!visible(0., 0., -92.));
+    }
+}
@@ -0,0 +1,475 @@
+use anyhow::{ensure, format_err, Context, Result};
+use naga::back::spv;
+use naga::front::{glsl, wgsl};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::SourceLocation;
+use std::path::Path;
+
+/// Language of shader source code
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShaderLanguage {
+    /// GLSL 450, as accepted by `glslc`
+    Glsl,
+    /// WGSL. Entry points may have any name, but there must be exactly one for the stage the
+    /// source is compiled for.
+    Wgsl,
+}
+
+/// Pipeline stage a shader is compiled for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+/// Shader source code, to be compiled to SPIR-V in-process
+#[derive(Copy, Clone, Debug)]
+pub struct ShaderSource<'a> {
+    /// What compile errors call the source, usually the path it was read from
+    pub name: &'a str,
+    pub language: ShaderLanguage,
+    pub source: &'a str,
+}
+
+impl<'a> ShaderSource<'a> {
+    pub fn glsl(name: &'a str, source: &'a str) -> Self {
+        Self {
+            name,
+            language: ShaderLanguage::Glsl,
+            source,
+        }
+    }
+
+    pub fn wgsl(name: &'a str, source: &'a str) -> Self {
+        Self {
+            name,
+            language: ShaderLanguage::Wgsl,
+            source,
+        }
+    }
+}
+
+impl From<ShaderStage> for naga::ShaderStage {
+    fn from(stage: ShaderStage) -> Self {
+        match stage {
+            ShaderStage::Vertex => naga::ShaderStage::Vertex,
+            ShaderStage::Fragment => naga::ShaderStage::Fragment,
+            ShaderStage::Compute => naga::ShaderStage::Compute,
+        }
+    }
+}
+
+/// Deepest chain of `#include`s allowed, to catch files which include themselves
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// naga's GLSL frontend doesn't know `gl_ViewIndex`, so it is renamed to this input, which is
+/// declared in place of the `GL_EXT_multiview` directive. The name is just as long, so error
+/// columns don't move.
+const VIEW_INDEX: &str = "kl_ViewIndex";
+
+/// Location of the `VIEW_INDEX` input, which is rebound to the view index built-in once parsed
+const VIEW_INDEX_LOCATION: u32 = 4095;
+
+/// Compile GLSL or WGSL to SPIR-V for the given stage, without any external tools. Errors name
+/// the source and the line and column they occurred at.
+///
+/// GLSL may `#include "klystron.glsl"`, the engine's interface header (see `KLYSTRON_GLSL`), and
+/// other files by path relative to the directory `source.name` is in. Multiview shaders may read
+/// `gl_ViewIndex` after `#extension GL_EXT_multiview : require`. WGSL takes the view index as
+/// `@builtin(view_index) view: i32`.
+pub fn compile_shader(source: ShaderSource, stage: ShaderStage) -> Result<Vec<u8>> {
+    let naga_stage = naga::ShaderStage::from(stage);
+    let expanded = match source.language {
+        ShaderLanguage::Glsl => Expanded::preprocess(source.name, source.source)?,
+        ShaderLanguage::Wgsl => Expanded::plain(source.name, source.source),
+    };
+    let text = expanded.text.as_str();
+
+    let mut module = match source.language {
+        ShaderLanguage::Glsl => glsl::Frontend::default()
+            .parse(&glsl::Options::from(naga_stage), text)
+            .map_err(|errors| {
+                let messages = errors
+                    .iter()
+                    .map(|e| {
+                        let location = e.meta.is_defined().then(|| e.meta.location(text));
+                        expanded.locate(location, e)
+                    })
+                    .collect::<Vec<_>>();
+                format_err!("{}", messages.join("\n"))
+            })?,
+        ShaderLanguage::Wgsl => wgsl::parse_str(text)
+            .map_err(|e| format_err!("{}", expanded.locate(e.location(text), &e)))?,
+    };
+    bind_view_index(&mut module);
+
+    // Pipelines are built with a "main" entry point
+    module.entry_points.retain(|ep| ep.stage == naga_stage);
+    ensure!(
+        module.entry_points.len() == 1,
+        "{}: expected one {:?} entry point, found {}",
+        source.name,
+        stage,
+        module.entry_points.len()
+    );
+    module.entry_points[0].name = "main".into();
+
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|e| {
+            let location = expanded.locate(e.location(text), "invalid shader");
+            anyhow::Error::new(e.into_inner()).context(location)
+        })?;
+
+    // Shaders are written against Vulkan's conventions already, so leave coordinates alone
+    let options = spv::Options {
+        flags: spv::WriterFlags::empty(),
+        ..Default::default()
+    };
+    let words = spv::write_vec(&module, &info, &options, None)
+        .with_context(|| format!("{}: failed to write SPIR-V", source.name))?;
+    Ok(bytemuck::cast_slice(&words).to_vec())
+}
+
+/// Source with its includes pasted in, and the file and line each of its lines came from
+#[derive(Debug)]
+struct Expanded {
+    text: String,
+    origins: Vec<(String, u32)>,
+    /// Whether `VIEW_INDEX` has been declared
+    view_index: bool,
+}
+
+impl Expanded {
+    /// Source which includes nothing
+    fn plain(name: &str, source: &str) -> Self {
+        Self {
+            text: source.to_string(),
+            origins: (1..=source.lines().count() as u32)
+                .map(|line| (name.to_string(), line))
+                .collect(),
+            view_index: false,
+        }
+    }
+
+    /// Expand `#include` directives recursively
+    fn preprocess(name: &str, source: &str) -> Result<Self> {
+        let mut expanded = Self {
+            text: String::with_capacity(source.len()),
+            origins: Vec::new(),
+            view_index: false,
+        };
+        expanded.append(name, source, 0)?;
+        ensure!(
+            expanded.view_index || !contains_word(&expanded.text, VIEW_INDEX),
+            "{}: gl_ViewIndex needs `#extension GL_EXT_multiview : require`",
+            name
+        );
+        Ok(expanded)
+    }
+
+    fn append(&mut self, name: &str, source: &str, depth: usize) -> Result<()> {
+        for (idx, line) in source.lines().enumerate() {
+            let number = idx as u32 + 1;
+            let directive = line.trim_start();
+
+            // glslc needs this extension to accept includes; naga doesn't know it. The multiview
+            // extension makes way for the input standing in for the view index.
+            let extension =
+                |name: &str| directive.starts_with("#extension") && directive.contains(name);
+            let line = if extension("GL_GOOGLE_include_directive") {
+                String::new()
+            } else if extension("GL_EXT_multiview") && !self.view_index {
+                self.view_index = true;
+                format!(
+                    "layout(location = {}) in int {};",
+                    VIEW_INDEX_LOCATION, VIEW_INDEX
+                )
+            } else {
+                replace_word(line, "gl_ViewIndex", VIEW_INDEX)
+            };
+
+            if let Some(target) = directive.strip_prefix("#include") {
+                let target = target
+                    .trim()
+                    .trim_matches(|c| c == '"' || c == '<' || c == '>');
+                ensure!(
+                    depth < MAX_INCLUDE_DEPTH,
+                    "{}:{}: includes are nested more than {} deep",
+                    name,
+                    number,
+                    MAX_INCLUDE_DEPTH
+                );
+                let (included_name, included) =
+                    resolve_include(name, target).with_context(|| {
+                        format!("{}:{}: failed to include {}", name, number, target)
+                    })?;
+                self.append(&included_name, &included, depth + 1)?;
+                continue;
+            }
+
+            self.text.push_str(&line);
+            self.text.push('\n');
+            self.origins.push((name.to_string(), number));
+        }
+        Ok(())
+    }
+
+    /// Prefix a message with the file, line and column a location in the text came from
+    fn locate(&self, location: Option<SourceLocation>, message: impl std::fmt::Display) -> String {
+        let origin = location.and_then(|loc| {
+            let (name, line) = self.origins.get(loc.line_number as usize - 1)?;
+            Some((name, line, loc.line_position))
+        });
+        match origin {
+            Some((name, line, column)) => format!("{}:{}:{}: {}", name, line, column, message),
+            None => match self.origins.first() {
+                Some((name, _)) => format!("{}: {}", name, message),
+                None => message.to_string(),
+            },
+        }
+    }
+}
+
+/// Make the input standing in for `gl_ViewIndex` the view index built-in it stands for
+fn bind_view_index(module: &mut naga::Module) {
+    let arguments = module
+        .entry_points
+        .iter_mut()
+        .flat_map(|ep| ep.function.arguments.iter_mut());
+    for argument in arguments {
+        if let Some(naga::Binding::Location { location, .. }) = argument.binding {
+            if location == VIEW_INDEX_LOCATION {
+                argument.binding = Some(naga::Binding::BuiltIn(naga::BuiltIn::ViewIndex));
+            }
+        }
+    }
+}
+
+/// Whether `text` contains `word` as an identifier rather than part of a longer one
+fn contains_word(text: &str, word: &str) -> bool {
+    word_matches(text, word).next().is_some()
+}
+
+/// Replace every occurrence of the identifier `word` in a line
+fn replace_word(line: &str, word: &str, with: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = 0;
+    for at in word_matches(line, word) {
+        out.push_str(&line[rest..at]);
+        out.push_str(with);
+        rest = at + word.len();
+    }
+    out.push_str(&line[rest..]);
+    out
+}
+
+/// Byte offsets of each occurrence of the identifier `word`
+fn word_matches<'a>(text: &'a str, word: &'a str) -> impl Iterator<Item = usize> + 'a {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(word)
+        .map(|(at, _)| at)
+        .filter(move |&at| {
+            let before = text[..at].chars().next_back();
+            let after = text[at + word.len()..].chars().next();
+            !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+        })
+}
+
+/// Name and contents of an included file. The engine header is built in, and anything else is
+/// read relative to the including file.
+fn resolve_include(includer: &str, target: &str) -> Result<(String, String)> {
+    if target == "klystron.glsl" {
+        return Ok((target.to_string(), crate::KLYSTRON_GLSL.to_string()));
+    }
+    let path = Path::new(includer)
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(target);
+    let contents = std::fs::read_to_string(&path)?;
+    Ok((path.display().to_string(), contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every shader source shipped in `shaders/`, with the stage it is built for
+    const SHIPPED: &[(&str, ShaderStage)] = &[
+        ("depth_resolve.frag", ShaderStage::Fragment),
+        ("fog.frag", ShaderStage::Fragment),
+        ("gradient.frag", ShaderStage::Fragment),
+        ("gradient.vert", ShaderStage::Vertex),
+        ("instanced.vert", ShaderStage::Vertex),
+        ("post.vert", ShaderStage::Vertex),
+        ("scalar.frag", ShaderStage::Fragment),
+        ("scalar.vert", ShaderStage::Vertex),
+        ("unlit.frag", ShaderStage::Fragment),
+        ("unlit.vert", ShaderStage::Vertex),
+        ("waves.comp", ShaderStage::Compute),
+    ];
+
+    fn read_shipped(file: &str) -> (String, String) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("shaders")
+            .join(file);
+        let source = std::fs::read_to_string(&path).unwrap();
+        (path.display().to_string(), source)
+    }
+
+    #[test]
+    fn shipped_shaders_compile() {
+        for &(file, stage) in SHIPPED {
+            let (name, source) = read_shipped(file);
+            if let Err(e) = compile_shader(ShaderSource::glsl(&name, &source), stage) {
+                panic!("{:#}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn view_index_enables_multiview() {
+        // OpCapability MultiView
+        let capability = [2 << 16 | 17, 4439];
+        for &(file, stage) in SHIPPED {
+            let (name, source) = read_shipped(file);
+            if !source.contains("gl_ViewIndex") {
+                continue;
+            }
+            let spirv = compile_shader(ShaderSource::glsl(&name, &source), stage).unwrap();
+            let words = spirv
+                .chunks_exact(4)
+                .map(|w| u32::from_ne_bytes([w[0], w[1], w[2], w[3]]))
+                .collect::<Vec<_>>();
+            assert!(words.windows(2).any(|pair| pair == capability), "{}", file);
+        }
+    }
+
+    const HEADER_VERT: &str = "#version 450
+#extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
+#include \"klystron.glsl\"
+
+layout(location = 0) in vec3 inPosition;
+
+void main() {
+    gl_Position = klystron_mvp() * vec4(inPosition, 1.0);
+}
+";
+
+    #[test]
+    fn header_compiles() {
+        compile_shader(
+            ShaderSource::glsl("header.vert", HEADER_VERT),
+            ShaderStage::Vertex,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_including_file() {
+        let source = HEADER_VERT.replace("inPosition, 1.0", "foo, 1.0");
+        let e = compile_shader(ShaderSource::glsl("foo.vert", &source), ShaderStage::Vertex)
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("foo.vert:9:"), "{}", e);
+        assert!(e.contains("foo"), "{}", e);
+    }
+
+    #[test]
+    fn includes_keep_their_origins() {
+        let source = "#extension GL_GOOGLE_include_directive : require\n\
+                      #extension GL_EXT_multiview : require\n\
+                      #include <klystron.glsl>\n\
+                      void main() {}\n";
+        let expanded = Expanded::preprocess("main.vert", source).unwrap();
+        let header_lines = crate::KLYSTRON_GLSL.lines().count();
+
+        assert_eq!(expanded.origins.len(), header_lines + 3);
+        assert_eq!(expanded.origins[1], ("main.vert".to_string(), 2));
+        assert_eq!(expanded.origins[2], ("klystron.glsl".to_string(), 1));
+        assert_eq!(
+            expanded.origins[header_lines + 2],
+            ("main.vert".to_string(), 4)
+        );
+
+        // The include extension is dropped, leaving its line blank, and the multiview extension
+        // declares the view index
+        let mut lines = expanded.text.lines();
+        assert_eq!(lines.next(), Some(""));
+        assert_eq!(
+            lines.next(),
+            Some("layout(location = 4095) in int kl_ViewIndex;")
+        );
+        assert_eq!(lines.last(), Some("void main() {}"));
+    }
+
+    #[test]
+    fn view_index_is_renamed() {
+        let source = "#extension GL_EXT_multiview : require\n\
+                      int a = gl_ViewIndex + my_gl_ViewIndex + gl_ViewIndex2;\n";
+        let expanded = Expanded::preprocess("main.frag", source).unwrap();
+        assert_eq!(
+            expanded.text.lines().nth(1),
+            Some("int a = kl_ViewIndex + my_gl_ViewIndex + gl_ViewIndex2;")
+        );
+
+        let e = Expanded::preprocess("main.frag", "int a = gl_ViewIndex;").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "main.frag: gl_ViewIndex needs `#extension GL_EXT_multiview : require`"
+        );
+    }
+
+    #[test]
+    fn wgsl_view_index_compiles() {
+        let source = "@fragment
+fn main(@builtin(view_index) view: i32) -> @location(0) vec4<f32> {
+    return vec4<f32>(f32(view), 0.0, 0.0, 1.0);
+}
+";
+        let spirv = compile_shader(
+            ShaderSource::wgsl("view.wgsl", source),
+            ShaderStage::Fragment,
+        );
+        spirv.unwrap();
+    }
+
+    #[test]
+    fn includes_resolve_relative_to_the_includer() {
+        let dir = std::env::temp_dir().join(format!("klystron-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/common.glsl"), "#include \"inner.glsl\"\n").unwrap();
+        std::fs::write(dir.join("lib/inner.glsl"), "float inner;\n").unwrap();
+        std::fs::write(dir.join("lib/self.glsl"), "#include \"self.glsl\"\n").unwrap();
+        let includer = dir.join("main.vert").display().to_string();
+
+        let (name, contents) = resolve_include(&includer, "lib/common.glsl").unwrap();
+        assert_eq!(name, dir.join("lib/common.glsl").display().to_string());
+        assert_eq!(contents, "#include \"inner.glsl\"\n");
+
+        // Nested includes are relative to the file they're in
+        let expanded = Expanded::preprocess(&includer, "#include \"lib/common.glsl\"").unwrap();
+        assert_eq!(expanded.text, "float inner;\n");
+        let inner = dir.join("lib").join("inner.glsl").display().to_string();
+        assert_eq!(expanded.origins, vec![(inner, 1)]);
+
+        let e = Expanded::preprocess(&includer, "#include \"lib/self.glsl\"").unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("includes are nested more than 16 deep"),
+            "{:#}",
+            e
+        );
+
+        let e = Expanded::preprocess(&includer, "\n#include \"missing.glsl\"").unwrap_err();
+        assert!(
+            format!("{:#}", e)
+                .starts_with(&format!("{}:2: failed to include missing.glsl", includer)),
+            "{:#}",
+            e
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+use crate::core::{AllocatedBuffer, FRAMES_IN_FLIGHT};
+use crate::staging;
+use crate::MAX_DISPATCH_BUFFERS;
+use anyhow::{ensure, Result};
+use erupt::{utils, vk1_0 as vk};
+use gpu_alloc::UsageFlags as UF;
+use slotmap::SlotMap;
+use std::ffi::CString;
+use vk_core::SharedCore;
+
+/// Number of dispatches each frame's descriptor pool starts out with room for
+const INITIAL_CAPACITY: usize = 16;
+
+/// Push constants of every dispatch
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DispatchConstants {
+    pub params: crate::ObjectParams,
+    pub time: f32,
+}
+
+unsafe impl bytemuck::Zeroable for DispatchConstants {}
+unsafe impl bytemuck::Pod for DispatchConstants {}
+
+/// A device-local buffer shared by every frame, which compute shaders read and write and meshes
+/// may draw their vertices from
+pub struct StorageBuffer {
+    pub buffer: AllocatedBuffer,
+    pub size: u64,
+}
+
+/// A compute pipeline
+pub struct ComputeMaterial {
+    pipeline: vk::Pipeline,
+    prelude: SharedCore,
+}
+
+/// Compute materials, storage buffers, and the descriptor sets each frame's dispatches bind
+pub struct Compute {
+    pub materials: SlotMap<crate::ComputeMaterial, ComputeMaterial>,
+    pub buffers: SlotMap<crate::StorageBuffer, StorageBuffer>,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    /// One descriptor pool per frame in flight, and the number of dispatches it has room for
+    pools: Vec<(vk::DescriptorPool, usize)>,
+    cache: vk::PipelineCache,
+    prelude: SharedCore,
+}
+
+impl Compute {
+    pub fn new(prelude: SharedCore, cache: vk::PipelineCache) -> Result<Self> {
+        let bindings = (0..MAX_DISPATCH_BUFFERS as u32)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBindingBuilder::new()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            })
+            .collect::<Vec<_>>();
+        let create_info = vk::DescriptorSetLayoutCreateInfoBuilder::new().bindings(&bindings);
+        let set_layout = unsafe {
+            prelude
+                .device
+                .create_descriptor_set_layout(&create_info, None)
+        }
+        .result()?;
+
+        let set_layouts = [set_layout];
+        let push_constant_ranges = [vk::PushConstantRangeBuilder::new()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .offset(0)
+            .size(std::mem::size_of::<DispatchConstants>() as u32)];
+        let create_info = vk::PipelineLayoutCreateInfoBuilder::new()
+            .push_constant_ranges(&push_constant_ranges)
+            .set_layouts(&set_layouts);
+        let pipeline_layout =
+            unsafe { prelude.device.create_pipeline_layout(&create_info, None) }.result()?;
+
+        let mut pools = Vec::with_capacity(FRAMES_IN_FLIGHT);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            pools.push((create_pool(&prelude, INITIAL_CAPACITY)?, INITIAL_CAPACITY));
+        }
+
+        Ok(Self {
+            materials: SlotMap::with_key(),
+            buffers: SlotMap::with_key(),
+            set_layout,
+            pipeline_layout,
+            pools,
+            cache,
+            prelude,
+        })
+    }
+
+    pub fn add_material(&mut self, compute_src: &[u8]) -> Result<crate::ComputeMaterial> {
+        let decoded = utils::decode_spv(compute_src)?;
+        let create_info = vk::ShaderModuleCreateInfoBuilder::new().code(&decoded);
+        let module =
+            unsafe { self.prelude.device.create_shader_module(&create_info, None) }.result()?;
+
+        let entry_point = CString::new("main")?;
+        let stage = vk::PipelineShaderStageCreateInfoBuilder::new()
+            .stage(vk::ShaderStageFlagBits::COMPUTE)
+            .module(module)
+            .name(&entry_point)
+            .build();
+        let create_info = vk::ComputePipelineCreateInfoBuilder::new()
+            .stage(stage)
+            .layout(self.pipeline_layout);
+
+        let pipeline = unsafe {
+            self.prelude
+                .device
+                .create_compute_pipelines(Some(self.cache), &[create_info], None)
+        }
+        .result();
+
+        unsafe {
+            self.prelude
+                .device
+                .destroy_shader_module(Some(module), None);
+        }
+
+        Ok(self.materials.insert(ComputeMaterial {
+            pipeline: pipeline?[0],
+            prelude: self.prelude.clone(),
+        }))
+    }
+
+    pub fn add_buffer(
+        &mut self,
+        command_pool: vk::CommandPool,
+        data: &[u8],
+    ) -> Result<crate::StorageBuffer> {
+        ensure!(!data.is_empty(), "Storage buffers may not be empty");
+        let size = data.len() as u64;
+        let buffer = AllocatedBuffer::new(
+            &self.prelude,
+            size,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::VERTEX_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            UF::FAST_DEVICE_ACCESS,
+        )?;
+        let mut buffer = StorageBuffer { buffer, size };
+        if let Err(e) = buffer.write(&self.prelude, command_pool, 0, data) {
+            buffer.buffer.free(&self.prelude)?;
+            return Err(e);
+        }
+        Ok(self.buffers.insert(buffer))
+    }
+
+    /// Overwrite part of a storage buffer. It must not be in use.
+    pub fn update_buffer(
+        &mut self,
+        command_pool: vk::CommandPool,
+        id: crate::StorageBuffer,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        match self.buffers.get_mut(id) {
+            Some(buffer) => buffer.write(&self.prelude, command_pool, offset, data),
+            None => Err(anyhow::format_err!("Storage buffer does not exist")),
+        }
+    }
+
+    /// Record every dispatch in order, each seeing the writes of those before it. Vertex input
+    /// later in the command buffer sees the writes of all of them. The frame must not be in
+    /// flight.
+    pub fn record(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_idx: usize,
+        dispatches: &[crate::Dispatch],
+        time: f32,
+    ) -> Result<()> {
+        if dispatches.is_empty() {
+            return Ok(());
+        }
+
+        let sets = self.allocate_sets(frame_idx, dispatches.len())?;
+
+        // The previous frame may still be drawing from buffers these dispatches write, and its
+        // own dispatches may have written buffers these read
+        self.barrier(
+            command_buffer,
+            vk::PipelineStageFlags::VERTEX_INPUT | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        );
+
+        for (dispatch, set) in dispatches.iter().zip(sets) {
+            let material = match self.materials.get(dispatch.material) {
+                Some(m) => m,
+                None => {
+                    log::error!("Dispatch references a compute material that no longer exists");
+                    continue;
+                }
+            };
+            if dispatch.buffers.len() > MAX_DISPATCH_BUFFERS {
+                log::error!(
+                    "Dispatches may bind at most {} buffers; skipping",
+                    MAX_DISPATCH_BUFFERS
+                );
+                continue;
+            }
+            let buffers = match dispatch
+                .buffers
+                .iter()
+                .map(|id| self.buffers.get(*id))
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(b) => b,
+                None => {
+                    log::error!("Dispatch references a storage buffer that no longer exists");
+                    continue;
+                }
+            };
+
+            let buffer_infos = buffers
+                .iter()
+                .map(|buffer| {
+                    [vk::DescriptorBufferInfoBuilder::new()
+                        .buffer(buffer.buffer.buffer)
+                        .offset(0)
+                        .range(vk::WHOLE_SIZE)]
+                })
+                .collect::<Vec<_>>();
+            let writes = buffer_infos
+                .iter()
+                .enumerate()
+                .map(|(binding, info)| {
+                    vk::WriteDescriptorSetBuilder::new()
+                        .buffer_info(info)
+                        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                        .dst_set(set)
+                        .dst_binding(binding as u32)
+                        .dst_array_element(0)
+                })
+                .collect::<Vec<_>>();
+
+            let constants = DispatchConstants {
+                params: dispatch.params,
+                time,
+            };
+            let [x, y, z] = dispatch.groups;
+
+            unsafe {
+                let device = &self.prelude.device;
+                device.update_descriptor_sets(&writes, &[]);
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    material.pipeline,
+                );
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout,
+                    0,
+                    &[set],
+                    &[],
+                );
+                device.cmd_push_constants(
+                    command_buffer,
+                    self.pipeline_layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    std::mem::size_of::<DispatchConstants>() as u32,
+                    &constants as *const DispatchConstants as _,
+                );
+                device.cmd_dispatch(command_buffer, x, y, z);
+            }
+
+            // Later dispatches and vertex input see this one's writes
+            self.barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::AccessFlags::SHADER_READ
+                    | vk::AccessFlags::SHADER_WRITE
+                    | vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Storage buffer to draw a mesh's vertices from, if it exists
+    pub fn vertex_buffer(&self, id: crate::StorageBuffer) -> Option<vk::Buffer> {
+        self.buffers.get(id).map(|buffer| buffer.buffer.buffer)
+    }
+
+    /// Reset this frame's descriptor pool, growing it if needed, and allocate `count` sets
+    fn allocate_sets(&mut self, frame_idx: usize, count: usize) -> Result<Vec<vk::DescriptorSet>> {
+        let (pool, capacity) = &mut self.pools[frame_idx];
+        if count > *capacity {
+            *capacity = count.next_power_of_two();
+            let old = std::mem::replace(pool, create_pool(&self.prelude, *capacity)?);
+            unsafe {
+                self.prelude.device.destroy_descriptor_pool(Some(old), None);
+            }
+        } else {
+            unsafe { self.prelude.device.reset_descriptor_pool(*pool, None) }.result()?;
+        }
+
+        let layouts = vec![self.set_layout; count];
+        let create_info = vk::DescriptorSetAllocateInfoBuilder::new()
+            .descriptor_pool(*pool)
+            .set_layouts(&layouts);
+        let sets =
+            unsafe { self.prelude.device.allocate_descriptor_sets(&create_info) }.result()?;
+        Ok(sets.to_vec())
+    }
+
+    /// Global memory barrier
+    fn barrier(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        src_stage: vk::PipelineStageFlags,
+        src_access: vk::AccessFlags,
+        dst_stage: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) {
+        let barriers = [vk::MemoryBarrierBuilder::new()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)];
+        unsafe {
+            self.prelude.device.cmd_pipeline_barrier(
+                command_buffer,
+                src_stage,
+                dst_stage,
+                None,
+                &barriers,
+                &[],
+                &[],
+            );
+        }
+    }
+}
+
+impl Drop for Compute {
+    fn drop(&mut self) {
+        self.materials.clear();
+        for (_, buffer) in self.buffers.drain() {
+            buffer.buffer.free(&self.prelude).unwrap();
+        }
+        unsafe {
+            let device = &self.prelude.device;
+            for (pool, _) in self.pools.drain(..) {
+                device.destroy_descriptor_pool(Some(pool), None);
+            }
+            device.destroy_pipeline_layout(Some(self.pipeline_layout), None);
+            device.destroy_descriptor_set_layout(Some(self.set_layout), None);
+        }
+    }
+}
+
+impl StorageBuffer {
+    fn write(
+        &mut self,
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        ensure!(
+            offset + data.len() as u64 <= self.size,
+            "Storage buffer write of {} bytes at offset {} is out of range; the buffer is {} bytes",
+            data.len(),
+            offset,
+            self.size
+        );
+        staging::upload_buffer(
+            prelude,
+            command_pool,
+            self.buffer.buffer,
+            offset,
+            data,
+            vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::VERTEX_INPUT,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+        )
+    }
+}
+
+impl Drop for ComputeMaterial {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude
+                .device
+                .destroy_pipeline(Some(self.pipeline), None);
+        }
+    }
+}
+
+/// Descriptor pool with room for `capacity` dispatches
+fn create_pool(prelude: &SharedCore, capacity: usize) -> Result<vk::DescriptorPool> {
+    let pool_sizes = [vk::DescriptorPoolSizeBuilder::new()
+        ._type(vk::DescriptorType::STORAGE_BUFFER)
+        .descriptor_count((capacity * MAX_DISPATCH_BUFFERS) as u32)];
+    let create_info = vk::DescriptorPoolCreateInfoBuilder::new()
+        .pool_sizes(&pool_sizes)
+        .max_sets(capacity as u32);
+    Ok(unsafe { prelude.device.create_descriptor_pool(&create_info, None) }.result()?)
+}
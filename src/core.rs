@@ -1,10 +1,26 @@
+use crate::compile::ShaderStage;
+use crate::compute::Compute;
+use crate::deletion_queue::{DeletionQueue, Retired};
 use crate::frame_sync::FrameSync;
-use crate::material::Material;
+use crate::instances::{instance_offsets, InstanceBuffers, InstanceData, INSTANCE_BINDING};
+use crate::material::{Material, PushConstants, PUSH_CONSTANT_STAGES};
+use crate::mesh::Mesh;
+use crate::pipeline_cache::PipelineCache;
+use crate::post::{PostChain, INTERMEDIATE_FORMAT};
+use crate::recorder::Recorder;
 use crate::swapchain_images::{SwapChainImage, SwapchainImages};
-use crate::vertex::Vertex;
-use anyhow::Result;
+use crate::material_set::create_material_set_layout;
+use crate::texture::{Samplers, Texture};
+use crate::vertex::VertexLayout;
+use crate::watch::{load_shader, ShaderWatcher};
+use crate::Indices;
+use nalgebra::{Matrix4, Vector4};
+use anyhow::{ensure, format_err, Result};
 use erupt::{vk1_0 as vk, vk1_1, DeviceLoader};
 use slotmap::SlotMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
 use vk_core::SharedCore;
 use gpu_alloc_erupt::EruptMemoryDevice;
 
@@ -12,18 +28,162 @@ pub(crate) const FRAMES_IN_FLIGHT: usize = 2;
 pub(crate) const COLOR_FORMAT: vk::Format = vk::Format::B8G8R8A8_SRGB;
 pub(crate) const DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
 
+const GRADIENT_VERT: &[u8] = include_bytes!("../shaders/gradient.vert.spv");
+const GRADIENT_FRAG: &[u8] = include_bytes!("../shaders/gradient.frag.spv");
+
 pub type CameraUbo = [f32; 32];
 
+/// Version of the shader interface described by `KLYSTRON_GLSL`. Bump it together with the
+/// header's `KLYSTRON_INTERFACE` whenever the camera, instance or push constant layouts change.
+pub const INTERFACE_VERSION: u32 = 1;
+
+// Keep the header and the layouts above from drifting apart
+const _: () = assert!(header_version(crate::KLYSTRON_GLSL.as_bytes()) == INTERFACE_VERSION);
+const _: () = assert!(std::mem::size_of::<CameraUbo>() == 2 * 64);
+const _: () = assert!(std::mem::size_of::<PushConstants>() == 64 + 4 * 16);
+const _: () = assert!(std::mem::size_of::<InstanceData>() == 64 + 16);
+
+/// Value of `#define KLYSTRON_INTERFACE` in a header, or 0 if it has none
+const fn header_version(header: &[u8]) -> u32 {
+    const DEFINE: &[u8] = b"#define KLYSTRON_INTERFACE ";
+    let mut i = 0;
+    while i + DEFINE.len() <= header.len() {
+        let mut j = 0;
+        while j < DEFINE.len() && header[i + j] == DEFINE[j] {
+            j += 1;
+        }
+        if j == DEFINE.len() {
+            let mut version = 0;
+            let mut k = i + j;
+            while k < header.len() && header[k].is_ascii_digit() {
+                version = version * 10 + (header[k] - b'0') as u32;
+                k += 1;
+            }
+            return version;
+        }
+        i += 1;
+    }
+    0
+}
+
+/// What kind of image the render pass draws into
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RenderTarget {
+    /// Swapchain images presented to a window surface
+    Windowed,
+    /// Two-layer multiview images handed to OpenXR
+    Vr,
+    /// Images owned by the engine, which are copied back to the host after rendering
+    Offscreen,
+}
+
+impl RenderTarget {
+    /// Layout the color attachment is left in at the end of the render pass
+    pub fn final_layout(self) -> vk::ImageLayout {
+        match self {
+            RenderTarget::Windowed => vk::ImageLayout::PRESENT_SRC_KHR,
+            RenderTarget::Vr => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            RenderTarget::Offscreen => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        }
+    }
+
+    /// Whether this target renders both eyes at once
+    pub fn is_vr(self) -> bool {
+        self == RenderTarget::Vr
+    }
+}
+
+/// Everything the render passes and frame attachments depend on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PassConfig {
+    pub target: RenderTarget,
+    /// MSAA samples per pixel of the render pass and every material
+    pub samples: vk::SampleCountFlagBits,
+    /// Whether the color attachment is kept between frames, for `Background::Keep`
+    pub keep_color: bool,
+    /// Whether objects are drawn into an intermediate image for post-processing
+    pub post: bool,
+}
+
+impl PassConfig {
+    pub fn msaa(&self) -> bool {
+        self.samples != vk::SampleCountFlagBits::_1
+    }
+
+    /// Array layers of every attachment; two for VR
+    pub fn layers(&self) -> u32 {
+        if self.target.is_vr() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Format of the color attachment objects are drawn into
+    pub fn scene_format(&self) -> vk::Format {
+        match self.post {
+            true => INTERMEDIATE_FORMAT,
+            false => COLOR_FORMAT,
+        }
+    }
+
+    /// Whether objects are drawn into a persistent single-sampled image, which is copied into
+    /// the target afterwards
+    pub fn copy_to_target(&self) -> bool {
+        self.keep_color && !self.msaa() && !self.post
+    }
+}
+
 // TODO: yes, I know this is a bad way to do things.
 pub struct AllocatedBuffer {
-    buffer: vk::Buffer,
-    memory: gpu_alloc::MemoryBlock<vk::DeviceMemory>,
+    pub buffer: vk::Buffer,
+    pub memory: gpu_alloc::MemoryBlock<vk::DeviceMemory>,
 }
 
-pub struct Mesh {
-    pub indices: AllocatedBuffer,
-    pub vertices: AllocatedBuffer,
-    pub n_indices: u32,
+impl AllocatedBuffer {
+    /// Create a buffer and bind it to freshly allocated memory
+    pub fn new(
+        prelude: &SharedCore,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+        memory_usage: gpu_alloc::UsageFlags,
+    ) -> Result<Self> {
+        let create_info = vk::BufferCreateInfoBuilder::new()
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .size(size);
+        let buffer = unsafe { prelude.device.create_buffer(&create_info, None) }.result()?;
+        let requirements = unsafe { prelude.device.get_buffer_memory_requirements(buffer) };
+        let request = gpu_alloc::Request {
+            size: requirements.size,
+            align_mask: requirements.alignment,
+            usage: memory_usage,
+            memory_types: requirements.memory_type_bits,
+        };
+        let memory = unsafe {
+            prelude
+                .allocator()?
+                .alloc(EruptMemoryDevice::wrap(&prelude.device), request)?
+        };
+        unsafe {
+            prelude
+                .device
+                .bind_buffer_memory(buffer, *memory.memory(), memory.offset())
+                .result()?;
+        }
+        Ok(Self { buffer, memory })
+    }
+
+    /// Destroy the buffer and return its memory to the allocator. The buffer must not be in use.
+    pub fn free(self, prelude: &SharedCore) -> Result<()> {
+        unsafe {
+            prelude
+                .allocator()?
+                .dealloc(EruptMemoryDevice::wrap(&prelude.device), self.memory);
+            prelude.device.destroy_buffer(Some(self.buffer), None);
+        }
+        Ok(())
+    }
 }
 
 // TODO: Turn the Vec<T>'s into [T; FRAMES_IN_FLIGHT]!
@@ -32,21 +192,63 @@ pub struct Mesh {
 pub struct Core {
     pub materials: SlotMap<crate::Material, Material>,
     pub meshes: SlotMap<crate::Mesh, Mesh>,
+    pub textures: SlotMap<crate::Texture, Texture>,
+    /// Bound in place of missing textures
+    pub default_texture: Texture,
+    pub samplers: Samplers,
+    /// Render pass which clears the color attachment first
     pub render_pass: vk::RenderPass,
+    /// Compatible render pass which draws over the previous frame instead
+    pub load_render_pass: vk::RenderPass,
+    pub config: PassConfig,
+    /// Draws `Background::Gradient`
+    pub gradient: Material,
+    pub post: PostChain,
+    /// Compute materials and storage buffers
+    pub compute: Compute,
+    /// Shader files behind file-backed materials
+    pub watcher: ShaderWatcher,
+    /// Used to build every pipeline, and saved for the next run when the core is dropped
+    pub pipeline_cache: PipelineCache,
+    /// Sample counts the device supports for both color and depth attachments
+    pub supported_samples: vk::SampleCountFlags,
     pub frame_sync: FrameSync,
+    /// Removed resources which frames in flight may still be using
+    pub deletion_queue: DeletionQueue<Retired>,
     pub swapchain_images: Option<SwapchainImages>,
     pub command_pool: vk::CommandPool,
     pub command_buffers: Vec<vk::CommandBuffer>,
+    /// One for each thread draws are recorded on, or none to record them on the calling thread
+    pub recorders: Vec<Recorder>,
+    pub queue_family_index: u32,
     pub descriptor_pool: vk::DescriptorPool,
     pub descriptor_set_layout: vk::DescriptorSetLayout,
+    pub material_set_layout: vk::DescriptorSetLayout,
     pub descriptor_sets: Vec<vk::DescriptorSet>,
     pub camera_ubos: Vec<AllocatedBuffer>,
     pub time_ubos: Vec<AllocatedBuffer>,
+    pub instance_buffers: InstanceBuffers,
+    /// Animation value, uploaded to the time UBO of each frame as it is written
+    pub time: f32,
+    /// Counts from the most recently written frame
+    pub stats: crate::FrameStats,
+    /// Whether opaque draws are sorted and batched, or drawn in packet order one at a time
+    pub batch_draws: bool,
+    /// Materials and meshes which couldn't be drawn together and have been logged, so that each
+    /// problem is only reported once rather than every frame
+    pub reported_meshes: Mutex<HashSet<(crate::Material, crate::Mesh)>>,
+    pub physical_device: vk::PhysicalDevice,
+    /// Device features enabled by the backend
+    pub features: vk::PhysicalDeviceFeatures,
     pub prelude: SharedCore,
 }
 
 impl Core {
-    pub fn new(prelude: SharedCore, core_meta: vk_core::CoreMeta, vr: bool) -> Result<Self> {
+    pub fn new(
+        prelude: SharedCore,
+        core_meta: vk_core::CoreMeta,
+        target: RenderTarget,
+    ) -> Result<Self> {
         // Command pool
         let create_info = vk::CommandPoolCreateInfoBuilder::new()
             .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
@@ -69,12 +271,17 @@ impl Core {
                 .binding(0)
                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
                 .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::VERTEX),
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
             vk::DescriptorSetLayoutBindingBuilder::new()
                 .binding(1)
                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
                 .descriptor_count(1)
                 .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
+            vk::DescriptorSetLayoutBindingBuilder::new()
+                .binding(INSTANCE_BINDING)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
         ];
 
         let descriptor_set_layout_ci =
@@ -88,9 +295,14 @@ impl Core {
         .result()?;
 
         // Create descriptor pool
-        let pool_sizes = [vk::DescriptorPoolSizeBuilder::new()
-            ._type(vk::DescriptorType::UNIFORM_BUFFER)
-            .descriptor_count((FRAMES_IN_FLIGHT * 2) as u32)];
+        let pool_sizes = [
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count((FRAMES_IN_FLIGHT * 2) as u32),
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(FRAMES_IN_FLIGHT as u32),
+        ];
         let create_info = vk::DescriptorPoolCreateInfoBuilder::new()
             .pool_sizes(&pool_sizes)
             .max_sets(FRAMES_IN_FLIGHT as u32);
@@ -204,22 +416,106 @@ impl Core {
             }
         }
 
+        // Textures
+        let material_set_layout = create_material_set_layout(&prelude)?;
+        let default_texture = Texture::new(
+            prelude.clone(),
+            core_meta.physical_device,
+            command_pool,
+            1,
+            1,
+            crate::TextureFormat::Rgba8Unorm,
+            &[255; 4],
+        )?;
+        let samplers = Samplers::new(prelude.clone());
+
+        // Instance data
+        let instance_buffers = InstanceBuffers::new(&prelude, &descriptor_sets)?;
+
         // Frame synchronization
         let frame_sync = FrameSync::new(prelude.clone(), FRAMES_IN_FLIGHT)?;
 
-        let render_pass = create_render_pass(&prelude.device, vr)?;
+        let config = PassConfig {
+            target,
+            samples: vk::SampleCountFlagBits::_1,
+            keep_color: false,
+            post: false,
+        };
+        let render_pass = create_render_pass(&prelude.device, config, false)?;
+        let load_render_pass = create_render_pass(&prelude.device, config, true)?;
+
+        let pipeline_cache = PipelineCache::new(prelude.clone(), core_meta.physical_device)?;
+
+        let gradient = Material::new(
+            prelude.clone(),
+            pipeline_cache.cache,
+            GRADIENT_VERT,
+            GRADIENT_FRAG,
+            crate::MaterialDesc {
+                // The full-screen triangle is generated from gl_VertexIndex
+                vertex_layout: VertexLayout {
+                    stride: 0,
+                    attributes: Vec::new(),
+                },
+                cull: crate::Cull::None,
+                depth_test: false,
+                depth_write: false,
+                ..Default::default()
+            },
+            render_pass,
+            config.samples,
+            descriptor_set_layout,
+            material_set_layout,
+        )?;
+
+        let post = PostChain::new(prelude.clone(), pipeline_cache.cache, target)?;
+        let compute = Compute::new(prelude.clone(), pipeline_cache.cache)?;
+
+        let limits = unsafe {
+            prelude
+                .instance
+                .get_physical_device_properties(core_meta.physical_device)
+        }
+        .limits;
+        let supported_samples =
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+
+        let features =
+            crate::extensions::device_features(&prelude.instance, core_meta.physical_device);
 
         Ok(Self {
             prelude,
             camera_ubos,
             time_ubos,
+            instance_buffers,
             descriptor_set_layout,
+            material_set_layout,
+            textures: SlotMap::with_capacity_and_key(10),
+            default_texture,
+            samplers,
+            physical_device: core_meta.physical_device,
+            features,
+            config,
+            supported_samples,
             descriptor_pool,
             descriptor_sets,
             command_pool,
             frame_sync,
+            deletion_queue: DeletionQueue::default(),
             command_buffers,
+            recorders: Vec::new(),
+            queue_family_index: core_meta.queue_family_index,
             render_pass,
+            load_render_pass,
+            gradient,
+            post,
+            compute,
+            watcher: ShaderWatcher::default(),
+            pipeline_cache,
+            time: 0.0,
+            stats: crate::FrameStats::default(),
+            batch_draws: true,
+            reported_meshes: Mutex::new(HashSet::new()),
             swapchain_images: None,
             materials: SlotMap::with_capacity_and_key(10),
             meshes: SlotMap::with_capacity_and_key(10),
@@ -230,129 +526,485 @@ impl Core {
         &mut self,
         vertex: &[u8],
         fragment: &[u8],
-        draw_type: crate::DrawType,
+        desc: crate::MaterialDesc,
     ) -> Result<crate::Material> {
+        ensure!(
+            !desc.wireframe || self.features.fill_mode_non_solid == vk::TRUE,
+            "Wireframe materials are not supported by this device"
+        );
+        ensure!(
+            desc.line_width == 1.0 || self.features.wide_lines == vk::TRUE,
+            "Line widths other than 1.0 are not supported by this device"
+        );
+        desc.vertex_layout.validate()?;
         let material = Material::new(
             self.prelude.clone(),
+            self.pipeline_cache.cache,
             vertex,
             fragment,
-            draw_type,
+            desc,
             self.render_pass,
+            self.config.samples,
             self.descriptor_set_layout,
+            self.material_set_layout,
         )?;
         Ok(self.materials.insert(material))
     }
 
-    pub fn remove_material(&mut self, material: crate::Material) -> Result<()> {
-        // Figure out how not to wait?
+    /// Add a material from shader files, which is rebuilt whenever they change
+    pub fn add_material_from_files(
+        &mut self,
+        vertex: &Path,
+        fragment: &Path,
+        desc: crate::MaterialDesc,
+    ) -> Result<crate::Material> {
+        let vertex_src = load_shader(vertex, ShaderStage::Vertex)?;
+        let fragment_src = load_shader(fragment, ShaderStage::Fragment)?;
+        let material = self.add_material(&vertex_src, &fragment_src, desc)?;
+        self.watcher.watch(material, vertex, fragment);
+        Ok(material)
+    }
+
+    /// Rebuild the pipelines of materials whose shader files changed. Materials whose new
+    /// shaders fail to build keep their old pipeline.
+    fn reload_shaders(&mut self) -> Result<()> {
+        let reloads = self.watcher.poll();
+        if reloads.is_empty() {
+            return Ok(());
+        }
+
+        // The other frame in flight may still be drawing with the old pipelines
         unsafe {
             self.prelude.device.device_wait_idle().result()?;
         }
-        self.materials.remove(material);
+
+        for reload in reloads {
+            let material = match self.materials.get_mut(reload.material) {
+                Some(m) => m,
+                None => continue,
+            };
+            match material.reload(
+                &reload.vertex,
+                &reload.fragment,
+                self.render_pass,
+                self.config.samples,
+            ) {
+                Ok(()) => log::info!("Reloaded shaders of {:?}", reload.material),
+                Err(e) => log::error!("Keeping the previous pipeline: {:#}", e),
+            }
+        }
+
         Ok(())
     }
 
-    pub fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u16]) -> Result<crate::Mesh> {
-        let n_indices = indices.len() as u32;
-        use gpu_alloc::UsageFlags as UF;
+    /// Largest number of MSAA samples per pixel the device supports
+    pub fn max_msaa_samples(&self) -> u32 {
+        let bits = self.supported_samples.bits();
+        1 << (31 - bits.leading_zeros())
+    }
 
-        // Vertex
-        let create_info = vk::BufferCreateInfoBuilder::new()
-            .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .size(std::mem::size_of_val(vertices) as u64);
-        let buffer =
-            unsafe { self.prelude.device.create_buffer(&create_info, None) }.result()?;
-        let requirements = unsafe { self.prelude.device.get_buffer_memory_requirements(buffer) };
-        let request = gpu_alloc::Request {
-            size: requirements.size,
-            align_mask: requirements.alignment,
-            usage: UF::DOWNLOAD | UF::UPLOAD | UF::HOST_ACCESS,
-            memory_types: requirements.memory_type_bits,
-        };
-        let mut memory = unsafe { self.prelude.allocator()?
-            .alloc(EruptMemoryDevice::wrap(&self.prelude.device), request)? };
-        unsafe {
-            self.prelude.device.bind_buffer_memory(buffer, *memory.memory(), memory.offset()).result()?;
+    /// Change the number of MSAA samples per pixel, rebuilding the render pass and every
+    /// material
+    pub fn set_msaa_samples(&mut self, samples: u32) -> Result<()> {
+        ensure!(
+            samples.is_power_of_two() && self.supported_samples.bits() & samples != 0,
+            "{} samples per pixel is not supported by this device (the maximum is {})",
+            samples,
+            self.max_msaa_samples()
+        );
+
+        // Sample count bits have the same value as the count they stand for
+        self.reconfigure(PassConfig {
+            samples: vk::SampleCountFlagBits(samples),
+            ..self.config
+        })
+    }
+
+    /// Split recording between this many threads, each with its own command pool
+    pub fn set_recording_threads(&mut self, threads: usize) -> Result<()> {
+        ensure!(threads > 0, "Draws must be recorded on at least one thread");
+
+        // A single thread records inline, into the primary command buffer
+        let workers = if threads == 1 { 0 } else { threads };
+        while self.recorders.len() > workers {
+            if let Some(recorder) = self.recorders.pop() {
+                self.retire(Retired::Recorder(recorder));
+            }
         }
-        unsafe {
-        memory.write_bytes(
-                EruptMemoryDevice::wrap(&self.prelude.device),
-                0,
-                &bytemuck::cast_slice(vertices),
-            )?;
+        while self.recorders.len() < workers {
+            self.recorders.push(Recorder::new(
+                self.prelude.clone(),
+                self.queue_family_index,
+                FRAMES_IN_FLIGHT,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Switch to a color image which persists between frames, the first time a packet asks to
+    /// keep the previous frame
+    pub fn prepare_background(&mut self, background: crate::Background) -> Result<()> {
+        match background {
+            crate::Background::Keep => self.reconfigure(PassConfig {
+                keep_color: true,
+                ..self.config
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Append a post-processing effect to the chain, drawing objects into an intermediate image
+    /// from now on if this is the first
+    pub fn add_post_effect(&mut self, fragment: &[u8]) -> Result<crate::PostEffect> {
+        let effect = self.post.add(fragment)?;
+        self.reconfigure(PassConfig {
+            post: true,
+            ..self.config
+        })?;
+        Ok(effect)
+    }
+
+    pub fn remove_post_effect(&mut self, effect: crate::PostEffect) -> Result<()> {
+        if let Some(effect) = self.post.remove(effect) {
+            self.retire(Retired::PostEffect(effect));
+        }
+        self.reconfigure(PassConfig {
+            post: !self.post.is_empty(),
+            ..self.config
+        })
+    }
+
+    pub fn set_post_effect_params(
+        &mut self,
+        effect: crate::PostEffect,
+        params: crate::ObjectParams,
+    ) -> Result<()> {
+        self.post.set_params(effect, params)
+    }
+
+    /// Switch to a new configuration, replacing both render passes and rebuilding every pipeline
+    /// which is no longer compatible. The swapchain images are freed if anything changed, and the
+    /// backend must recreate them before its next frame.
+    fn reconfigure(&mut self, config: PassConfig) -> Result<()> {
+        if config == self.config {
+            return Ok(());
         }
-        let vertex_buffer = AllocatedBuffer {
-            memory,
-            buffer,
-        };
 
-        // Indices
-        let create_info = vk::BufferCreateInfoBuilder::new()
-            .usage(vk::BufferUsageFlags::INDEX_BUFFER)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .size(std::mem::size_of_val(indices) as u64);
-        let buffer =
-            unsafe { self.prelude.device.create_buffer(&create_info, None) }.result()?;
-        let requirements = unsafe { self.prelude.device.get_buffer_memory_requirements(buffer) };
-        let request = gpu_alloc::Request {
-            size: requirements.size,
-            align_mask: requirements.alignment,
-            usage: UF::DOWNLOAD | UF::UPLOAD | UF::HOST_ACCESS,
-            memory_types: requirements.memory_type_bits,
-        };
-        let mut memory = unsafe { self.prelude.allocator()?
-            .alloc(EruptMemoryDevice::wrap(&self.prelude.device), request)? };
         unsafe {
-            self.prelude.device.bind_buffer_memory(buffer, *memory.memory(), memory.offset()).result()?;
+            self.prelude.device.device_wait_idle().result()?;
         }
+
+        // Framebuffers reference the old render passes
+        drop(self.swapchain_images.take());
+
+        let device = &self.prelude.device;
+        let render_pass = create_render_pass(device, config, false)?;
+        let load_render_pass = create_render_pass(device, config, true)?;
         unsafe {
-        memory.write_bytes(
-                EruptMemoryDevice::wrap(&self.prelude.device),
-                0,
-                &bytemuck::cast_slice(indices),
-            )?;
+            device.destroy_render_pass(Some(self.render_pass), None);
+            device.destroy_render_pass(Some(self.load_render_pass), None);
         }
-        let index_buffer = AllocatedBuffer {
-            memory,
-            buffer,
-        };
+        self.render_pass = render_pass;
+        self.load_render_pass = load_render_pass;
+
+        // Load and store ops and layouts don't affect compatibility, but samples and formats do
+        if config.samples != self.config.samples
+            || config.scene_format() != self.config.scene_format()
+        {
+            for material in self.materials.values_mut() {
+                material.rebuild(self.render_pass, config.samples)?;
+            }
+            self.gradient.rebuild(self.render_pass, config.samples)?;
+        }
+        self.config = config;
+
+        Ok(())
+    }
+
+    /// Create attachments and framebuffers for the given target images, to match the current
+    /// configuration
+    pub fn create_swapchain_images(
+        &mut self,
+        extent: vk::Extent2D,
+        images: Vec<vk::Image>,
+    ) -> Result<()> {
+        self.swapchain_images = Some(SwapchainImages::new(
+            self.prelude.clone(),
+            extent,
+            self.render_pass,
+            images,
+            self.config,
+            &self.post,
+            &mut self.samplers,
+        )?);
+        Ok(())
+    }
+
+    pub fn remove_material(&mut self, material: crate::Material) -> Result<()> {
+        if let Some(removed) = self.materials.remove(material) {
+            self.retire(Retired::Material(removed));
+        }
+        self.watcher.unwatch(material);
+        Ok(())
+    }
+
+    pub fn set_material_textures(
+        &mut self,
+        material: crate::Material,
+        textures: &[crate::TextureBinding],
+    ) -> Result<()> {
+        match self.materials.get_mut(material) {
+            Some(material) => material.set.set_textures(textures),
+            None => Err(anyhow::format_err!("Material does not exist")),
+        }
+    }
+
+    pub fn set_material_params(&mut self, material: crate::Material, data: &[u8]) -> Result<()> {
+        match self.materials.get_mut(material) {
+            Some(material) => material.set.set_params(data),
+            None => Err(anyhow::format_err!("Material does not exist")),
+        }
+    }
+
+    pub fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: crate::TextureFormat,
+        data: &[u8],
+    ) -> Result<crate::Texture> {
+        let texture = Texture::new(
+            self.prelude.clone(),
+            self.physical_device,
+            self.command_pool,
+            width,
+            height,
+            format,
+            data,
+        )?;
+        Ok(self.textures.insert(texture))
+    }
 
+    pub fn remove_texture(&mut self, texture: crate::Texture) -> Result<()> {
+        if let Some(removed) = self.textures.remove(texture) {
+            self.retire(Retired::Texture(removed));
+            // Descriptor sets may still reference the texture's view
+            for material in self.materials.values_mut() {
+                material.set.mark_dirty();
+            }
+        }
+        Ok(())
+    }
 
-        let mesh = Mesh {
-            indices: index_buffer,
-            vertices: vertex_buffer,
-            n_indices,
+    pub fn add_mesh(
+        &mut self,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+        usage: crate::MeshUsage,
+    ) -> Result<crate::Mesh> {
+        layout.validate()?;
+        let mesh = match usage {
+            crate::MeshUsage::Static => Mesh::new_static(
+                &self.prelude,
+                self.command_pool,
+                layout.clone(),
+                vertices,
+                indices,
+            )?,
+            crate::MeshUsage::Dynamic => {
+                Mesh::new_dynamic(&self.prelude, layout.clone(), vertices, indices)?
+            }
         };
+        Ok(self.meshes.insert(mesh))
+    }
+
+    pub fn update_mesh(
+        &mut self,
+        id: crate::Mesh,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<()> {
+        self.wait_for_static_mesh(id)?;
+        match self.meshes.get_mut(id) {
+            Some(mesh) => mesh.update(&self.prelude, self.command_pool, layout, vertices, indices),
+            None => Err(anyhow::format_err!("Mesh does not exist")),
+        }
+    }
+
+    pub fn update_mesh_vertices(
+        &mut self,
+        id: crate::Mesh,
+        layout: &VertexLayout,
+        first_vertex: usize,
+        vertices: &[u8],
+    ) -> Result<()> {
+        self.wait_for_static_mesh(id)?;
+        match self.meshes.get_mut(id) {
+            Some(mesh) => mesh.update_vertices(
+                &self.prelude,
+                self.command_pool,
+                layout,
+                first_vertex,
+                vertices,
+            ),
+            None => Err(anyhow::format_err!("Mesh does not exist")),
+        }
+    }
+
+    /// Static meshes are shared by all frames in flight, so those must finish before one is written
+    fn wait_for_static_mesh(&self, id: crate::Mesh) -> Result<()> {
+        if let Some(false) = self.meshes.get(id).map(|mesh| mesh.is_dynamic()) {
+            unsafe {
+                self.prelude.device.device_wait_idle().result()?;
+            }
+        }
+        Ok(())
+    }
 
+    pub fn add_storage_mesh(
+        &mut self,
+        layout: &VertexLayout,
+        storage: crate::StorageBuffer,
+        indices: Indices,
+    ) -> Result<crate::Mesh> {
+        layout.validate()?;
+        ensure!(
+            self.compute.buffers.contains_key(storage),
+            "Storage buffer does not exist"
+        );
+        let mesh = Mesh::new_storage(
+            &self.prelude,
+            self.command_pool,
+            layout.clone(),
+            storage,
+            indices,
+        )?;
         Ok(self.meshes.insert(mesh))
     }
 
-    pub fn remove_mesh(&mut self, id: crate::Mesh) -> Result<()> {
-        // Figure out how not to wait?
+    pub fn add_storage_buffer(&mut self, data: &[u8]) -> Result<crate::StorageBuffer> {
+        self.compute.add_buffer(self.command_pool, data)
+    }
+
+    pub fn update_storage_buffer(
+        &mut self,
+        buffer: crate::StorageBuffer,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        // Storage buffers are shared by all frames in flight
         unsafe {
             self.prelude.device.device_wait_idle().result()?;
         }
+        self.compute
+            .update_buffer(self.command_pool, buffer, offset, data)
+    }
+
+    pub fn remove_storage_buffer(&mut self, buffer: crate::StorageBuffer) -> Result<()> {
+        if let Some(removed) = self.compute.buffers.remove(buffer) {
+            self.retire(Retired::StorageBuffer(removed));
+        }
+        Ok(())
+    }
+
+    pub fn add_compute_material(&mut self, compute: &[u8]) -> Result<crate::ComputeMaterial> {
+        self.compute.add_material(compute)
+    }
+
+    pub fn remove_compute_material(&mut self, material: crate::ComputeMaterial) -> Result<()> {
+        if let Some(removed) = self.compute.materials.remove(material) {
+            self.retire(Retired::ComputeMaterial(removed));
+        }
+        Ok(())
+    }
+
+    pub fn remove_mesh(&mut self, id: crate::Mesh) -> Result<()> {
         if let Some(mesh) = self.meshes.remove(id) {
-            unsafe {
-                self.prelude.allocator()?.dealloc(EruptMemoryDevice::wrap(&self.prelude.device), mesh.indices.memory);
-                self.prelude.allocator()?.dealloc(EruptMemoryDevice::wrap(&self.prelude.device), mesh.vertices.memory);
-                self.prelude.device.destroy_buffer(Some(mesh.indices.buffer), None);
-                self.prelude.device.destroy_buffer(Some(mesh.vertices.buffer), None);
-            }
+            self.retire(Retired::Mesh(mesh));
         }
         Ok(())
     }
 
+    /// Destroy a removed resource once every frame started so far has finished with it
+    fn retire(&mut self, resource: Retired) {
+        let last_frame = self.frame_sync.frame_number();
+        self.deletion_queue.push(last_frame, resource);
+    }
+
     pub fn write_command_buffers(
-        &self,
+        &mut self,
         frame_idx: usize,
         packet: &crate::FramePacket,
         image: &SwapChainImage,
+        cameras: &[Matrix4<f32>],
     ) -> Result<vk::CommandBuffer> {
+        // Pick up edited shaders before anything is recorded with them
+        self.reload_shaders()?;
+
+        // Frames which could still use removed resources may have finished by now
+        let completed_frame = self.frame_sync.completed_frame();
+        for resource in self.deletion_queue.collect(completed_frame) {
+            resource.destroy(&self.prelude)?;
+        }
+
+        // This frame is no longer in flight, so its time value can be written
+        let ubo = &mut self.time_ubos[frame_idx];
+        unsafe {
+            ubo.memory.write_bytes(EruptMemoryDevice::wrap(&self.prelude.device), 0, bytemuck::cast_slice(&[self.time]))?;
+        }
+
+        // Likewise for this frame's copy of each dynamic mesh
+        for mesh in self.meshes.values_mut() {
+            mesh.sync(&self.prelude, frame_idx)?;
+        }
+
+        // And each material's texture descriptors
+        for material in self.materials.values_mut() {
+            material.set.update(
+                frame_idx,
+                &self.textures,
+                &self.default_texture,
+                &mut self.samplers,
+            )?;
+        }
+
         // Reset and write command buffers for this frame
         let command_buffer = self.command_buffers[frame_idx];
         let descriptor_set = self.descriptor_sets[frame_idx];
+
+        // Sort and batch opaque draws up front, since batches add to the instance data. Objects
+        // outside the view of every camera are skipped.
+        let mut stats = crate::FrameStats::default();
+        let (first_instances, first_batched) = instance_offsets(&packet.instanced);
+        let (mut draws, mut batched) = opaque_queue(
+            &self.materials,
+            packet,
+            &first_instances,
+            first_batched,
+            self.batch_draws,
+            |object| self.is_visible(object, cameras, &mut stats),
+        );
+
+        // Then blended materials, back to front so that they cover what is behind them
+        draws.extend(transparent_queue(
+            &self.materials,
+            packet,
+            &first_instances,
+            first_batched,
+            &mut batched,
+            cameras,
+            |object| self.is_visible(object, cameras, &mut stats),
+        ));
+
+        self.instance_buffers.upload(
+            &self.prelude,
+            frame_idx,
+            descriptor_set,
+            &packet.instanced,
+            &batched,
+        )?;
         unsafe {
             self.prelude
                 .device
@@ -365,11 +1017,20 @@ impl Core {
                 .begin_command_buffer(command_buffer, &begin_info)
                 .result()?;
 
+            // Simulations run ahead of the render pass, so objects draw what they wrote
+            self.compute
+                .record(command_buffer, frame_idx, &packet.dispatches, self.time)?;
+
             // Set render pass
+            let clear_color = match packet.background {
+                crate::Background::Color(color) => color,
+                crate::Background::Gradient { top, .. } => top,
+                crate::Background::Keep => [0.0, 0.0, 0.0, 1.0],
+            };
             let clear_values = [
                 vk::ClearValue {
                     color: vk::ClearColorValue {
-                        float32: [0.0, 0.0, 0.0, 1.0],
+                        float32: clear_color,
                     },
                 },
                 vk::ClearValue {
@@ -380,20 +1041,26 @@ impl Core {
                 },
             ];
 
+            let render_pass = match packet.background {
+                crate::Background::Keep if image.previous_contents => self.load_render_pass,
+                _ => self.render_pass,
+            };
             let begin_info = vk::RenderPassBeginInfoBuilder::new()
                 .framebuffer(image.framebuffer)
-                .render_pass(self.render_pass)
+                .render_pass(render_pass)
                 .render_area(vk::Rect2D {
                     offset: vk::Offset2D { x: 0, y: 0 },
                     extent: image.extent,
                 })
                 .clear_values(&clear_values);
 
-            self.prelude.device.cmd_begin_render_pass(
-                command_buffer,
-                &begin_info,
-                vk::SubpassContents::INLINE,
-            );
+            let contents = match self.recorders.is_empty() {
+                true => vk::SubpassContents::INLINE,
+                false => vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
+            };
+            self.prelude
+                .device
+                .cmd_begin_render_pass(command_buffer, &begin_info, contents);
 
             let viewports = [vk::ViewportBuilder::new()
                 .x(0.0)
@@ -407,78 +1074,45 @@ impl Core {
                 .offset(vk::Offset2D { x: 0, y: 0 })
                 .extent(image.extent)];
 
-            for (material_id, material) in self.materials.iter() {
-                self.prelude.device.cmd_bind_pipeline(
+            if self.recorders.is_empty() {
+                if let crate::Background::Gradient { top, bottom } = packet.background {
+                    self.draw_gradient(command_buffer, top, bottom, &viewports, &scissors);
+                }
+                self.record_draws(
                     command_buffer,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    material.pipeline,
+                    frame_idx,
+                    &draws,
+                    &viewports,
+                    &scissors,
+                    &mut stats,
                 );
-
-                self.prelude
-                    .device
-                    .cmd_set_viewport(command_buffer, 0, &viewports);
-
+            } else {
+                let secondaries = self.record_secondaries(
+                    frame_idx,
+                    packet.background,
+                    &draws,
+                    render_pass,
+                    image.framebuffer,
+                    &viewports,
+                    &scissors,
+                    &mut stats,
+                )?;
                 self.prelude
                     .device
-                    .cmd_set_scissor(command_buffer, 0, &scissors);
+                    .cmd_execute_commands(command_buffer, &secondaries);
+            }
 
-                self.prelude.device.cmd_bind_descriptor_sets(
-                    command_buffer,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    material.pipeline_layout,
-                    0,
-                    &[descriptor_set],
-                    &[],
-                );
+            self.prelude.device.cmd_end_render_pass(command_buffer);
+            self.stats = stats;
 
-                for object in packet
-                    .objects
-                    .iter()
-                    .filter(|o| o.material == material_id)
-                {
-                    let mesh = match self.meshes.get(object.mesh) {
-                        Some(m) => m,
-                        None => {
-                            log::error!("Object references a mesh that no exists");
-                            continue;
-                        }
-                    };
-                    self.prelude.device.cmd_bind_vertex_buffers(
-                        command_buffer,
-                        0,
-                        &[mesh.vertices.buffer],
-                        &[0],
-                    );
-
-                    self.prelude.device.cmd_bind_index_buffer(
-                        command_buffer,
-                        mesh.indices.buffer,
-                        0,
-                        vk::IndexType::UINT16,
-                    );
-
-                    // TODO: ADD ANIM
-                    self.prelude.device.cmd_push_constants(
-                        command_buffer,
-                        material.pipeline_layout,
-                        vk::ShaderStageFlags::VERTEX,
-                        0,
-                        std::mem::size_of::<[f32; 16]>() as u32,
-                        object.transform.data.as_ptr() as _,
-                    );
-
-                    self.prelude.device.cmd_draw_indexed(
-                        command_buffer,
-                        mesh.n_indices,
-                        1,
-                        0,
-                        0,
-                        0,
-                    );
-                }
+            let post_targets = self.swapchain_images.as_ref().and_then(|i| i.post.as_ref());
+            if let Some(targets) = post_targets {
+                self.post.record(command_buffer, image, targets, self.time);
             }
 
-            self.prelude.device.cmd_end_render_pass(command_buffer);
+            if let Some(color_image) = image.copy_from {
+                self.copy_to_target(command_buffer, color_image, image);
+            }
 
             self.prelude
                 .device
@@ -489,6 +1123,404 @@ impl Core {
         Ok(command_buffer)
     }
 
+    /// Split the draws into runs of about equal length, in order, and record each into a secondary
+    /// command buffer on its own thread. The first run starts with the gradient. Each run binds
+    /// its own state, so the buffers draw exactly what recording them all inline would.
+    #[allow(clippy::too_many_arguments)]
+    fn record_secondaries(
+        &self,
+        frame_idx: usize,
+        background: crate::Background,
+        draws: &[Draw],
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        viewports: &[vk::ViewportBuilder],
+        scissors: &[vk::Rect2DBuilder],
+        stats: &mut crate::FrameStats,
+    ) -> Result<Vec<vk::CommandBuffer>> {
+        let runs = split_runs(draws, self.recorders.len());
+
+        let recorded = std::thread::scope(|scope| {
+            let threads = self
+                .recorders
+                .iter()
+                .zip(runs)
+                .enumerate()
+                .map(|(idx, (recorder, run))| {
+                    scope.spawn(move || -> Result<(vk::CommandBuffer, usize)> {
+                        let command_buffer = recorder.begin(frame_idx, render_pass, framebuffer)?;
+                        if let (0, crate::Background::Gradient { top, bottom }) = (idx, background)
+                        {
+                            self.draw_gradient(command_buffer, top, bottom, viewports, scissors);
+                        }
+
+                        let mut stats = crate::FrameStats::default();
+                        self.record_draws(
+                            command_buffer,
+                            frame_idx,
+                            run,
+                            viewports,
+                            scissors,
+                            &mut stats,
+                        );
+                        unsafe {
+                            self.prelude
+                                .device
+                                .end_command_buffer(command_buffer)
+                                .result()?;
+                        }
+                        Ok((command_buffer, stats.draw_calls))
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|thread| {
+                    thread
+                        .join()
+                        .map_err(|_| format_err!("Command recording thread panicked"))?
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut command_buffers = Vec::with_capacity(recorded.len());
+        for (command_buffer, draw_calls) in recorded {
+            command_buffers.push(command_buffer);
+            stats.draw_calls += draw_calls;
+        }
+        Ok(command_buffers)
+    }
+
+    /// Fill the screen with a vertical gradient
+    fn draw_gradient(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        top: [f32; 4],
+        bottom: [f32; 4],
+        viewports: &[vk::ViewportBuilder],
+        scissors: &[vk::Rect2DBuilder],
+    ) {
+        // The gradient shaders use no descriptors, so none are bound
+        unsafe {
+            self.prelude.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.gradient.pipeline,
+            );
+            self.prelude
+                .device
+                .cmd_set_viewport(command_buffer, 0, viewports);
+            self.prelude
+                .device
+                .cmd_set_scissor(command_buffer, 0, scissors);
+        }
+
+        let mut params = [0.0; 16];
+        params[..4].copy_from_slice(&top);
+        params[4..8].copy_from_slice(&bottom);
+        self.push_constants(command_buffer, &self.gradient, &Matrix4::identity(), params);
+
+        unsafe {
+            self.prelude.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+    }
+
+    /// Copy the persistent color image into the target image, which is left in the target's
+    /// final layout
+    fn copy_to_target(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        color_image: vk::Image,
+        image: &SwapChainImage,
+    ) {
+        let subresource_range = vk::ImageSubresourceRangeBuilder::new()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(image.layers)
+            .build();
+        let subresource_layers = vk::ImageSubresourceLayersBuilder::new()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(image.layers)
+            .build();
+
+        // Wait for rendering, and discard whatever was in the target before
+        let to_transfer = [
+            vk::ImageMemoryBarrierBuilder::new()
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(color_image)
+                .subresource_range(subresource_range),
+            vk::ImageMemoryBarrierBuilder::new()
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.image)
+                .subresource_range(subresource_range),
+        ];
+
+        let regions = [vk::ImageCopyBuilder::new()
+            .src_subresource(subresource_layers)
+            .src_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+            .dst_subresource(subresource_layers)
+            .dst_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+            .extent(vk::Extent3D {
+                width: image.extent.width,
+                height: image.extent.height,
+                depth: 1,
+            })];
+
+        let to_target = [vk::ImageMemoryBarrierBuilder::new()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(self.config.target.final_layout())
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.image)
+            .subresource_range(subresource_range)];
+
+        unsafe {
+            self.prelude.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::TRANSFER,
+                None,
+                &[],
+                &[],
+                &to_transfer,
+            );
+            self.prelude.device.cmd_copy_image(
+                command_buffer,
+                color_image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                image.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            );
+            self.prelude.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                None,
+                &[],
+                &[],
+                &to_target,
+            );
+        }
+    }
+
+    /// Bind a material's pipeline and descriptor sets, and set the dynamic state
+    fn bind_material(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_idx: usize,
+        material: &Material,
+        viewports: &[vk::ViewportBuilder],
+        scissors: &[vk::Rect2DBuilder],
+    ) {
+        unsafe {
+            self.prelude.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                material.pipeline,
+            );
+
+            self.prelude
+                .device
+                .cmd_set_viewport(command_buffer, 0, viewports);
+
+            self.prelude
+                .device
+                .cmd_set_scissor(command_buffer, 0, scissors);
+
+            self.prelude.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                material.pipeline_layout,
+                0,
+                &[
+                    self.descriptor_sets[frame_idx],
+                    material.set.descriptor_set(frame_idx),
+                ],
+                &[],
+            );
+        }
+    }
+
+    /// Record draws in order, binding each material and mesh only when it changes from the
+    /// previous draw
+    fn record_draws(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_idx: usize,
+        queue: &[Draw],
+        viewports: &[vk::ViewportBuilder],
+        scissors: &[vk::Rect2DBuilder],
+        stats: &mut crate::FrameStats,
+    ) {
+        let mut bound_material = None;
+        let mut bound_mesh = None;
+        for draw in queue {
+            let material_id = draw.material();
+            let material = &self.materials[material_id];
+            if bound_material != Some(material_id) {
+                self.bind_material(command_buffer, frame_idx, material, viewports, scissors);
+                bound_material = Some(material_id);
+            }
+
+            let mesh = match self.drawable_mesh(material_id, draw.mesh()) {
+                Some(m) => m,
+                None => continue,
+            };
+            if bound_mesh != Some(draw.mesh()) {
+                if !self.bind_mesh(command_buffer, frame_idx, mesh) {
+                    continue;
+                }
+                bound_mesh = Some(draw.mesh());
+            }
+
+            // Instanced shaders take their transforms from the instance buffer, but materials
+            // may still read the model matrix
+            let (transform, params, instances, first_instance) = match *draw {
+                Draw::Object(object) => (object.transform, object.params, 1, 0),
+                Draw::Batch(object, first, count) => {
+                    (Matrix4::identity(), object.params, count, first)
+                }
+                Draw::Instanced(object, first) => (
+                    Matrix4::identity(),
+                    object.params,
+                    object.transforms.len() as u32,
+                    first,
+                ),
+            };
+            self.push_constants(command_buffer, material, &transform, params);
+
+            unsafe {
+                self.prelude.device.cmd_draw_indexed(
+                    command_buffer,
+                    mesh.n_indices,
+                    instances,
+                    0,
+                    0,
+                    first_instance,
+                );
+            }
+            stats.draw_calls += 1;
+        }
+    }
+
+    /// Whether an object may be seen by any of the cameras, counting it as drawn or culled.
+    /// Objects whose meshes have no bounds are always drawn.
+    fn is_visible(
+        &self,
+        object: &crate::Object,
+        cameras: &[Matrix4<f32>],
+        stats: &mut crate::FrameStats,
+    ) -> bool {
+        let visible = match self.meshes.get(object.mesh).and_then(|m| m.bounds) {
+            Some(bounds) => cameras
+                .iter()
+                .any(|camera| bounds.in_frustum(&(camera * object.transform))),
+            None => true,
+        };
+        match visible {
+            true => stats.drawn += 1,
+            false => stats.culled += 1,
+        }
+        visible
+    }
+
+    /// The mesh an object draws, if it exists and matches the material's vertex layout. Objects
+    /// which can't be drawn are skipped, and the problem is logged the first time it is seen.
+    fn drawable_mesh(&self, material_id: crate::Material, mesh_id: crate::Mesh) -> Option<&Mesh> {
+        let material = &self.materials[material_id];
+        let mesh = self.meshes.get(mesh_id);
+        if let Some(mesh) = mesh.filter(|m| m.layout == material.desc.vertex_layout) {
+            return Some(mesh);
+        }
+
+        let first_time = self
+            .reported_meshes
+            .lock()
+            .map_or(true, |mut reported| reported.insert((material_id, mesh_id)));
+        if first_time {
+            match mesh {
+                Some(mesh) => log::error!(
+                    "Mesh vertex layout {:?} does not match material vertex layout {:?}; skipping",
+                    mesh.layout,
+                    material.desc.vertex_layout
+                ),
+                None => log::error!("Object references a mesh that no longer exists; skipping"),
+            }
+        }
+        None
+    }
+
+    /// Bind a mesh's vertex and index buffers. Returns false if its storage buffer is gone.
+    fn bind_mesh(&self, command_buffer: vk::CommandBuffer, frame_idx: usize, mesh: &Mesh) -> bool {
+        let buffers = mesh.buffers(frame_idx);
+        let vertices = match mesh.storage {
+            Some(storage) => match self.compute.vertex_buffer(storage) {
+                Some(buffer) => buffer,
+                None => {
+                    log::error!("Mesh references a storage buffer that no longer exists");
+                    return false;
+                }
+            },
+            None => buffers.vertices.buffer,
+        };
+        unsafe {
+            self.prelude
+                .device
+                .cmd_bind_vertex_buffers(command_buffer, 0, &[vertices], &[0]);
+
+            self.prelude.device.cmd_bind_index_buffer(
+                command_buffer,
+                buffers.indices.buffer,
+                0,
+                mesh.index_type,
+            );
+        }
+        true
+    }
+
+    /// Push an object's model matrix and parameters
+    fn push_constants(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        material: &Material,
+        transform: &Matrix4<f32>,
+        params: crate::ObjectParams,
+    ) {
+        let mut constants = PushConstants {
+            model: [0.0; 16],
+            params,
+        };
+        constants.model.copy_from_slice(transform.as_slice());
+        unsafe {
+            self.prelude.device.cmd_push_constants(
+                command_buffer,
+                material.pipeline_layout,
+                PUSH_CONSTANT_STAGES,
+                0,
+                std::mem::size_of::<PushConstants>() as u32,
+                &constants as *const PushConstants as _,
+            );
+        }
+    }
+
     /// Upload camera matricies (Two f32 camera matrics in column-major order)
     pub fn update_camera_data(&mut self, frame_idx: usize, data: &[f32; 32]) -> Result<()> {
         let ubo = &mut self.camera_ubos[frame_idx];
@@ -498,44 +1530,87 @@ impl Core {
         Ok(())
     }
 
-    /// Update time value
+    /// Update time value. Takes effect on the next frame written.
     pub fn update_time_value(&mut self, time: f32) -> Result<()> {
-        let frame_idx = self.frame_sync.current_frame();
-        let ubo = &mut self.time_ubos[frame_idx];
-        unsafe {
-            ubo.memory.write_bytes(EruptMemoryDevice::wrap(&self.prelude.device), 0, bytemuck::cast_slice(&[time]))?;
-        }
+        self.time = time;
         Ok(())
     }
 }
 
-fn create_render_pass(device: &DeviceLoader, vr: bool) -> Result<vk::RenderPass> {
+/// Create a render pass. Passes which differ only in `load` are compatible, and so share
+/// framebuffers and pipelines. Loading requires `keep_color`, and the previous frame's contents.
+fn create_render_pass(
+    device: &DeviceLoader,
+    config: PassConfig,
+    load: bool,
+) -> Result<vk::RenderPass> {
+    let msaa = config.msaa();
+
+    // The scene ends up in the target, or in an image post-processing reads
+    let output_layout = match config.post {
+        true => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        false => config.target.final_layout(),
+    };
+
+    // Persistent single-sampled color is copied into the target after the pass
+    let color_layout = match (msaa, config.copy_to_target()) {
+        (true, _) => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        (false, true) => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        (false, false) => output_layout,
+    };
+
     // Render pass
     let color_attachment = vk::AttachmentDescriptionBuilder::new()
-        .format(COLOR_FORMAT)
-        .samples(vk::SampleCountFlagBits::_1)
+        .format(config.scene_format())
+        .samples(config.samples)
+        .load_op(match load {
+            true => vk::AttachmentLoadOp::LOAD,
+            false => vk::AttachmentLoadOp::CLEAR,
+        })
+        .store_op(match msaa && !config.keep_color {
+            true => vk::AttachmentStoreOp::DONT_CARE,
+            false => vk::AttachmentStoreOp::STORE,
+        })
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(match load {
+            true => color_layout,
+            false => vk::ImageLayout::UNDEFINED,
+        })
+        .final_layout(color_layout);
+
+    // Post-processing samples depth after the pass
+    let depth_attachment = vk::AttachmentDescriptionBuilder::new()
+        .format(DEPTH_FORMAT)
+        .samples(config.samples)
         .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::STORE)
+        .store_op(match config.post {
+            true => vk::AttachmentStoreOp::STORE,
+            false => vk::AttachmentStoreOp::DONT_CARE,
+        })
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(if vr {
-            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
-        } else {
-            vk::ImageLayout::PRESENT_SRC_KHR
+        .final_layout(match config.post {
+            true => vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            false => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
         });
 
-    let depth_attachment = vk::AttachmentDescriptionBuilder::new()
-        .format(DEPTH_FORMAT)
+    // With MSAA, the multisampled color attachment is resolved into the output image
+    let resolve_attachment = vk::AttachmentDescriptionBuilder::new()
+        .format(config.scene_format())
         .samples(vk::SampleCountFlagBits::_1)
-        .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+        .final_layout(output_layout);
 
-    let attachments = [color_attachment, depth_attachment];
+    let mut attachments = vec![color_attachment, depth_attachment];
+    if msaa {
+        attachments.push(resolve_attachment);
+    }
 
     let color_attachment_refs = [vk::AttachmentReferenceBuilder::new()
         .attachment(0)
@@ -546,26 +1621,71 @@ fn create_render_pass(device: &DeviceLoader, vr: bool) -> Result<vk::RenderPass>
         .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
         .build();
 
-    let subpasses = [vk::SubpassDescriptionBuilder::new()
+    let resolve_attachment_refs = [vk::AttachmentReferenceBuilder::new()
+        .attachment(2)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+
+    let subpass = vk::SubpassDescriptionBuilder::new()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
         .color_attachments(&color_attachment_refs)
-        .depth_stencil_attachment(&depth_attachment_ref)];
-
-    let dependencies = [vk::SubpassDependencyBuilder::new()
+        .depth_stencil_attachment(&depth_attachment_ref);
+    let subpasses = [match msaa {
+        true => subpass.resolve_attachments(&resolve_attachment_refs),
+        false => subpass,
+    }];
+
+    // A persistent color image was last written by the previous frame's render pass, and
+    // perhaps read by its copy into the target. Post-processing in the previous frame may still
+    // be reading the output image and depth.
+    let mut src_stage = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+    let mut src_access = vk::AccessFlags::empty();
+    let mut dst_stage = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+    if config.keep_color {
+        src_stage |= vk::PipelineStageFlags::TRANSFER;
+        src_access |= vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+    }
+    if config.post {
+        src_stage |= vk::PipelineStageFlags::FRAGMENT_SHADER;
+        dst_stage |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS;
+    }
+    let mut dependencies = vec![vk::SubpassDependencyBuilder::new()
         .src_subpass(vk::SUBPASS_EXTERNAL)
         .dst_subpass(0)
-        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .src_access_mask(vk::AccessFlags::empty())
-        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)];
+        .src_stage_mask(src_stage)
+        .src_access_mask(src_access)
+        .dst_stage_mask(dst_stage)
+        .dst_access_mask(match load {
+            true => {
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            }
+            false => vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        })];
+
+    // Post-processing reads the output image and depth in fragment shaders
+    if config.post {
+        dependencies.push(
+            vk::SubpassDependencyBuilder::new()
+                .src_subpass(0)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                )
+                .src_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+                .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ),
+        );
+    }
 
     let mut create_info = vk::RenderPassCreateInfoBuilder::new()
         .attachments(&attachments)
         .subpasses(&subpasses)
         .dependencies(&dependencies);
 
-    let views = if vr { 2 } else { 1 };
-    let view_mask = [!(!0 << views)];
+    let view_mask = [!(!0 << config.layers())];
     let mut multiview = vk1_1::RenderPassMultiviewCreateInfoBuilder::new()
         .view_masks(&view_mask)
         .correlation_masks(&view_mask)
@@ -576,18 +1696,207 @@ fn create_render_pass(device: &DeviceLoader, vr: bool) -> Result<vk::RenderPass>
     Ok(unsafe { device.create_render_pass(&create_info, None) }.result()?)
 }
 
+/// A draw call
+enum Draw<'a> {
+    /// A single object, whose transform is pushed
+    Object(&'a crate::Object),
+    /// Objects which share this one's material, mesh and parameters, drawn as the given number of
+    /// instances starting at the given instance
+    Batch(&'a crate::Object, u32, u32),
+    /// Every instance of an instanced object, starting at the given instance
+    Instanced(&'a crate::InstancedObject, u32),
+}
+
+impl Draw<'_> {
+    fn material(&self) -> crate::Material {
+        match self {
+            Draw::Object(object) | Draw::Batch(object, ..) => object.material,
+            Draw::Instanced(object, _) => object.material,
+        }
+    }
+
+    fn mesh(&self) -> crate::Mesh {
+        match self {
+            Draw::Object(object) | Draw::Batch(object, ..) => object.mesh,
+            Draw::Instanced(object, _) => object.mesh,
+        }
+    }
+}
+
+/// What sorting and batching draws needs to know about a material
+trait DrawMaterial {
+    fn is_transparent(&self) -> bool;
+    /// Whether the vertex shader takes its transform from the instance buffer
+    fn reads_instances(&self) -> bool;
+}
+
+impl DrawMaterial for Material {
+    fn is_transparent(&self) -> bool {
+        Material::is_transparent(self)
+    }
+
+    fn reads_instances(&self) -> bool {
+        self.instanced
+    }
+}
+
+/// Visible objects and instanced objects with opaque materials, sorted by material and then mesh.
+/// Objects whose material reads the instance buffer are merged into batches when consecutive
+/// ones share a mesh and parameters; the transforms of the batches are returned too, to be
+/// uploaded as instances starting at `first_batched`. Without `batch`, draws keep their order in
+/// the packet and each object is a batch of its own.
+fn opaque_queue<'a, M: DrawMaterial>(
+    materials: &SlotMap<crate::Material, M>,
+    packet: &'a crate::FramePacket,
+    first_instances: &[u32],
+    first_batched: u32,
+    batch: bool,
+    mut visible: impl FnMut(&crate::Object) -> bool,
+) -> (Vec<Draw<'a>>, Vec<Matrix4<f32>>) {
+    let is_opaque = |id: crate::Material| {
+        materials
+            .get(id)
+            .map(|m| !m.is_transparent())
+            .unwrap_or(false)
+    };
+
+    let objects = packet
+        .objects
+        .iter()
+        .filter(|o| is_opaque(o.material) && visible(o))
+        .map(|o| ((o.material, o.mesh), Draw::Object(o)));
+
+    let instanced = packet
+        .instanced
+        .iter()
+        .zip(first_instances.iter())
+        .filter(|(o, _)| is_opaque(o.material) && !o.transforms.is_empty())
+        .map(|(o, first_instance)| ((o.material, o.mesh), Draw::Instanced(o, *first_instance)));
+
+    let mut sorted = objects.chain(instanced).collect::<Vec<_>>();
+    if batch {
+        sorted.sort_by_key(|(key, _)| *key);
+    }
+
+    let mut queue = Vec::with_capacity(sorted.len());
+    let mut batched = Vec::new();
+    for (_, draw) in sorted {
+        let object = match draw {
+            Draw::Object(object) if materials[object.material].reads_instances() => object,
+            draw => {
+                queue.push(draw);
+                continue;
+            }
+        };
+        if let Some(Draw::Batch(first, _, count)) = queue.last_mut().filter(|_| batch) {
+            if first.material == object.material
+                && first.mesh == object.mesh
+                && first.params == object.params
+            {
+                *count += 1;
+                batched.push(object.transform);
+                continue;
+            }
+        }
+        queue.push(Draw::Batch(object, first_batched + batched.len() as u32, 1));
+        batched.push(object.transform);
+    }
+    (queue, batched)
+}
+
+/// Visible objects and instanced objects with blended materials, sorted back to front. Each
+/// instanced object is drawn in one call, so its instances sort together around their mean
+/// position. Objects whose material reads the instance buffer are drawn as batches of one, with
+/// their transforms appended to `batched`, which starts at instance `first_batched`.
+fn transparent_queue<'a, M: DrawMaterial>(
+    materials: &SlotMap<crate::Material, M>,
+    packet: &'a crate::FramePacket,
+    first_instances: &[u32],
+    first_batched: u32,
+    batched: &mut Vec<Matrix4<f32>>,
+    cameras: &[Matrix4<f32>],
+    mut visible: impl FnMut(&crate::Object) -> bool,
+) -> Vec<Draw<'a>> {
+    let is_transparent = |id: crate::Material| {
+        materials
+            .get(id)
+            .map(|m| m.is_transparent())
+            .unwrap_or(false)
+    };
+
+    let objects = packet
+        .objects
+        .iter()
+        .filter(|o| is_transparent(o.material) && visible(o))
+        .map(|o| {
+            let position: Vector4<f32> = o.transform.column(3).into_owned();
+            let draw = match materials[o.material].reads_instances() {
+                true => {
+                    batched.push(o.transform);
+                    Draw::Batch(o, first_batched + batched.len() as u32 - 1, 1)
+                }
+                false => Draw::Object(o),
+            };
+            (view_depth(cameras, &position), draw)
+        });
+
+    let instanced = packet
+        .instanced
+        .iter()
+        .zip(first_instances.iter())
+        .filter(|(o, _)| is_transparent(o.material) && !o.transforms.is_empty())
+        .map(|(o, first_instance)| {
+            let sum = o
+                .transforms
+                .iter()
+                .fold(Vector4::zeros(), |acc, t| acc + t.column(3));
+            let position = sum / o.transforms.len() as f32;
+            (
+                view_depth(cameras, &position),
+                Draw::Instanced(o, *first_instance),
+            )
+        });
+
+    let mut queue = objects.chain(instanced).collect::<Vec<_>>();
+    // Farthest first. The sort is stable, so ties keep their order in the packet
+    queue.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    queue.into_iter().map(|(_, draw)| draw).collect()
+}
+
+/// Depth of a world-space position, averaged over the cameras (one per eye in VR). Clip-space z
+/// increases with view-space depth for both perspective and orthographic projections, and unlike
+/// normalized depth it keeps its order for points behind the camera.
+fn view_depth(cameras: &[Matrix4<f32>], position: &Vector4<f32>) -> f32 {
+    let total: f32 = cameras.iter().map(|camera| (camera * position).z).sum();
+    total / cameras.len().max(1) as f32
+}
+
+/// Split items into at most `count` runs of equal length, in order, except that the last may be
+/// shorter. There is always a first run, even if it is empty.
+fn split_runs<T>(items: &[T], count: usize) -> Vec<&[T]> {
+    if items.is_empty() {
+        return vec![items];
+    }
+    items.chunks(items.len().div_ceil(count)).collect()
+}
+
 impl Drop for Core {
     fn drop(&mut self) {
         unsafe {
             self.prelude.device.device_wait_idle().unwrap();
-            let handles = self.meshes.keys().collect::<Vec<_>>();
-            for mesh in handles {
-                self.remove_mesh(mesh).unwrap();
+            self.materials.clear();
+            self.textures.clear();
+            for (_, mesh) in self.meshes.drain() {
+                mesh.free(&self.prelude).unwrap();
+            }
+            for resource in self.deletion_queue.flush() {
+                resource.destroy(&self.prelude).unwrap();
             }
             for ubo in self.camera_ubos.drain(..) {
                 self.prelude.allocator().unwrap().dealloc(EruptMemoryDevice::wrap(&self.prelude.device), ubo.memory);
                 self.prelude.device.destroy_buffer(Some(ubo.buffer), None);
             }
+            self.instance_buffers.free(&self.prelude).unwrap();
             for ubo in self.time_ubos.drain(..) {
                 self.prelude.allocator().unwrap().dealloc(EruptMemoryDevice::wrap(&self.prelude.device), ubo.memory);
                 self.prelude.device.destroy_buffer(Some(ubo.buffer), None);
@@ -595,9 +1904,15 @@ impl Drop for Core {
             self.prelude
                 .device
                 .destroy_render_pass(Some(self.render_pass), None);
+            self.prelude
+                .device
+                .destroy_render_pass(Some(self.load_render_pass), None);
             self.prelude
                 .device
                 .destroy_descriptor_set_layout(Some(self.descriptor_set_layout), None);
+            self.prelude
+                .device
+                .destroy_descriptor_set_layout(Some(self.material_set_layout), None);
             self.prelude
                 .device
                 .destroy_descriptor_pool(Some(self.descriptor_pool), None);
@@ -610,3 +1925,128 @@ impl Drop for Core {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    struct TestMaterial {
+        transparent: bool,
+        instanced: bool,
+    }
+
+    impl DrawMaterial for TestMaterial {
+        fn is_transparent(&self) -> bool {
+            self.transparent
+        }
+
+        fn reads_instances(&self) -> bool {
+            self.instanced
+        }
+    }
+
+    #[test]
+    fn runs_cover_the_draws_without_empty_runs() {
+        let draws = (0..10).collect::<Vec<u32>>();
+        let lens = |count| {
+            split_runs(&draws, count)
+                .iter()
+                .map(|run| run.len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lens(1), [10]);
+        assert_eq!(lens(3), [4, 4, 2]);
+        assert_eq!(lens(4), [3, 3, 3, 1]);
+        assert_eq!(lens(16), [1; 10]);
+        assert_eq!(split_runs(&draws, 4).concat(), draws);
+        assert_eq!(split_runs::<u32>(&[], 4), [&[][..]]);
+    }
+
+    #[test]
+    fn blended_objects_read_their_own_instance() {
+        let mut materials = SlotMap::with_key();
+        let mut add = |transparent, instanced| {
+            materials.insert(TestMaterial {
+                transparent,
+                instanced,
+            })
+        };
+        let opaque = add(false, true);
+        let blended = add(true, true);
+        let plain = add(true, false);
+
+        let at = |material, z: f32| crate::Object {
+            material,
+            transform: Matrix4::new_translation(&Vector3::new(0., 0., z)),
+            ..Default::default()
+        };
+        let packet = crate::FramePacket {
+            objects: vec![
+                at(opaque, 0.),
+                at(blended, 1.),
+                at(plain, 2.),
+                at(blended, 3.),
+            ],
+            ..Default::default()
+        };
+
+        // As if an instanced object's 5 instances came first
+        let first_batched = 5;
+        let (_, mut batched) =
+            opaque_queue(&materials, &packet, &[], first_batched, true, |_| true);
+        assert_eq!(batched.len(), 1);
+
+        let cameras = [Matrix4::identity()];
+        let queue = transparent_queue(
+            &materials,
+            &packet,
+            &[],
+            first_batched,
+            &mut batched,
+            &cameras,
+            |_| true,
+        );
+        assert_eq!(batched.len(), 3);
+
+        // Farthest first, with each batch pointing at the object's own transform
+        let depths = queue
+            .iter()
+            .map(|draw| match *draw {
+                Draw::Batch(object, first, 1) => {
+                    assert_eq!(object.material, blended);
+                    let transform = batched[(first - first_batched) as usize];
+                    assert_eq!(transform, object.transform);
+                    transform[(2, 3)]
+                }
+                Draw::Object(object) => {
+                    assert_eq!(object.material, plain);
+                    object.transform[(2, 3)]
+                }
+                _ => panic!("Unexpected draw"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(depths, vec![3., 2., 1.]);
+    }
+
+    #[test]
+    fn header_version_reads_the_define() {
+        assert_eq!(
+            header_version(crate::KLYSTRON_GLSL.as_bytes()),
+            INTERFACE_VERSION
+        );
+        assert_eq!(header_version(b"#define KLYSTRON_INTERFACE 42\n"), 42);
+        assert_eq!(
+            header_version(b"// KLYSTRON_INTERFACE 3\n#define KLYSTRON_INTERFACE 12"),
+            12
+        );
+    }
+
+    #[test]
+    fn header_version_is_zero_without_a_define() {
+        assert_eq!(header_version(b""), 0);
+        assert_eq!(header_version(b"#define KLYSTRON_GLSL\n"), 0);
+        assert_eq!(header_version(b"#define KLYSTRON_INTERFACE"), 0);
+        assert_eq!(header_version(b"#define KLYSTRON_INTERFACE x\n"), 0);
+    }
+}
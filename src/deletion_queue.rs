@@ -0,0 +1,105 @@
+use crate::compute::{ComputeMaterial, StorageBuffer};
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::post::PostEffect;
+use crate::recorder::Recorder;
+use crate::texture::Texture;
+use anyhow::Result;
+use std::collections::VecDeque;
+use vk_core::SharedCore;
+
+/// A resource which has been removed, but may still be used by frames in flight
+pub enum Retired {
+    Mesh(Mesh),
+    Material(Material),
+    Texture(Texture),
+    StorageBuffer(StorageBuffer),
+    ComputeMaterial(ComputeMaterial),
+    PostEffect(PostEffect),
+    Recorder(Recorder),
+}
+
+/// Removed resources, held until every frame which may reference them has finished
+pub struct DeletionQueue<T> {
+    /// Each resource along with the last frame which may use it, in the order they were removed
+    pending: VecDeque<(u64, T)>,
+}
+
+impl<T> Default for DeletionQueue<T> {
+    fn default() -> Self {
+        Self {
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T> DeletionQueue<T> {
+    /// Hold a resource until frame `last_frame` (see `FrameSync::frame_number()`) and all those
+    /// before it have finished
+    pub fn push(&mut self, last_frame: u64, resource: T) {
+        self.pending.push_back((last_frame, resource));
+    }
+
+    /// Take every resource whose frames have finished, given the number of the last frame known
+    /// to have done so, in the order they were pushed
+    pub fn collect(&mut self, completed_frame: u64) -> impl Iterator<Item = T> + '_ {
+        let due = self
+            .pending
+            .iter()
+            .take_while(|(last_frame, _)| *last_frame <= completed_frame)
+            .count();
+        self.pending.drain(..due).map(|(_, resource)| resource)
+    }
+
+    /// Take everything in the queue. The device must be idle before it is destroyed.
+    pub fn flush(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.pending.drain(..).map(|(_, resource)| resource)
+    }
+}
+
+impl Retired {
+    /// Free the resource. Frames which used it must have finished.
+    pub fn destroy(self, prelude: &SharedCore) -> Result<()> {
+        match self {
+            Retired::Mesh(mesh) => mesh.free(prelude),
+            Retired::StorageBuffer(buffer) => buffer.buffer.free(prelude),
+            // These free themselves when dropped
+            Retired::Material(_)
+            | Retired::Texture(_)
+            | Retired::ComputeMaterial(_)
+            | Retired::PostEffect(_)
+            | Retired::Recorder(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_waits_for_frames() {
+        let mut queue = DeletionQueue::default();
+        queue.push(1, 10);
+        queue.push(1, 11);
+        queue.push(2, 20);
+        queue.push(4, 40);
+
+        assert_eq!(queue.collect(0).collect::<Vec<u32>>(), vec![]);
+        assert_eq!(queue.collect(1).collect::<Vec<_>>(), vec![10, 11]);
+        assert_eq!(queue.collect(1).collect::<Vec<_>>(), vec![]);
+        assert_eq!(queue.collect(3).collect::<Vec<_>>(), vec![20]);
+        assert_eq!(queue.collect(10).collect::<Vec<_>>(), vec![40]);
+        assert_eq!(queue.flush().count(), 0);
+    }
+
+    #[test]
+    fn flush_takes_everything() {
+        let mut queue = DeletionQueue::default();
+        queue.push(5, 50);
+        queue.push(6, 60);
+
+        assert_eq!(queue.flush().collect::<Vec<u32>>(), vec![50, 60]);
+        assert_eq!(queue.collect(u64::MAX).count(), 0);
+    }
+}
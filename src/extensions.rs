@@ -1,4 +1,4 @@
-use erupt::cstr;
+use erupt::{cstr, vk1_0 as vk, InstanceLoader};
 
 pub fn extensions_and_layers(
     instance_layers: &mut Vec<*const i8>,
@@ -16,3 +16,19 @@ pub fn extensions_and_layers(
         device_layers.push(LAYER_KHRONOS_VALIDATION);
     }
 }
+
+/// Optional device features the engine makes use of, limited to those the hardware supports
+pub fn device_features(
+    instance: &InstanceLoader,
+    physical_device: vk::PhysicalDevice,
+) -> vk::PhysicalDeviceFeatures {
+    let supported = unsafe { instance.get_physical_device_features(physical_device) };
+    vk::PhysicalDeviceFeatures {
+        // Allows 32-bit indices to reach past 2^24 vertices
+        full_draw_index_uint32: supported.full_draw_index_uint32,
+        // Material line widths and wireframes
+        wide_lines: supported.wide_lines,
+        fill_mode_non_solid: supported.fill_mode_non_solid,
+        ..Default::default()
+    }
+}
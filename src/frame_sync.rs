@@ -6,6 +6,8 @@ use erupt::{vk1_0 as vk, DeviceLoader};
 pub struct FrameSync {
     frames: Vec<Frame>,
     frame_idx: usize,
+    /// Number of frames started so far
+    frame_number: u64,
     prelude: SharedCore,
 }
 
@@ -26,6 +28,7 @@ impl FrameSync {
         Ok(Self {
             frames,
             frame_idx: 0,
+            frame_number: 0,
             prelude,
         })
     }
@@ -39,12 +42,25 @@ impl FrameSync {
                 .wait_for_fences(&[frame.in_flight_fence], true, u64::MAX)
                 .result()?;
         }
+        self.frame_number += 1;
         Ok((self.frame_idx, frame))
     }
 
     pub fn current_frame(&self) -> usize {
         self.frame_idx
     }
+
+    /// Number of the frame most recently started, counting from 1. Zero before the first frame.
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
+    }
+
+    /// Number of the last frame known to have finished on the device. Frames before it have too,
+    /// since they were submitted to the same queue.
+    pub fn completed_frame(&self) -> u64 {
+        // Starting a frame waits on the fence of the one which last used its slot
+        self.frame_number.saturating_sub(self.frames.len() as u64)
+    }
 }
 
 impl Drop for FrameSync {
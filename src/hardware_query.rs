@@ -19,31 +19,35 @@ impl HardwareSelection {
         surface: khr_surface::SurfaceKHR,
         device_extensions: &[*const c_char],
     ) -> Result<Self> {
-        unsafe { instance.enumerate_physical_devices(None) }
-            .unwrap()
-            .into_iter()
-            .filter_map(|physical_device| unsafe {
-                let queue_family = match instance
-                    .get_physical_device_queue_family_properties(physical_device, None)
-                    .into_iter()
-                    .enumerate()
-                    .position(|(i, properties)| {
-                        properties.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                            && instance
-                                .get_physical_device_surface_support_khr(
-                                    physical_device,
-                                    i as u32,
-                                    surface,
-                                )
-                                .unwrap()
-                    }) {
-                    Some(queue_family) => queue_family as u32,
-                    None => return None,
+        select_device(
+            instance,
+            device_extensions,
+            |physical_device, properties| unsafe {
+                let mut queue_family = None;
+                let families =
+                    instance.get_physical_device_queue_family_properties(physical_device, None);
+                for (i, family) in families.iter().enumerate() {
+                    if family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                        && instance
+                            .get_physical_device_surface_support_khr(
+                                physical_device,
+                                i as u32,
+                                surface,
+                            )
+                            .result()?
+                    {
+                        queue_family = Some(i as u32);
+                        break;
+                    }
+                }
+                let queue_family = match queue_family {
+                    Some(queue_family) => queue_family,
+                    None => return Ok(None),
                 };
 
                 let formats = instance
                     .get_physical_device_surface_formats_khr(physical_device, surface, None)
-                    .unwrap();
+                    .result()?;
                 let format = match formats
                     .iter()
                     .find(|surface_format| {
@@ -54,44 +58,109 @@ impl HardwareSelection {
                     .or_else(|| formats.get(0))
                 {
                     Some(surface_format) => surface_format.clone(),
-                    None => return None,
+                    None => return Ok(None),
                 };
 
                 let present_mode = instance
                     .get_physical_device_surface_present_modes_khr(physical_device, surface, None)
-                    .unwrap()
+                    .result()?
                     .into_iter()
-                    .find(|present_mode| present_mode == &khr_surface::PresentModeKHR::MAILBOX_KHR)
+                    .find(|mode| *mode == khr_surface::PresentModeKHR::MAILBOX_KHR)
                     .unwrap_or(khr_surface::PresentModeKHR::FIFO_KHR);
 
-                let supported_extensions = instance
-                    .enumerate_device_extension_properties(physical_device, None, None)
-                    .unwrap();
-                if !device_extensions.iter().all(|device_extension| {
-                    let device_extension = CStr::from_ptr(*device_extension);
-
-                    supported_extensions.iter().any(|properties| {
-                        CStr::from_ptr(properties.extension_name.as_ptr()) == device_extension
-                    })
-                }) {
-                    return None;
-                }
-
-                let physical_device_properties =
-                    instance.get_physical_device_properties(physical_device);
-                Some(Self {
+                Ok(Some(Self {
                     physical_device,
                     queue_family,
                     format,
                     present_mode,
-                    physical_device_properties,
-                })
-            })
-            .max_by_key(|query| match query.physical_device_properties.device_type {
-                vk::PhysicalDeviceType::DISCRETE_GPU => 2,
-                vk::PhysicalDeviceType::INTEGRATED_GPU => 1,
-                _ => 0,
-            })
-            .ok_or_else(|| anyhow::format_err!("No suitable hardware found for this configuration"))
+                    physical_device_properties: properties,
+                }))
+            },
+        )?
+        .ok_or_else(|| anyhow::format_err!("No suitable hardware found for this configuration"))
+    }
+}
+
+/// Hardware selection for the headless backend, which has no surface to present to
+#[derive(Debug)]
+pub struct OffscreenHardwareSelection {
+    pub physical_device: vk::PhysicalDevice,
+    pub physical_device_properties: vk::PhysicalDeviceProperties,
+    pub queue_family: u32,
+}
+
+impl OffscreenHardwareSelection {
+    /// Query for hardware with a graphics queue. Software implementations such as lavapipe are
+    /// accepted, but real GPUs are preferred when present.
+    pub fn query(instance: &InstanceLoader, device_extensions: &[*const c_char]) -> Result<Self> {
+        select_device(
+            instance,
+            device_extensions,
+            |physical_device, properties| {
+                let queue_family = unsafe {
+                    instance.get_physical_device_queue_family_properties(physical_device, None)
+                }
+                .into_iter()
+                .position(|family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS));
+
+                Ok(queue_family.map(|queue_family| Self {
+                    physical_device,
+                    queue_family: queue_family as u32,
+                    physical_device_properties: properties,
+                }))
+            },
+        )?
+        .ok_or_else(|| anyhow::format_err!("No suitable hardware found for offscreen rendering"))
     }
 }
+
+/// The most preferred device which supports every extension in `device_extensions`, and for
+/// which `select` returns a selection. Discrete GPUs are preferred, then integrated and virtual
+/// GPUs, then anything else (such as software implementations).
+fn select_device<T>(
+    instance: &InstanceLoader,
+    device_extensions: &[*const c_char],
+    mut select: impl FnMut(vk::PhysicalDevice, vk::PhysicalDeviceProperties) -> Result<Option<T>>,
+) -> Result<Option<T>> {
+    let mut best: Option<(u32, T)> = None;
+    for physical_device in unsafe { instance.enumerate_physical_devices(None) }.result()? {
+        if !supports_extensions(instance, physical_device, device_extensions)? {
+            continue;
+        }
+
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let score = match properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 3,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 2,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+            _ => 0,
+        };
+        if let Some(selection) = select(physical_device, properties)? {
+            // Later devices win ties
+            if best
+                .as_ref()
+                .is_none_or(|(best_score, _)| score >= *best_score)
+            {
+                best = Some((score, selection));
+            }
+        }
+    }
+    Ok(best.map(|(_, selection)| selection))
+}
+
+/// Whether a device supports every one of the given extensions
+fn supports_extensions(
+    instance: &InstanceLoader,
+    physical_device: vk::PhysicalDevice,
+    device_extensions: &[*const c_char],
+) -> Result<bool> {
+    let supported_extensions =
+        unsafe { instance.enumerate_device_extension_properties(physical_device, None, None) }
+            .result()?;
+    Ok(device_extensions.iter().all(|device_extension| {
+        let device_extension = unsafe { CStr::from_ptr(*device_extension) };
+        supported_extensions.iter().any(|properties| unsafe {
+            CStr::from_ptr(properties.extension_name.as_ptr()) == device_extension
+        })
+    }))
+}
@@ -0,0 +1,454 @@
+use crate::core::{Core, RenderTarget, COLOR_FORMAT, FRAMES_IN_FLIGHT};
+use crate::frame_sync::Frame;
+use crate::hardware_query::OffscreenHardwareSelection;
+use crate::readback::{Capture, Readback};
+use crate::swapchain_images::SwapChainImage;
+use crate::{
+    Camera, ComputeMaterial, Engine, FramePacket, FrameStats, Indices, Material, MaterialDesc,
+    Mesh, MeshUsage, ObjectParams, PostEffect, StorageBuffer, Texture, TextureBinding,
+    TextureFormat, VertexLayout,
+};
+use anyhow::Result;
+use erupt::{vk1_0 as vk, vk1_1, DeviceLoader, EntryLoader, InstanceLoader};
+use gpu_alloc::GpuAllocator;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::Mutex;
+use vk_core::SharedCore;
+
+/// Headless engine backend. Renders into offscreen images without a window or display, and can
+/// run on software implementations of Vulkan such as lavapipe.
+pub struct HeadlessBackend {
+    extent: vk::Extent2D,
+    images: Vec<(vk::Image, gpu_alloc::MemoryBlock<vk::DeviceMemory>)>,
+    readback: Readback,
+    /// The most recently submitted frame and the image it renders, if any
+    last_frame: Option<(usize, Frame, SwapChainImage)>,
+    prelude: SharedCore,
+    core: Core,
+}
+
+impl HeadlessBackend {
+    /// Create a new engine instance which renders images of the given size.
+    pub fn new(application_name: &str, width: u32, height: u32) -> Result<Self> {
+        // Entry
+        let entry = EntryLoader::new()?;
+
+        // Instance (1.1 for multiview)
+        let application_name = CString::new(application_name)?;
+        let engine_name = CString::new(crate::ENGINE_NAME)?;
+        let app_info = vk::ApplicationInfoBuilder::new()
+            .application_name(&application_name)
+            .application_version(vk::make_api_version(0, 1, 0, 0))
+            .engine_name(&engine_name)
+            .engine_version(crate::engine_version())
+            .api_version(vk::make_api_version(0, 1, 1, 0));
+
+        // Instance and device layers and extensions
+        let mut instance_layers = Vec::new();
+        let mut instance_extensions = Vec::new();
+        let mut device_layers = Vec::new();
+        let mut device_extensions = Vec::new();
+
+        crate::extensions::extensions_and_layers(
+            &mut instance_layers,
+            &mut instance_extensions,
+            &mut device_layers,
+            &mut device_extensions,
+        );
+
+        // Instance creation
+        let create_info = vk::InstanceCreateInfoBuilder::new()
+            .application_info(&app_info)
+            .enabled_extension_names(&instance_extensions)
+            .enabled_layer_names(&instance_layers);
+
+        let instance = unsafe { InstanceLoader::new(&entry, &create_info, None)? };
+
+        // Hardware selection
+        let hardware = OffscreenHardwareSelection::query(&instance, &device_extensions)?;
+
+        // Create logical device and queues
+        let queue_create_info = [vk::DeviceQueueCreateInfoBuilder::new()
+            .queue_family_index(hardware.queue_family)
+            .queue_priorities(&[1.0])];
+
+        let physical_device_features =
+            crate::extensions::device_features(&instance, hardware.physical_device);
+        let mut create_info = vk::DeviceCreateInfoBuilder::new()
+            .queue_create_infos(&queue_create_info)
+            .enabled_features(&physical_device_features)
+            .enabled_extension_names(&device_extensions)
+            .enabled_layer_names(&device_layers);
+
+        let mut multiview_features = vk1_1::PhysicalDeviceMultiviewFeatures {
+            multiview: vk::TRUE,
+            ..Default::default()
+        };
+        create_info.p_next = &mut multiview_features as *mut _ as _;
+
+        let device =
+            unsafe { DeviceLoader::new(&instance, hardware.physical_device, &create_info, None)? };
+        let queue = unsafe { device.get_device_queue(hardware.queue_family, 0) };
+
+        let device_props =
+            unsafe { gpu_alloc_erupt::device_properties(&instance, hardware.physical_device)? };
+        let allocator = Mutex::new(GpuAllocator::new(
+            gpu_alloc::Config::i_am_prototyping(),
+            device_props,
+        ));
+
+        let prelude = SharedCore::new(vk_core::Core {
+            queue,
+            device,
+            instance,
+            allocator,
+            _entry: entry,
+        });
+
+        let meta = vk_core::CoreMeta {
+            queue_family_index: hardware.queue_family,
+            physical_device: hardware.physical_device,
+        };
+
+        let core = Core::new(prelude.clone(), meta, RenderTarget::Offscreen)?;
+
+        let readback = Readback::new(prelude.clone(), core.command_pool)?;
+
+        Ok(Self {
+            extent: vk::Extent2D { width, height },
+            images: Vec::new(),
+            readback,
+            last_frame: None,
+            prelude,
+            core,
+        })
+    }
+
+    /// Render a frame of video into an offscreen image. Use `read_frame()` to retrieve it.
+    pub fn next_frame(&mut self, packet: &FramePacket, camera: &dyn Camera) -> Result<()> {
+        self.core.prepare_background(packet.background)?;
+
+        // The core frees its attachments whenever its configuration changes
+        if self.core.swapchain_images.is_none() {
+            self.free_images()?;
+            self.create_images()?;
+        }
+
+        let (frame_idx, frame) = self.core.frame_sync.next_frame()?;
+
+        // There is one offscreen image per frame in flight, so the indices are the same
+        let image = self
+            .core
+            .swapchain_images
+            .as_mut()
+            .unwrap()
+            .next_image(frame_idx as u32, &frame)?;
+
+        // Write command buffers
+        let camera_matrix = camera.matrix(image.extent.width, image.extent.height);
+        let command_buffer =
+            self.core
+                .write_command_buffers(frame_idx, packet, &image, &[camera_matrix])?;
+
+        // Upload camera matrix
+        let mut data = [0.0; 32];
+        data.iter_mut()
+            .zip(camera_matrix.as_slice().iter())
+            .for_each(|(o, i)| *o = *i);
+        self.core.update_camera_data(frame_idx, &data)?;
+
+        // Submit to the queue
+        self.submit(command_buffer, &frame)?;
+
+        self.last_frame = Some((frame_idx, frame, image));
+
+        Ok(())
+    }
+
+    /// Wait for the most recent frame to finish rendering, and copy it into host memory. Frames
+    /// are only copied when they are read.
+    pub fn read_frame(&mut self) -> Result<Capture> {
+        let (frame_idx, frame, image) = self
+            .last_frame
+            .ok_or_else(|| anyhow::format_err!("No frame has been rendered yet"))?;
+        self.wait(&frame)?;
+
+        // The image keeps its contents until the next frame with this index, so the copy can run
+        // on its own after the frame's fence
+        let command_buffer = self.readback.record(
+            frame_idx,
+            &image,
+            RenderTarget::Offscreen.final_layout(),
+            COLOR_FORMAT,
+        )?;
+        self.submit(command_buffer, &frame)?;
+        self.wait(&frame)?;
+
+        self.readback.read(frame_idx)
+    }
+
+    /// Size of the rendered images
+    pub fn extent(&self) -> (u32, u32) {
+        (self.extent.width, self.extent.height)
+    }
+
+    /// Change the size of the rendered images, starting with the next frame
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<()> {
+        self.free_images()?;
+        self.extent = vk::Extent2D { width, height };
+        Ok(())
+    }
+
+    fn create_images(&mut self) -> Result<()> {
+        for _ in 0..FRAMES_IN_FLIGHT {
+            let create_info = vk::ImageCreateInfoBuilder::new()
+                .image_type(vk::ImageType::_2D)
+                .extent(
+                    vk::Extent3DBuilder::new()
+                        .width(self.extent.width)
+                        .height(self.extent.height)
+                        .depth(1)
+                        .build(),
+                )
+                .mip_levels(1)
+                .array_layers(1)
+                .format(COLOR_FORMAT)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .usage(
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                )
+                .samples(vk::SampleCountFlagBits::_1)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE);
+            let image = unsafe { self.prelude.device.create_image(&create_info, None) }.result()?;
+
+            let requirements = unsafe { self.prelude.device.get_image_memory_requirements(image) };
+
+            use gpu_alloc::UsageFlags as UF;
+            let request = gpu_alloc::Request {
+                size: requirements.size,
+                align_mask: requirements.alignment,
+                usage: UF::FAST_DEVICE_ACCESS,
+                memory_types: requirements.memory_type_bits,
+            };
+
+            let memory = unsafe {
+                self.prelude
+                    .allocator()?
+                    .alloc(EruptMemoryDevice::wrap(&self.prelude.device), request)?
+            };
+
+            unsafe {
+                self.prelude
+                    .device
+                    .bind_image_memory(image, *memory.memory(), memory.offset())
+                    .result()?;
+            }
+
+            self.images.push((image, memory));
+        }
+
+        let images = self.images.iter().map(|(image, _)| *image).collect();
+        self.core.create_swapchain_images(self.extent, images)
+    }
+
+    /// Submit a command buffer to the queue, signaling the frame's fence once it has executed
+    fn submit(&self, command_buffer: vk::CommandBuffer, frame: &Frame) -> Result<()> {
+        let command_buffers = [command_buffer];
+        let submit_info = vk::SubmitInfoBuilder::new().command_buffers(&command_buffers);
+        unsafe {
+            self.prelude
+                .device
+                .reset_fences(&[frame.in_flight_fence])
+                .result()?;
+            self.prelude
+                .device
+                .queue_submit(
+                    self.prelude.queue,
+                    &[submit_info],
+                    Some(frame.in_flight_fence),
+                )
+                .result()?;
+        }
+        Ok(())
+    }
+
+    fn wait(&self, frame: &Frame) -> Result<()> {
+        unsafe {
+            self.prelude
+                .device
+                .wait_for_fences(&[frame.in_flight_fence], true, u64::MAX)
+                .result()?;
+        }
+        Ok(())
+    }
+
+    fn free_images(&mut self) -> Result<()> {
+        unsafe {
+            self.prelude.device.device_wait_idle().result()?;
+        }
+
+        // Frames rendered into the old images can no longer be read
+        self.last_frame = None;
+
+        // Framebuffers and views first, as they reference the images
+        drop(self.core.swapchain_images.take());
+
+        for (image, memory) in self.images.drain(..) {
+            unsafe {
+                self.prelude.device.destroy_image(Some(image), None);
+                self.prelude
+                    .allocator()?
+                    .dealloc(EruptMemoryDevice::wrap(&self.prelude.device), memory);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// TODO: This is stupid.
+impl Engine for HeadlessBackend {
+    fn add_material(
+        &mut self,
+        vertex: &[u8],
+        fragment: &[u8],
+        desc: MaterialDesc,
+    ) -> Result<Material> {
+        self.core.add_material(vertex, fragment, desc)
+    }
+    fn add_material_from_files(
+        &mut self,
+        vertex: &Path,
+        fragment: &Path,
+        desc: MaterialDesc,
+    ) -> Result<Material> {
+        self.core.add_material_from_files(vertex, fragment, desc)
+    }
+    fn add_mesh_raw(
+        &mut self,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+        usage: MeshUsage,
+    ) -> Result<Mesh> {
+        self.core.add_mesh(layout, vertices, indices, usage)
+    }
+    fn update_mesh_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<()> {
+        self.core.update_mesh(mesh, layout, vertices, indices)
+    }
+    fn update_mesh_vertices_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        first_vertex: usize,
+        vertices: &[u8],
+    ) -> Result<()> {
+        self.core.update_mesh_vertices(mesh, layout, first_vertex, vertices)
+    }
+    fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: &[u8],
+    ) -> Result<Texture> {
+        self.core.add_texture(width, height, format, data)
+    }
+    fn remove_texture(&mut self, texture: Texture) -> Result<()> {
+        self.core.remove_texture(texture)
+    }
+    fn set_material_textures(
+        &mut self,
+        material: Material,
+        textures: &[TextureBinding],
+    ) -> Result<()> {
+        self.core.set_material_textures(material, textures)
+    }
+    fn set_material_params(&mut self, material: Material, data: &[u8]) -> Result<()> {
+        self.core.set_material_params(material, data)
+    }
+    fn remove_material(&mut self, material: Material) -> Result<()> {
+        self.core.remove_material(material)
+    }
+    fn remove_mesh(&mut self, mesh: Mesh) -> Result<()> {
+        self.core.remove_mesh(mesh)
+    }
+    fn update_time_value(&mut self, data: f32) -> Result<()> {
+        self.core.update_time_value(data)
+    }
+    fn set_msaa_samples(&mut self, samples: u32) -> Result<()> {
+        self.core.set_msaa_samples(samples)
+    }
+    fn max_msaa_samples(&self) -> u32 {
+        self.core.max_msaa_samples()
+    }
+    fn frame_stats(&self) -> FrameStats {
+        self.core.stats
+    }
+    fn set_recording_threads(&mut self, threads: usize) -> Result<()> {
+        self.core.set_recording_threads(threads)
+    }
+    fn set_draw_batching(&mut self, enabled: bool) {
+        self.core.batch_draws = enabled;
+    }
+    fn add_post_effect(&mut self, fragment: &[u8]) -> Result<PostEffect> {
+        self.core.add_post_effect(fragment)
+    }
+    fn set_post_effect_params(&mut self, effect: PostEffect, params: ObjectParams) -> Result<()> {
+        self.core.set_post_effect_params(effect, params)
+    }
+    fn remove_post_effect(&mut self, effect: PostEffect) -> Result<()> {
+        self.core.remove_post_effect(effect)
+    }
+
+    fn add_compute_material(&mut self, compute: &[u8]) -> Result<ComputeMaterial> {
+        self.core.add_compute_material(compute)
+    }
+
+    fn remove_compute_material(&mut self, material: ComputeMaterial) -> Result<()> {
+        self.core.remove_compute_material(material)
+    }
+
+    fn add_storage_buffer(&mut self, data: &[u8]) -> Result<StorageBuffer> {
+        self.core.add_storage_buffer(data)
+    }
+
+    fn update_storage_buffer(
+        &mut self,
+        buffer: StorageBuffer,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        self.core.update_storage_buffer(buffer, offset, data)
+    }
+
+    fn remove_storage_buffer(&mut self, buffer: StorageBuffer) -> Result<()> {
+        self.core.remove_storage_buffer(buffer)
+    }
+
+    fn add_storage_mesh(
+        &mut self,
+        layout: &VertexLayout,
+        buffer: StorageBuffer,
+        indices: Indices,
+    ) -> Result<Mesh> {
+        self.core.add_storage_mesh(layout, buffer, indices)
+    }
+}
+
+impl Drop for HeadlessBackend {
+    fn drop(&mut self) {
+        self.free_images().unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use crate::core::{AllocatedBuffer, FRAMES_IN_FLIGHT};
+use crate::InstancedObject;
+use anyhow::Result;
+use erupt::vk1_0 as vk;
+use gpu_alloc::UsageFlags as UF;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use nalgebra::Matrix4;
+use vk_core::SharedCore;
+
+/// Binding of the instance storage buffer in descriptor set 0
+pub const INSTANCE_BINDING: u32 = 2;
+
+/// Number of instances each frame's buffer starts out with room for
+const INITIAL_CAPACITY: usize = 1024;
+
+/// Per-instance data as laid out in the instance storage buffer (std430)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct InstanceData {
+    pub transform: [f32; 16],
+    pub color: [f32; 4],
+}
+
+unsafe impl bytemuck::Zeroable for InstanceData {}
+unsafe impl bytemuck::Pod for InstanceData {}
+
+/// One storage buffer of instance data per frame in flight, each bound to that frame's descriptor
+/// set. Grows as needed.
+pub struct InstanceBuffers {
+    buffers: Vec<(AllocatedBuffer, usize)>,
+    scratch: Vec<InstanceData>,
+}
+
+impl InstanceBuffers {
+    pub fn new(prelude: &SharedCore, descriptor_sets: &[vk::DescriptorSet]) -> Result<Self> {
+        let mut buffers = Vec::with_capacity(FRAMES_IN_FLIGHT);
+        for descriptor_set in descriptor_sets {
+            let buffer = new_buffer(prelude, INITIAL_CAPACITY)?;
+            write_descriptor(prelude, *descriptor_set, &buffer);
+            buffers.push((buffer, INITIAL_CAPACITY));
+        }
+
+        Ok(Self {
+            buffers,
+            scratch: Vec::new(),
+        })
+    }
+
+    /// Write the instances of every object into this frame's buffer, in order and starting at
+    /// those given by `instance_offsets()`, followed by a white instance for each of the
+    /// `batched` transforms. The frame must not be in flight.
+    pub fn upload(
+        &mut self,
+        prelude: &SharedCore,
+        frame_idx: usize,
+        descriptor_set: vk::DescriptorSet,
+        objects: &[InstancedObject],
+        batched: &[Matrix4<f32>],
+    ) -> Result<()> {
+        self.scratch.clear();
+        for object in objects {
+            for (idx, transform) in object.transforms.iter().enumerate() {
+                let color = object
+                    .colors
+                    .as_ref()
+                    .and_then(|colors| colors.get(idx))
+                    .copied()
+                    .unwrap_or([1.0; 4]);
+                let mut data = InstanceData {
+                    transform: [0.0; 16],
+                    color,
+                };
+                data.transform.copy_from_slice(transform.as_slice());
+                self.scratch.push(data);
+            }
+        }
+        for transform in batched {
+            let mut data = InstanceData {
+                transform: [0.0; 16],
+                color: [1.0; 4],
+            };
+            data.transform.copy_from_slice(transform.as_slice());
+            self.scratch.push(data);
+        }
+
+        if self.scratch.is_empty() {
+            return Ok(());
+        }
+
+        let (buffer, capacity) = &mut self.buffers[frame_idx];
+        if self.scratch.len() > *capacity {
+            *capacity = self.scratch.len().next_power_of_two();
+            let old = std::mem::replace(buffer, new_buffer(prelude, *capacity)?);
+            old.free(prelude)?;
+            write_descriptor(prelude, descriptor_set, buffer);
+        }
+
+        unsafe {
+            buffer.memory.write_bytes(
+                EruptMemoryDevice::wrap(&prelude.device),
+                0,
+                bytemuck::cast_slice(&self.scratch),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Release the buffers. None of them may be in use.
+    pub fn free(&mut self, prelude: &SharedCore) -> Result<()> {
+        for (buffer, _) in self.buffers.drain(..) {
+            buffer.free(prelude)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the first instance of each object in the instance buffer, and the index just past the
+/// last, where batched instances start
+pub fn instance_offsets(objects: &[InstancedObject]) -> (Vec<u32>, u32) {
+    let mut first_instances = Vec::with_capacity(objects.len());
+    let mut end = 0;
+    for object in objects {
+        first_instances.push(end);
+        end += object.transforms.len() as u32;
+    }
+    (first_instances, end)
+}
+
+fn new_buffer(prelude: &SharedCore, capacity: usize) -> Result<AllocatedBuffer> {
+    AllocatedBuffer::new(
+        prelude,
+        (capacity * std::mem::size_of::<InstanceData>()) as u64,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        UF::UPLOAD | UF::HOST_ACCESS,
+    )
+}
+
+fn write_descriptor(
+    prelude: &SharedCore,
+    descriptor_set: vk::DescriptorSet,
+    buffer: &AllocatedBuffer,
+) {
+    let buffer_infos = [vk::DescriptorBufferInfoBuilder::new()
+        .buffer(buffer.buffer)
+        .offset(0)
+        .range(vk::WHOLE_SIZE)];
+
+    let writes = [vk::WriteDescriptorSetBuilder::new()
+        .buffer_info(&buffer_infos)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .dst_set(descriptor_set)
+        .dst_binding(INSTANCE_BINDING)
+        .dst_array_element(0)];
+
+    unsafe {
+        prelude.device.update_descriptor_sets(&writes, &[]);
+    }
+}
@@ -2,30 +2,96 @@
 //! simple, unlit scenes with dynamically placed objects. VR capable through the OpenXR
 //! interface, and hopefully easily modifiable.
 extern crate openxr as xr;
+mod bounds;
+mod compile;
+mod compute;
 mod core;
+mod deletion_queue;
 mod extensions;
 mod frame_sync;
 mod hardware_query;
+mod headless;
+mod instances;
 mod material;
+mod material_set;
+mod mesh;
+mod pipeline_cache;
+mod post;
+mod readback;
+mod recorder;
+mod reflect;
 mod runtime;
 pub use runtime::{runtime_2d, runtime_3d};
+mod staging;
 mod swapchain_images;
+mod texture;
 mod vertex;
 mod vr;
+mod watch;
 mod windowed;
 use anyhow::Result;
+pub use compile::{compile_shader, ShaderLanguage, ShaderSource, ShaderStage};
+pub use headless::HeadlessBackend;
 pub use nalgebra::Matrix4;
-pub use vertex::Vertex;
+pub use readback::Capture;
+pub use vertex::{Vertex, VertexAttribute, VertexFormat, VertexLayout, VertexType};
 pub use vr::{XrPrelude, OpenXrBackend};
 pub use windowed::{Camera, PerspectiveCamera, WinitBackend};
 use slotmap::new_key_type;
+use std::path::Path;
 
 /// All information necessary to define a frame of video (besides camera, which is passed in a
 /// special camera for windowed mode and implicitly in OpenXR)
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct FramePacket {
     /// The entire scene's worth of objects
     pub objects: Vec<Object>,
+    /// Objects drawn many times over with a single draw call
+    pub instanced: Vec<InstancedObject>,
+    /// What is behind the objects
+    pub background: Background,
+    /// How OpenXR composites the frame with the real world. Ignored outside of VR.
+    pub environment_blend: EnvironmentBlend,
+    /// Compute shader invocations, run in order before any objects are drawn
+    pub dispatches: Vec<Dispatch>,
+}
+
+/// How each frame starts out, before any objects are drawn
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Background {
+    /// Clear to a single color (linear RGBA)
+    Color([f32; 4]),
+    /// Fade from `top` to `bottom` down the screen (or each eye, in VR)
+    Gradient { top: [f32; 4], bottom: [f32; 4] },
+    /// Draw over the previous frame, for trails and accumulation. The first time this is used,
+    /// the engine switches to a color image which persists between frames, so the frame it is
+    /// requested on starts out black.
+    Keep,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color([0.0, 0.0, 0.0, 1.0])
+    }
+}
+
+/// How a VR frame is combined with the user's surroundings. Modes the runtime doesn't support
+/// fall back to the runtime's preferred mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EnvironmentBlend {
+    /// The frame replaces the real world
+    Opaque,
+    /// The frame is added to the real world, as on see-through displays
+    Additive,
+    /// The frame is blended over the real world using its alpha channel (passthrough). Pair
+    /// with a transparent background.
+    AlphaBlend,
+}
+
+impl Default for EnvironmentBlend {
+    fn default() -> Self {
+        EnvironmentBlend::Opaque
+    }
 }
 
 /// A single object in the scene
@@ -37,6 +103,112 @@ pub struct Object {
     pub mesh: Mesh,
     /// Transformation applied to each vertex of this Object
     pub transform: Matrix4<f32>,
+    /// Shader parameters for this object only
+    pub params: ObjectParams,
+}
+
+impl Default for Object {
+    fn default() -> Self {
+        Self {
+            material: Material::default(),
+            mesh: Mesh::default(),
+            transform: Matrix4::identity(),
+            params: [0.0; 16],
+        }
+    }
+}
+
+/// Per-object shader parameters. These follow the model matrix in the push constant block, which
+/// both vertex and fragment shaders can read:
+/// ```glsl
+/// layout(push_constant) uniform Model {
+///     mat4 model;
+///     vec4 params[4];
+/// };
+/// ```
+pub type ObjectParams = [f32; 16];
+
+/// One mesh drawn once per transform, in a single draw call.
+///
+/// Instance data is available to vertex shaders through a storage buffer at binding 2, indexed
+/// by `gl_InstanceIndex` (see `shaders/instanced.vert`):
+/// ```glsl
+/// struct Instance {
+///     mat4 transform;
+///     vec4 color;
+/// };
+///
+/// layout(std430, binding = 2) readonly buffer Instances {
+///     Instance instances[];
+/// };
+/// ```
+/// The push constant model matrix is set to the identity for instanced draws.
+#[derive(Clone)]
+pub struct InstancedObject {
+    /// How to draw each instance
+    pub material: Material,
+    /// Vertex and Index data shared by every instance
+    pub mesh: Mesh,
+    /// Transformation applied to each instance
+    pub transforms: Vec<Matrix4<f32>>,
+    /// Color of each instance, in the same order as `transforms`. Instances without a color are
+    /// white.
+    pub colors: Option<Vec<[f32; 4]>>,
+    /// Shader parameters shared by every instance
+    pub params: ObjectParams,
+}
+
+/// One compute shader invocation. Each dispatch sees the writes of those before it in the same
+/// frame, and every object drawn that frame sees the writes of all of them.
+///
+/// Buffers are bound in order to descriptor set 0, starting at binding 0, and the push constants
+/// carry the parameters and the animation value:
+/// ```glsl
+/// layout(local_size_x = 64) in;
+/// layout(std430, set = 0, binding = 0) buffer Particles {
+///     float data[];
+/// };
+/// layout(push_constant) uniform Dispatch {
+///     vec4 params[4];
+///     float time;
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct Dispatch {
+    /// Compute shader to run
+    pub material: ComputeMaterial,
+    /// Storage buffers to bind, at most `MAX_DISPATCH_BUFFERS`
+    pub buffers: Vec<StorageBuffer>,
+    /// Number of workgroups in each dimension
+    pub groups: [u32; 3],
+    /// Shader parameters for this dispatch only
+    pub params: ObjectParams,
+}
+
+impl Default for Dispatch {
+    fn default() -> Self {
+        Self {
+            material: ComputeMaterial::default(),
+            buffers: Vec::new(),
+            groups: [1; 3],
+            params: [0.0; 16],
+        }
+    }
+}
+
+/// What happened to the objects of the most recent frame. Objects are culled when their mesh's
+/// bounds lie outside the view of every camera (both eyes, in VR). Instanced objects are always
+/// drawn, and aren't counted.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Objects which were drawn
+    pub drawn: usize,
+    /// Objects which were skipped because they were out of view
+    pub culled: usize,
+    /// Draw calls recorded. Opaque objects whose material's vertex shader reads the instance
+    /// buffer are batched (see `Engine::set_draw_batching()`), so this can be far fewer than the
+    /// objects drawn.
+    pub draw_calls: usize,
 }
 
 new_key_type! {
@@ -45,10 +217,143 @@ new_key_type! {
 
     /// Handle for a Mesh (Draw content)
     pub struct Mesh;
+
+    /// Handle for a Texture (Sampled image data)
+    pub struct Texture;
+
+    /// Handle for a post-processing effect (Full-screen pass over the rendered frame)
+    pub struct PostEffect;
+
+    /// Handle for a ComputeMaterial (Compute commands)
+    pub struct ComputeMaterial;
+
+    /// Handle for a StorageBuffer (Data written by compute shaders)
+    pub struct StorageBuffer;
 }
 
-/// Material rasterization method
+/// Number of storage buffers a single dispatch can bind
+pub const MAX_DISPATCH_BUFFERS: usize = 8;
+
+/// Number of textures a material can bind. Textures are bound as combined image samplers in
+/// descriptor set 1, at bindings `0..MAX_MATERIAL_TEXTURES`:
+/// ```glsl
+/// layout(set = 1, binding = 0) uniform sampler2D tex;
+/// ```
+/// Bindings without a texture read opaque white.
+pub const MAX_MATERIAL_TEXTURES: usize = 4;
+
+/// Binding of the material parameter block (see `MaterialDesc::params_size`) in descriptor set 1,
+/// just after the textures:
+/// ```glsl
+/// layout(set = 1, binding = 4) uniform Params {
+///     vec4 tint;
+///     float threshold;
+/// };
+/// ```
+pub const MATERIAL_PARAMS_BINDING: u32 = MAX_MATERIAL_TEXTURES as u32;
+
+/// Pixel format of texture data
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextureFormat {
+    /// Four bytes per pixel, color in sRGB space
+    Rgba8Srgb,
+    /// Four bytes per pixel, linear
+    Rgba8Unorm,
+    /// One byte per pixel, linear. Useful for lookup tables and masks
+    R8Unorm,
+}
+
+/// Texture filtering method
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Filter {
+    /// Nearest texel, and nearest mip level
+    Nearest,
+    /// Linear interpolation between texels and between mip levels
+    Linear,
+}
+
+/// What happens to texture coordinates outside of 0..1
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Wrap {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+/// How a texture is sampled
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Sampling {
+    pub filter: Filter,
+    pub wrap: Wrap,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            filter: Filter::Linear,
+            wrap: Wrap::Repeat,
+        }
+    }
+}
+
+/// A texture bound to a material, and how to sample it
+#[derive(Copy, Clone, Debug)]
+pub struct TextureBinding {
+    pub texture: Texture,
+    pub sampling: Sampling,
+}
+
+/// Index data for a mesh, in either 16 or 32-bit form
 #[derive(Copy, Clone, Debug)]
+pub enum Indices<'a> {
+    /// Up to 65,536 vertices
+    U16(&'a [u16]),
+    /// For meshes with more vertices than fit in a `u16`
+    U32(&'a [u32]),
+}
+
+impl Indices<'_> {
+    /// Number of indices
+    pub fn len(&self) -> usize {
+        match self {
+            Indices::U16(i) => i.len(),
+            Indices::U32(i) => i.len(),
+        }
+    }
+
+    /// Whether there are no indices
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Raw bytes of the index data
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        match self {
+            Indices::U16(i) => bytemuck::cast_slice(i),
+            Indices::U32(i) => bytemuck::cast_slice(i),
+        }
+    }
+
+    /// Vulkan index type matching this data
+    pub(crate) fn index_type(&self) -> erupt::vk1_0::IndexType {
+        match self {
+            Indices::U16(_) => erupt::vk1_0::IndexType::UINT16,
+            Indices::U32(_) => erupt::vk1_0::IndexType::UINT32,
+        }
+    }
+}
+
+/// How often a mesh is expected to change
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MeshUsage {
+    /// Uploaded once to device-local memory. Updates wait for the GPU to become idle.
+    Static,
+    /// Kept in host-visible memory, with one copy per frame in flight. Updates never wait.
+    Dynamic,
+}
+
+/// Material rasterization method
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DrawType {
     /// Lines in between each pair of indices
     Lines,
@@ -58,26 +363,331 @@ pub enum DrawType {
     Triangles,
 }
 
+/// How a material's output is combined with what has already been drawn
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Blend {
+    /// Overwrite the destination
+    Opaque,
+    /// Mix with the destination using the output's alpha channel
+    Alpha,
+    /// Add the output (scaled by its alpha) to the destination
+    Additive,
+}
+
+/// Which faces to discard
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cull {
+    /// Draw both sides, for double-sided sheets
+    None,
+    Front,
+    Back,
+}
+
+/// Depth comparison; a fragment is drawn when `fragment depth <op> stored depth`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DepthCompare {
+    Never,
+    Less,
+    Equal,
+    LessOrEqual,
+    Greater,
+    NotEqual,
+    GreaterOrEqual,
+    Always,
+}
+
+/// Fixed-function state for a material's pipeline
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialDesc {
+    /// Rasterization method
+    pub draw_type: DrawType,
+    /// Layout of the vertices this material consumes. Only meshes with the same layout are drawn.
+    pub vertex_layout: VertexLayout,
+    /// Blended materials are drawn after opaque ones, sorted back to front
+    pub blend: Blend,
+    /// Faces to discard. Front faces wind counter-clockwise.
+    pub cull: Cull,
+    /// Whether to discard fragments which fail `depth_compare`
+    pub depth_test: bool,
+    /// Whether to write the depth buffer. Blended materials never write depth.
+    pub depth_write: bool,
+    pub depth_compare: DepthCompare,
+    /// Draw triangle edges only. Requires the `fillModeNonSolid` device feature.
+    pub wireframe: bool,
+    /// Width of lines in pixels. Widths other than 1.0 require the `wideLines` device feature.
+    pub line_width: f32,
+    /// Size in bytes of the material's parameter block, which is set with
+    /// `Engine::set_material_params()`. Zero for materials without parameters.
+    pub params_size: usize,
+}
+
+impl MaterialDesc {
+    /// Whether this material goes in the transparent queue
+    pub fn is_transparent(&self) -> bool {
+        self.blend != Blend::Opaque
+    }
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        Self {
+            draw_type: DrawType::Triangles,
+            vertex_layout: Vertex::layout(),
+            blend: Blend::Opaque,
+            cull: Cull::Back,
+            depth_test: true,
+            depth_write: true,
+            depth_compare: DepthCompare::Less,
+            wireframe: false,
+            line_width: 1.0,
+            params_size: 0,
+        }
+    }
+}
+
+impl From<DrawType> for MaterialDesc {
+    fn from(draw_type: DrawType) -> Self {
+        Self {
+            draw_type,
+            ..Default::default()
+        }
+    }
+}
+
 /// Traits all engines must implement; next_frame() not included because all engines have different
 /// per-frame requirements.
 pub trait Engine {
-    /// Add a material, given SPIR-V bytecode
+    /// Add a material, given SPIR-V bytecode and pipeline state. A plain `DrawType` converts into
+    /// a `MaterialDesc` with default state. The shaders' descriptor bindings, push constants,
+    /// vertex inputs and outputs are checked against what the engine provides first.
     fn add_material(
         &mut self,
         vertex: &[u8],
         fragment: &[u8],
-        draw_type: DrawType,
+        desc: MaterialDesc,
+    ) -> Result<Material>;
+    /// Add a material from shader files on disk, for iterating on shaders while the app runs.
+    /// Files ending in `.spv` are read as SPIR-V, `.wgsl` files are compiled as WGSL, and anything
+    /// else as GLSL. The files are watched, and when either changes the material's pipeline is
+    /// rebuilt behind the same handle. If the new shaders fail to compile or link, the error is
+    /// logged and the previous pipeline is kept.
+    fn add_material_from_files(
+        &mut self,
+        vertex: &Path,
+        fragment: &Path,
+        desc: MaterialDesc,
     ) -> Result<Material>;
-    /// Add a mesh, given vertices and indices
-    fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u16]) -> Result<Mesh>;
-    /// Remove the given material
+    /// Add a material from GLSL or WGSL source, compiled in-process (see `compile_shader()`).
+    /// A single WGSL source holding both entry points may be passed for both stages.
+    fn add_material_from_source(
+        &mut self,
+        vertex: ShaderSource,
+        fragment: ShaderSource,
+        desc: MaterialDesc,
+    ) -> Result<Material> {
+        let vertex = compile_shader(vertex, ShaderStage::Vertex)?;
+        let fragment = compile_shader(fragment, ShaderStage::Fragment)?;
+        self.add_material(&vertex, &fragment, desc)
+    }
+    /// Add a mesh, given raw vertex data in the given layout and indices
+    fn add_mesh_raw(
+        &mut self,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+        usage: MeshUsage,
+    ) -> Result<Mesh>;
+    /// Replace the contents of a mesh, reallocating only if it grew. Static meshes wait for the
+    /// GPU to become idle before they are written. The layout must match the mesh's.
+    fn update_mesh_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<()>;
+    /// Overwrite a range of a mesh's vertices, starting at `first_vertex`. The range must lie
+    /// within the mesh's current vertices, and the layout must match the mesh's. The mesh's culling
+    /// bounds only grow; `update_mesh()` tightens them.
+    fn update_mesh_vertices_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        first_vertex: usize,
+        vertices: &[u8],
+    ) -> Result<()>;
+    /// Add a static mesh, given vertices and indices. Static meshes are uploaded to device-local
+    /// memory, and are best for geometry which rarely changes.
+    fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u16]) -> Result<Mesh> {
+        self.add_custom_mesh(vertices, Indices::U16(indices), MeshUsage::Static)
+    }
+    /// Add a mesh with 32-bit indices, for meshes with more than 65,536 vertices
+    fn add_mesh_u32(&mut self, vertices: &[Vertex], indices: &[u32]) -> Result<Mesh> {
+        self.add_custom_mesh(vertices, Indices::U32(indices), MeshUsage::Static)
+    }
+    /// Add a mesh which is expected to change often. Dynamic meshes keep a copy of their data per
+    /// frame in flight, so updating them never waits on the GPU.
+    fn add_dynamic_mesh(&mut self, vertices: &[Vertex], indices: Indices) -> Result<Mesh> {
+        self.add_custom_mesh(vertices, indices, MeshUsage::Dynamic)
+    }
+    /// Replace the contents of a mesh, reallocating only if it grew. Static meshes wait for the
+    /// GPU to become idle before they are written.
+    fn update_mesh(&mut self, mesh: Mesh, vertices: &[Vertex], indices: Indices) -> Result<()> {
+        self.update_custom_mesh(mesh, vertices, indices)
+    }
+    /// Overwrite a range of a mesh's vertices, starting at `first_vertex`. The range must lie
+    /// within the mesh's current vertices.
+    fn update_mesh_vertices(
+        &mut self,
+        mesh: Mesh,
+        first_vertex: usize,
+        vertices: &[Vertex],
+    ) -> Result<()> {
+        self.update_custom_mesh_vertices(mesh, first_vertex, vertices)
+    }
+    /// Add a texture, given tightly packed pixel data, row by row starting at the top. Mips are
+    /// generated automatically.
+    fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: &[u8],
+    ) -> Result<Texture>;
+    /// Remove the given texture. Materials which still bind it read opaque white instead.
+    fn remove_texture(&mut self, texture: Texture) -> Result<()>;
+    /// Bind textures to a material, in order starting at binding 0 of descriptor set 1. Replaces
+    /// any textures bound previously.
+    fn set_material_textures(
+        &mut self,
+        material: Material,
+        textures: &[TextureBinding],
+    ) -> Result<()>;
+    /// Set a material's parameter block (std140 layout), starting from the beginning. Takes
+    /// effect from the next frame on.
+    fn set_material_params(&mut self, material: Material, data: &[u8]) -> Result<()>;
+    /// Remove the given material. Like every other `remove_*` method this doesn't wait for the
+    /// GPU; the material is destroyed once the frames in flight which may use it have finished.
     fn remove_material(&mut self, material: Material) -> Result<()>;
     /// Remove the given mesh
     fn remove_mesh(&mut self, mesh: Mesh) -> Result<()>;
     /// Update the animation value
     fn update_time_value(&mut self, data: f32) -> Result<()>;
+    /// Set the number of samples per pixel for multisample anti-aliasing. 1, the default,
+    /// disables it. Every material is rebuilt, so this is best done once at startup.
+    fn set_msaa_samples(&mut self, samples: u32) -> Result<()>;
+    /// Largest sample count `set_msaa_samples()` accepts on this device
+    fn max_msaa_samples(&self) -> u32;
+    /// Number of objects drawn and culled, and of draw calls, in the most recent frame
+    fn frame_stats(&self) -> FrameStats;
+    /// Record each frame's draws on this many threads. With more than one, the frame's draws are
+    /// split between them in order, each recording secondary command buffers which are
+    /// executed inside the render pass. 1, the default, records everything on the calling
+    /// thread. The frames drawn are identical either way.
+    fn set_recording_threads(&mut self, threads: usize) -> Result<()>;
+    /// Sort opaque objects by material and mesh, and merge consecutive objects which share a
+    /// mesh and parameters into instanced draws. Only objects whose material's vertex shader
+    /// reads the instance buffer at binding 2 (see `InstancedObject`) are merged; the rest are
+    /// still drawn one at a time. On by default. Turned off, opaque objects are drawn in packet
+    /// order with a draw call each, as a baseline to measure batching against.
+    fn set_draw_batching(&mut self, enabled: bool);
+    /// Append a post-processing effect to the end of the chain, given fragment shader SPIR-V.
+    /// While any effects exist, objects are drawn into an intermediate floating-point image,
+    /// and each effect draws a full-screen triangle which reads the previous effect's output (or
+    /// the objects, for the first) and the depth buffer. The last effect writes the final image.
+    /// Effects use this interface, with one layer per view (two in VR):
+    /// ```glsl
+    /// #extension GL_EXT_multiview : require
+    /// layout(set = 0, binding = 0) uniform texture2DArray color;
+    /// layout(set = 0, binding = 1) uniform texture2DArray depth;
+    /// layout(set = 0, binding = 2) uniform sampler linearSampler;
+    /// layout(set = 0, binding = 3) uniform sampler nearestSampler;
+    /// layout(push_constant) uniform Post {
+    ///     vec4 params[4];
+    ///     float time;
+    /// };
+    /// layout(location = 0) in vec2 uv;
+    /// layout(location = 0) out vec4 outColor;
+    ///
+    /// void main() {
+    ///     vec3 coord = vec3(uv, gl_ViewIndex);
+    ///     outColor = texture(sampler2DArray(color, linearSampler), coord);
+    ///     float d = texture(sampler2DArray(depth, nearestSampler), coord).r;
+    /// }
+    /// ```
+    /// With MSAA, effects see the first sample of each pixel's depth. Adding the first effect
+    /// rebuilds every material.
+    fn add_post_effect(&mut self, fragment: &[u8]) -> Result<PostEffect>;
+    /// Set the `params` pushed to a post-processing effect. Takes effect from the next frame on.
+    fn set_post_effect_params(&mut self, effect: PostEffect, params: ObjectParams) -> Result<()>;
+    /// Remove a post-processing effect from the chain
+    fn remove_post_effect(&mut self, effect: PostEffect) -> Result<()>;
+    /// Add a compute material, given compute shader SPIR-V (see `Dispatch` for its interface)
+    fn add_compute_material(&mut self, compute: &[u8]) -> Result<ComputeMaterial>;
+    /// Remove the given compute material
+    fn remove_compute_material(&mut self, material: ComputeMaterial) -> Result<()>;
+    /// Add a device-local storage buffer holding `data`, which may not be empty. Storage buffers
+    /// are shared by every frame, and are only written by dispatches from then on.
+    fn add_storage_buffer(&mut self, data: &[u8]) -> Result<StorageBuffer>;
+    /// Overwrite part of a storage buffer from the CPU, starting at byte `offset`. Waits for the
+    /// GPU to become idle first.
+    fn update_storage_buffer(
+        &mut self,
+        buffer: StorageBuffer,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()>;
+    /// Remove the given storage buffer. Meshes which draw from it are skipped.
+    fn remove_storage_buffer(&mut self, buffer: StorageBuffer) -> Result<()>;
+    /// Add a mesh which draws its vertices, in the given layout, from a storage buffer. Only the
+    /// indices belong to the mesh, and it can't be updated; write the buffer instead.
+    fn add_storage_mesh(
+        &mut self,
+        layout: &VertexLayout,
+        buffer: StorageBuffer,
+        indices: Indices,
+    ) -> Result<Mesh>;
+}
+
+/// Generic helpers for meshes of any `VertexType`, available on every `Engine` (including
+/// `dyn Engine`)
+pub trait EngineExt: Engine {
+    /// Add a mesh of custom vertices
+    fn add_custom_mesh<V: VertexType>(
+        &mut self,
+        vertices: &[V],
+        indices: Indices,
+        usage: MeshUsage,
+    ) -> Result<Mesh> {
+        self.add_mesh_raw(&V::layout(), bytemuck::cast_slice(vertices), indices, usage)
+    }
+    /// Replace the contents of a mesh of custom vertices
+    fn update_custom_mesh<V: VertexType>(
+        &mut self,
+        mesh: Mesh,
+        vertices: &[V],
+        indices: Indices,
+    ) -> Result<()> {
+        self.update_mesh_raw(mesh, &V::layout(), bytemuck::cast_slice(vertices), indices)
+    }
+    /// Overwrite a range of a mesh of custom vertices, starting at `first_vertex`
+    fn update_custom_mesh_vertices<V: VertexType>(
+        &mut self,
+        mesh: Mesh,
+        first_vertex: usize,
+        vertices: &[V],
+    ) -> Result<()> {
+        self.update_mesh_vertices_raw(
+            mesh,
+            &V::layout(),
+            first_vertex,
+            bytemuck::cast_slice(vertices),
+        )
+    }
 }
 
+impl<E: Engine + ?Sized> EngineExt for E {}
+
 pub(crate) const ENGINE_NAME: &str = "Klystron";
 pub(crate) fn engine_version() -> u32 {
     erupt::vk1_0::make_api_version(0, 1, 0, 0)
@@ -87,3 +697,9 @@ pub(crate) fn engine_version() -> u32 {
 pub const UNLIT_FRAG: &[u8] = include_bytes!("../shaders/unlit.frag.spv");
 //#[cfg(feature = "builtin_shaders")]
 pub const UNLIT_VERT: &[u8] = include_bytes!("../shaders/unlit.vert.spv");
+//#[cfg(feature = "builtin_shaders")]
+pub const INSTANCED_VERT: &[u8] = include_bytes!("../shaders/instanced.vert.spv");
+
+/// GLSL header declaring the engine's descriptor sets and push constants, with helpers such as
+/// `klystron_mvp()`. Shaders compiled by the engine may `#include "klystron.glsl"` to get it.
+pub const KLYSTRON_GLSL: &str = include_str!("../shaders/klystron.glsl");
@@ -1,116 +1,70 @@
 use vk_core::SharedCore;
-use crate::vertex::Vertex;
-use crate::DrawType;
+use crate::material_set::MaterialSet;
+use crate::{Blend, Cull, DepthCompare, DrawType, MaterialDesc};
 use anyhow::Result;
 use erupt::{utils, vk1_0 as vk};
 use std::ffi::CString;
 
+/// Shader stages which can read push constants
+pub const PUSH_CONSTANT_STAGES: vk::ShaderStageFlags = vk::ShaderStageFlags::from_bits_truncate(
+    vk::ShaderStageFlags::VERTEX.bits() | vk::ShaderStageFlags::FRAGMENT.bits(),
+);
+
+/// Per-draw data, pushed before each object is drawn
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PushConstants {
+    pub model: [f32; 16],
+    pub params: crate::ObjectParams,
+}
+
+unsafe impl bytemuck::Zeroable for PushConstants {}
+unsafe impl bytemuck::Pod for PushConstants {}
+
 /// Represents a backing pipeline that can render an object
 /// with the from which it was created.
 pub struct Material {
     pub pipeline: vk::Pipeline,
     pub pipeline_layout: vk::PipelineLayout,
+    pub desc: MaterialDesc,
+    /// Textures and parameters, in descriptor set 1
+    pub set: MaterialSet,
+    /// Whether the vertex shader reads transforms from the instance buffer, so that objects
+    /// drawn with this material can be batched into instanced draws
+    pub instanced: bool,
+    /// SPIR-V the pipeline was built from, kept so that it can be rebuilt
+    vertex_src: Vec<u8>,
+    fragment_src: Vec<u8>,
+    /// Pipeline cache owned by the core, used whenever the pipeline is rebuilt
+    cache: vk::PipelineCache,
     prelude: SharedCore,
 }
 
 impl Material {
+    pub fn is_transparent(&self) -> bool {
+        self.desc.is_transparent()
+    }
+
     pub fn new(
         prelude: SharedCore,
+        cache: vk::PipelineCache,
         vertex_src: &[u8],
         fragment_src: &[u8],
-        draw_type: DrawType,
+        desc: MaterialDesc,
         render_pass: vk::RenderPass,
+        samples: vk::SampleCountFlagBits,
         descriptor_set_layout: vk::DescriptorSetLayout,
+        material_set_layout: vk::DescriptorSetLayout,
     ) -> Result<Self> {
-        // Create shader modules
-        let vert_decoded = utils::decode_spv(vertex_src)?;
-        let create_info = vk::ShaderModuleCreateInfoBuilder::new().code(&vert_decoded);
-        let vertex = unsafe {
-            prelude
-                .device
-                .create_shader_module(&create_info, None)
-        }
-        .result()?;
-
-        let frag_decoded = utils::decode_spv(fragment_src)?;
-        let create_info = vk::ShaderModuleCreateInfoBuilder::new().code(&frag_decoded);
-        let fragment = unsafe {
-            prelude
-                .device
-                .create_shader_module(&create_info, None)
-        }
-        .result()?;
+        crate::reflect::check_material(vertex_src, fragment_src, &desc)?;
+        let instanced = crate::reflect::reads_instances(vertex_src)?;
 
-        let attribute_descriptions = Vertex::get_attribute_descriptions();
-        let binding_descriptions = [Vertex::binding_description()];
-
-        // Build pipeline
-        let vertex_input = vk::PipelineVertexInputStateCreateInfoBuilder::new()
-            .vertex_attribute_descriptions(&attribute_descriptions[..])
-            .vertex_binding_descriptions(&binding_descriptions);
-
-        let draw_type = match draw_type {
-            DrawType::Triangles => vk::PrimitiveTopology::TRIANGLE_LIST,
-            DrawType::Points => vk::PrimitiveTopology::POINT_LIST,
-            DrawType::Lines => vk::PrimitiveTopology::LINE_LIST,
-        };
-
-        let input_assembly = vk::PipelineInputAssemblyStateCreateInfoBuilder::new()
-            .topology(draw_type)
-            .primitive_restart_enable(false);
-
-        let viewport_state = vk::PipelineViewportStateCreateInfoBuilder::new()
-            .viewport_count(1)
-            .scissor_count(1);
-
-        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let dynamic_state =
-            vk::PipelineDynamicStateCreateInfoBuilder::new().dynamic_states(&dynamic_states);
-
-        let rasterizer = vk::PipelineRasterizationStateCreateInfoBuilder::new()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .depth_clamp_enable(false);
-
-        let multisampling = vk::PipelineMultisampleStateCreateInfoBuilder::new()
-            .sample_shading_enable(false)
-            .rasterization_samples(vk::SampleCountFlagBits::_1);
-
-        let color_blend_attachments = [vk::PipelineColorBlendAttachmentStateBuilder::new()
-            .color_write_mask(
-                vk::ColorComponentFlags::R
-                    | vk::ColorComponentFlags::G
-                    | vk::ColorComponentFlags::B
-                    | vk::ColorComponentFlags::A,
-            )
-            .blend_enable(false)];
-        let color_blending = vk::PipelineColorBlendStateCreateInfoBuilder::new()
-            .logic_op_enable(false)
-            .attachments(&color_blend_attachments);
-
-        let entry_point = CString::new("main")?;
-
-        let shader_stages = [
-            vk::PipelineShaderStageCreateInfoBuilder::new()
-                .stage(vk::ShaderStageFlagBits::VERTEX)
-                .module(vertex)
-                .name(&entry_point),
-            vk::PipelineShaderStageCreateInfoBuilder::new()
-                .stage(vk::ShaderStageFlagBits::FRAGMENT)
-                .module(fragment)
-                .name(&entry_point),
-        ];
-
-        let descriptor_set_layouts = [descriptor_set_layout];
+        let descriptor_set_layouts = [descriptor_set_layout, material_set_layout];
 
         let push_constant_ranges = [vk::PushConstantRangeBuilder::new()
-            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .stage_flags(PUSH_CONSTANT_STAGES)
             .offset(0)
-            .size(std::mem::size_of::<[f32; 16]>() as u32)];
+            .size(std::mem::size_of::<PushConstants>() as u32)];
 
         let create_info = vk::PipelineLayoutCreateInfoBuilder::new()
             .push_constant_ranges(&push_constant_ranges)
@@ -123,45 +77,253 @@ impl Material {
         }
         .result()?;
 
-        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfoBuilder::new()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS) // TODO: Play with this! For fun!
-            .depth_bounds_test_enable(false)
-            .stencil_test_enable(false);
-
-        let create_info = vk::GraphicsPipelineCreateInfoBuilder::new()
-            .stages(&shader_stages)
-            .vertex_input_state(&vertex_input)
-            .input_assembly_state(&input_assembly)
-            .viewport_state(&viewport_state)
-            .rasterization_state(&rasterizer)
-            .multisample_state(&multisampling)
-            .color_blend_state(&color_blending)
-            .depth_stencil_state(&depth_stencil_state)
-            .dynamic_state(&dynamic_state)
-            .layout(pipeline_layout)
-            .render_pass(render_pass)
-            .subpass(0);
-
-        let pipeline = unsafe {
-            prelude
-                .device
-                .create_graphics_pipelines(None, &[create_info], None)
-        }
-        .result()?[0];
+        let pipeline = create_pipeline(
+            &prelude,
+            cache,
+            vertex_src,
+            fragment_src,
+            &desc,
+            pipeline_layout,
+            render_pass,
+            samples,
+        )?;
 
-        unsafe {
-            prelude.device.destroy_shader_module(Some(fragment), None);
-            prelude.device.destroy_shader_module(Some(vertex), None);
-        }
+        let set = MaterialSet::new(prelude.clone(), material_set_layout, desc.params_size)?;
 
         Ok(Self {
             pipeline,
             pipeline_layout,
+            desc,
+            set,
+            instanced,
+            vertex_src: vertex_src.to_vec(),
+            fragment_src: fragment_src.to_vec(),
+            cache,
             prelude,
         })
     }
+
+    /// Rebuild the pipeline for a new render pass or sample count. The material must not be in
+    /// use by any frame in flight.
+    pub fn rebuild(
+        &mut self,
+        render_pass: vk::RenderPass,
+        samples: vk::SampleCountFlagBits,
+    ) -> Result<()> {
+        let pipeline = create_pipeline(
+            &self.prelude,
+            self.cache,
+            &self.vertex_src,
+            &self.fragment_src,
+            &self.desc,
+            self.pipeline_layout,
+            render_pass,
+            samples,
+        )?;
+        unsafe {
+            self.prelude
+                .device
+                .destroy_pipeline(Some(self.pipeline), None);
+        }
+        self.pipeline = pipeline;
+        Ok(())
+    }
+
+    /// Replace the shaders and rebuild the pipeline. If the new pipeline can't be built, the old
+    /// one and its shaders are kept. The material must not be in use by any frame in flight.
+    pub fn reload(
+        &mut self,
+        vertex_src: &[u8],
+        fragment_src: &[u8],
+        render_pass: vk::RenderPass,
+        samples: vk::SampleCountFlagBits,
+    ) -> Result<()> {
+        crate::reflect::check_material(vertex_src, fragment_src, &self.desc)?;
+        let instanced = crate::reflect::reads_instances(vertex_src)?;
+        let pipeline = create_pipeline(
+            &self.prelude,
+            self.cache,
+            vertex_src,
+            fragment_src,
+            &self.desc,
+            self.pipeline_layout,
+            render_pass,
+            samples,
+        )?;
+        unsafe {
+            self.prelude
+                .device
+                .destroy_pipeline(Some(self.pipeline), None);
+        }
+        self.pipeline = pipeline;
+        self.instanced = instanced;
+        self.vertex_src = vertex_src.to_vec();
+        self.fragment_src = fragment_src.to_vec();
+        Ok(())
+    }
+}
+
+/// Build a graphics pipeline from SPIR-V and pipeline state
+pub fn create_pipeline(
+    prelude: &SharedCore,
+    cache: vk::PipelineCache,
+    vertex_src: &[u8],
+    fragment_src: &[u8],
+    desc: &MaterialDesc,
+    layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    samples: vk::SampleCountFlagBits,
+) -> Result<vk::Pipeline> {
+    let entry_point = CString::new("main")?;
+
+    // Create shader modules. Nothing may return early from here until they are destroyed.
+    let vertex = shader_module(prelude, vertex_src)?;
+    let fragment = match shader_module(prelude, fragment_src) {
+        Ok(module) => module,
+        Err(e) => {
+            unsafe { prelude.device.destroy_shader_module(Some(vertex), None) };
+            return Err(e);
+        }
+    };
+
+    let attribute_descriptions = desc.vertex_layout.attribute_descriptions();
+    let binding_descriptions = [desc.vertex_layout.binding_description()];
+
+    // Build pipeline
+    let vertex_input = vk::PipelineVertexInputStateCreateInfoBuilder::new()
+        .vertex_attribute_descriptions(&attribute_descriptions[..])
+        .vertex_binding_descriptions(&binding_descriptions);
+
+    let draw_type = match desc.draw_type {
+        DrawType::Triangles => vk::PrimitiveTopology::TRIANGLE_LIST,
+        DrawType::Points => vk::PrimitiveTopology::POINT_LIST,
+        DrawType::Lines => vk::PrimitiveTopology::LINE_LIST,
+    };
+
+    let input_assembly = vk::PipelineInputAssemblyStateCreateInfoBuilder::new()
+        .topology(draw_type)
+        .primitive_restart_enable(false);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfoBuilder::new()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state =
+        vk::PipelineDynamicStateCreateInfoBuilder::new().dynamic_states(&dynamic_states);
+
+    let rasterizer = vk::PipelineRasterizationStateCreateInfoBuilder::new()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(match desc.wireframe {
+            true => vk::PolygonMode::LINE,
+            false => vk::PolygonMode::FILL,
+        })
+        .line_width(desc.line_width)
+        .cull_mode(match desc.cull {
+            Cull::None => vk::CullModeFlags::NONE,
+            Cull::Front => vk::CullModeFlags::FRONT,
+            Cull::Back => vk::CullModeFlags::BACK,
+        })
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_clamp_enable(false);
+
+    let multisampling = vk::PipelineMultisampleStateCreateInfoBuilder::new()
+        .sample_shading_enable(false)
+        .rasterization_samples(samples);
+
+    let color_blend_attachment = vk::PipelineColorBlendAttachmentStateBuilder::new()
+        .color_write_mask(
+            vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A,
+        );
+    let color_blend_attachment = match desc.blend {
+        Blend::Opaque => color_blend_attachment.blend_enable(false),
+        Blend::Alpha => color_blend_attachment
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD),
+        Blend::Additive => color_blend_attachment
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD),
+    };
+    let color_blend_attachments = [color_blend_attachment];
+    let color_blending = vk::PipelineColorBlendStateCreateInfoBuilder::new()
+        .logic_op_enable(false)
+        .attachments(&color_blend_attachments);
+
+    let shader_stages = [
+        vk::PipelineShaderStageCreateInfoBuilder::new()
+            .stage(vk::ShaderStageFlagBits::VERTEX)
+            .module(vertex)
+            .name(&entry_point),
+        vk::PipelineShaderStageCreateInfoBuilder::new()
+            .stage(vk::ShaderStageFlagBits::FRAGMENT)
+            .module(fragment)
+            .name(&entry_point),
+    ];
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfoBuilder::new()
+        .depth_test_enable(desc.depth_test)
+        .depth_write_enable(desc.depth_write && !desc.is_transparent())
+        .depth_compare_op(match desc.depth_compare {
+            DepthCompare::Never => vk::CompareOp::NEVER,
+            DepthCompare::Less => vk::CompareOp::LESS,
+            DepthCompare::Equal => vk::CompareOp::EQUAL,
+            DepthCompare::LessOrEqual => vk::CompareOp::LESS_OR_EQUAL,
+            DepthCompare::Greater => vk::CompareOp::GREATER,
+            DepthCompare::NotEqual => vk::CompareOp::NOT_EQUAL,
+            DepthCompare::GreaterOrEqual => vk::CompareOp::GREATER_OR_EQUAL,
+            DepthCompare::Always => vk::CompareOp::ALWAYS,
+        })
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let create_info = vk::GraphicsPipelineCreateInfoBuilder::new()
+        .stages(&shader_stages)
+        .vertex_input_state(&vertex_input)
+        .input_assembly_state(&input_assembly)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterizer)
+        .multisample_state(&multisampling)
+        .color_blend_state(&color_blending)
+        .depth_stencil_state(&depth_stencil_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(render_pass)
+        .subpass(0);
+
+    let pipeline = unsafe {
+        prelude
+            .device
+            .create_graphics_pipelines(Some(cache), &[create_info], None)
+    }
+    .result();
+
+    unsafe {
+        prelude.device.destroy_shader_module(Some(fragment), None);
+        prelude.device.destroy_shader_module(Some(vertex), None);
+    }
+
+    Ok(pipeline?[0])
+}
+
+fn shader_module(prelude: &SharedCore, spirv: &[u8]) -> Result<vk::ShaderModule> {
+    let decoded = utils::decode_spv(spirv)?;
+    let create_info = vk::ShaderModuleCreateInfoBuilder::new().code(&decoded);
+    let module = unsafe { prelude.device.create_shader_module(&create_info, None) }.result()?;
+    Ok(module)
 }
 
 impl Drop for Material {
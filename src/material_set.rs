@@ -0,0 +1,223 @@
+use crate::core::{AllocatedBuffer, FRAMES_IN_FLIGHT};
+use crate::texture::{Samplers, Texture};
+use crate::{Sampling, TextureBinding, MATERIAL_PARAMS_BINDING, MAX_MATERIAL_TEXTURES};
+use anyhow::{ensure, Result};
+use erupt::vk1_0 as vk;
+use gpu_alloc::UsageFlags as UF;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use slotmap::SlotMap;
+use vk_core::SharedCore;
+
+/// Layout of descriptor set 1, which holds a material's textures and parameters
+pub fn create_material_set_layout(prelude: &SharedCore) -> Result<vk::DescriptorSetLayout> {
+    let mut bindings = (0..MAX_MATERIAL_TEXTURES as u32)
+        .map(|binding| {
+            vk::DescriptorSetLayoutBindingBuilder::new()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+        })
+        .collect::<Vec<_>>();
+    bindings.push(
+        vk::DescriptorSetLayoutBindingBuilder::new()
+            .binding(MATERIAL_PARAMS_BINDING)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
+    );
+
+    let create_info = vk::DescriptorSetLayoutCreateInfoBuilder::new().bindings(&bindings);
+    Ok(unsafe {
+        prelude
+            .device
+            .create_descriptor_set_layout(&create_info, None)
+    }
+    .result()?)
+}
+
+/// A material's texture bindings and parameter block, with one descriptor set and parameter
+/// buffer per frame in flight. Both are written lazily, just before their frame is drawn.
+pub struct MaterialSet {
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    bindings: Vec<TextureBinding>,
+    dirty: [bool; FRAMES_IN_FLIGHT],
+    params: Vec<u8>,
+    params_buffers: Vec<AllocatedBuffer>,
+    params_dirty: [bool; FRAMES_IN_FLIGHT],
+    prelude: SharedCore,
+}
+
+impl MaterialSet {
+    pub fn new(
+        prelude: SharedCore,
+        layout: vk::DescriptorSetLayout,
+        params_size: usize,
+    ) -> Result<Self> {
+        let pool_sizes = [
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count((FRAMES_IN_FLIGHT * MAX_MATERIAL_TEXTURES) as u32),
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(FRAMES_IN_FLIGHT as u32),
+        ];
+        let create_info = vk::DescriptorPoolCreateInfoBuilder::new()
+            .pool_sizes(&pool_sizes)
+            .max_sets(FRAMES_IN_FLIGHT as u32);
+        let descriptor_pool =
+            unsafe { prelude.device.create_descriptor_pool(&create_info, None) }.result()?;
+
+        let layouts = vec![layout; FRAMES_IN_FLIGHT];
+        let create_info = vk::DescriptorSetAllocateInfoBuilder::new()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let descriptor_sets =
+            unsafe { prelude.device.allocate_descriptor_sets(&create_info) }.result()?;
+
+        // Uniform buffers may not be empty, so materials without parameters get a small one
+        let buffer_size = params_size.max(16) as u64;
+        let mut params_buffers = Vec::with_capacity(FRAMES_IN_FLIGHT);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            params_buffers.push(AllocatedBuffer::new(
+                &prelude,
+                buffer_size,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                UF::UPLOAD | UF::HOST_ACCESS,
+            )?);
+        }
+
+        Ok(Self {
+            descriptor_pool,
+            descriptor_sets,
+            bindings: Vec::new(),
+            dirty: [true; FRAMES_IN_FLIGHT],
+            params: vec![0; params_size],
+            params_buffers,
+            params_dirty: [true; FRAMES_IN_FLIGHT],
+            prelude,
+        })
+    }
+
+    /// Bind the given textures, in order starting at binding 0
+    pub fn set_textures(&mut self, bindings: &[TextureBinding]) -> Result<()> {
+        ensure!(
+            bindings.len() <= MAX_MATERIAL_TEXTURES,
+            "Materials may have at most {} textures",
+            MAX_MATERIAL_TEXTURES
+        );
+        self.bindings = bindings.to_vec();
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Replace the start of the parameter block with `data`
+    pub fn set_params(&mut self, data: &[u8]) -> Result<()> {
+        ensure!(
+            data.len() <= self.params.len(),
+            "Material parameters are {} bytes, but the material only has room for {}",
+            data.len(),
+            self.params.len()
+        );
+        self.params[..data.len()].copy_from_slice(data);
+        self.params_dirty = [true; FRAMES_IN_FLIGHT];
+        Ok(())
+    }
+
+    /// Rewrite every frame's descriptor set before it is next used
+    pub fn mark_dirty(&mut self) {
+        self.dirty = [true; FRAMES_IN_FLIGHT];
+    }
+
+    /// Write this frame's descriptor set and parameters if they are out of date. Unbound or
+    /// missing textures are replaced with `default`. The frame must not be in flight.
+    pub fn update(
+        &mut self,
+        frame_idx: usize,
+        textures: &SlotMap<crate::Texture, Texture>,
+        default: &Texture,
+        samplers: &mut Samplers,
+    ) -> Result<()> {
+        if std::mem::take(&mut self.params_dirty[frame_idx]) && !self.params.is_empty() {
+            unsafe {
+                self.params_buffers[frame_idx].memory.write_bytes(
+                    EruptMemoryDevice::wrap(&self.prelude.device),
+                    0,
+                    &self.params,
+                )?;
+            }
+        }
+
+        if !std::mem::take(&mut self.dirty[frame_idx]) {
+            return Ok(());
+        }
+
+        let mut image_infos = Vec::with_capacity(MAX_MATERIAL_TEXTURES);
+        for idx in 0..MAX_MATERIAL_TEXTURES {
+            let (view, sampling) = match self.bindings.get(idx) {
+                Some(binding) => match textures.get(binding.texture) {
+                    Some(texture) => (texture.view, binding.sampling),
+                    None => {
+                        log::error!("Material references a texture that no longer exists");
+                        (default.view, binding.sampling)
+                    }
+                },
+                None => (default.view, Sampling::default()),
+            };
+            image_infos.push([vk::DescriptorImageInfoBuilder::new()
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(view)
+                .sampler(samplers.get(sampling)?)]);
+        }
+
+        let buffer_infos = [vk::DescriptorBufferInfoBuilder::new()
+            .buffer(self.params_buffers[frame_idx].buffer)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)];
+
+        let mut writes = image_infos
+            .iter()
+            .enumerate()
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSetBuilder::new()
+                    .image_info(info)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .dst_set(self.descriptor_sets[frame_idx])
+                    .dst_binding(binding as u32)
+                    .dst_array_element(0)
+            })
+            .collect::<Vec<_>>();
+        writes.push(
+            vk::WriteDescriptorSetBuilder::new()
+                .buffer_info(&buffer_infos)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .dst_set(self.descriptor_sets[frame_idx])
+                .dst_binding(MATERIAL_PARAMS_BINDING)
+                .dst_array_element(0),
+        );
+
+        unsafe {
+            self.prelude.device.update_descriptor_sets(&writes, &[]);
+        }
+
+        Ok(())
+    }
+
+    pub fn descriptor_set(&self, frame_idx: usize) -> vk::DescriptorSet {
+        self.descriptor_sets[frame_idx]
+    }
+}
+
+impl Drop for MaterialSet {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude
+                .device
+                .destroy_descriptor_pool(Some(self.descriptor_pool), None);
+        }
+        for buffer in self.params_buffers.drain(..) {
+            buffer.free(&self.prelude).unwrap();
+        }
+    }
+}
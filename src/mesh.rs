@@ -0,0 +1,461 @@
+use crate::bounds::Bounds;
+use crate::core::{AllocatedBuffer, FRAMES_IN_FLIGHT};
+use crate::staging;
+use crate::vertex::VertexLayout;
+use crate::Indices;
+use anyhow::{ensure, Result};
+use erupt::vk1_0 as vk;
+use gpu_alloc::UsageFlags as UF;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use std::ops::Range;
+use vk_core::SharedCore;
+
+/// Vertex and index buffers holding one copy of a mesh
+pub struct MeshBuffers {
+    pub vertices: AllocatedBuffer,
+    pub indices: AllocatedBuffer,
+    vertex_capacity: u64,
+    index_capacity: u64,
+    /// Device-local buffers are written through a staging buffer, others are mapped directly
+    device_local: bool,
+    /// Byte ranges of the shadow copy which have not been written to these buffers yet
+    dirty_vertices: Option<Range<usize>>,
+    dirty_indices: Option<Range<usize>>,
+}
+
+/// Host copy of a dynamic mesh, written into each frame's buffers just before that frame is drawn
+struct Shadow {
+    vertices: Vec<u8>,
+    indices: Vec<u8>,
+}
+
+/// Geometry on the GPU. Static meshes have a single set of device-local buffers shared by every
+/// frame, while dynamic meshes have one set of host-visible buffers per frame in flight so that
+/// they can be updated without waiting. Storage meshes draw their vertices from a storage buffer
+/// written by compute shaders, and only own their indices.
+pub struct Mesh {
+    buffers: Vec<MeshBuffers>,
+    shadow: Option<Shadow>,
+    vertex_bytes: usize,
+    /// Storage buffer holding the vertices, in place of this mesh's own vertex buffer
+    pub storage: Option<crate::StorageBuffer>,
+    /// Box around the vertices, used to skip objects outside the view. None if unknown.
+    pub bounds: Option<Bounds>,
+    pub layout: VertexLayout,
+    pub n_indices: u32,
+    pub index_type: vk::IndexType,
+}
+
+impl Mesh {
+    pub fn new_static(
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        layout: VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<Self> {
+        check_vertices(&layout, vertices)?;
+        let bounds = Bounds::from_vertices(&layout, vertices)?;
+        let mut buffers = MeshBuffers::new(
+            prelude,
+            buffer_size(vertices.len()),
+            buffer_size(indices.as_bytes().len()),
+            true,
+        )?;
+        buffers.write_vertices(prelude, command_pool, 0, vertices)?;
+        buffers.write_indices(prelude, command_pool, 0, indices.as_bytes())?;
+
+        Ok(Self {
+            buffers: vec![buffers],
+            shadow: None,
+            vertex_bytes: vertices.len(),
+            storage: None,
+            bounds,
+            layout,
+            n_indices: indices.len() as u32,
+            index_type: indices.index_type(),
+        })
+    }
+
+    pub fn new_dynamic(
+        prelude: &SharedCore,
+        layout: VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<Self> {
+        check_vertices(&layout, vertices)?;
+        let bounds = Bounds::from_vertices(&layout, vertices)?;
+        let mut buffers = Vec::with_capacity(FRAMES_IN_FLIGHT);
+        for _ in 0..FRAMES_IN_FLIGHT {
+            // Nothing can be drawing these yet, so write them immediately
+            let mut frame_buffers = MeshBuffers::new(
+                prelude,
+                buffer_size(vertices.len()),
+                buffer_size(indices.as_bytes().len()),
+                false,
+            )?;
+            write_mapped(prelude, &mut frame_buffers.vertices, 0, vertices)?;
+            write_mapped(prelude, &mut frame_buffers.indices, 0, indices.as_bytes())?;
+            buffers.push(frame_buffers);
+        }
+
+        Ok(Self {
+            buffers,
+            shadow: Some(Shadow {
+                vertices: vertices.to_vec(),
+                indices: indices.as_bytes().to_vec(),
+            }),
+            vertex_bytes: vertices.len(),
+            storage: None,
+            bounds,
+            layout,
+            n_indices: indices.len() as u32,
+            index_type: indices.index_type(),
+        })
+    }
+
+    /// Mesh whose vertices live in `storage`, which the caller has checked exists
+    pub fn new_storage(
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        layout: VertexLayout,
+        storage: crate::StorageBuffer,
+        indices: Indices,
+    ) -> Result<Self> {
+        let mut mesh = Self::new_static(prelude, command_pool, layout, &[], indices)?;
+        // Compute shaders may move the vertices anywhere
+        mesh.storage = Some(storage);
+        mesh.bounds = None;
+        Ok(mesh)
+    }
+
+    fn check_layout(&self, layout: &VertexLayout) -> Result<()> {
+        ensure!(
+            *layout == self.layout,
+            "Vertex layout {:?} does not match the mesh's layout {:?}",
+            layout,
+            self.layout
+        );
+        Ok(())
+    }
+
+    fn check_storage(&self) -> Result<()> {
+        ensure!(
+            self.storage.is_none(),
+            "Storage meshes are updated through their storage buffer"
+        );
+        Ok(())
+    }
+
+    /// Whether this mesh has per-frame buffers
+    pub fn is_dynamic(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    /// Buffers to draw from on the given frame
+    pub fn buffers(&self, frame_idx: usize) -> &MeshBuffers {
+        &self.buffers[frame_idx % self.buffers.len()]
+    }
+
+    /// Replace the contents of this mesh. Dynamic meshes are written lazily by `sync()`; static
+    /// meshes are written immediately, so the caller must make sure they aren't in use.
+    pub fn update(
+        &mut self,
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<()> {
+        self.check_storage()?;
+        self.check_layout(layout)?;
+        check_vertices(layout, vertices)?;
+        self.bounds = Bounds::from_vertices(layout, vertices)?;
+        self.vertex_bytes = vertices.len();
+        self.n_indices = indices.len() as u32;
+        self.index_type = indices.index_type();
+
+        match &mut self.shadow {
+            Some(shadow) => {
+                shadow.vertices.clear();
+                shadow.vertices.extend_from_slice(vertices);
+                shadow.indices.clear();
+                shadow.indices.extend_from_slice(indices.as_bytes());
+                for buffers in &mut self.buffers {
+                    buffers.dirty_vertices = Some(0..vertices.len());
+                    buffers.dirty_indices = Some(0..indices.as_bytes().len());
+                }
+            }
+            None => {
+                let buffers = &mut self.buffers[0];
+                buffers.reserve(prelude, vertices.len(), indices.as_bytes().len(), false)?;
+                buffers.write_vertices(prelude, command_pool, 0, vertices)?;
+                buffers.write_indices(prelude, command_pool, 0, indices.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overwrite part of the vertex data, starting at `first_vertex`. The range must lie within
+    /// the current vertices. The bounds grow to fit the new vertices but never shrink; use
+    /// `update()` to tighten them. Same caveats as `update()` for static meshes.
+    pub fn update_vertices(
+        &mut self,
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        layout: &VertexLayout,
+        first_vertex: usize,
+        vertices: &[u8],
+    ) -> Result<()> {
+        self.check_storage()?;
+        self.check_layout(layout)?;
+        check_vertices(layout, vertices)?;
+        let offset = first_vertex * layout.stride as usize;
+        let range = offset..offset + vertices.len();
+        ensure!(
+            range.end <= self.vertex_bytes,
+            "Vertex update out of range; use update_mesh() to grow a mesh"
+        );
+
+        // Only the updated range is read, so the bounds can only grow
+        self.bounds = match (self.bounds, Bounds::from_vertices(layout, vertices)?) {
+            (Some(old), Some(new)) => Some(old.union(new)),
+            _ => None,
+        };
+
+        match &mut self.shadow {
+            Some(shadow) => {
+                shadow.vertices[range.clone()].copy_from_slice(vertices);
+                for buffers in &mut self.buffers {
+                    mark_dirty(&mut buffers.dirty_vertices, range.clone());
+                }
+            }
+            None => self.buffers[0].write_vertices(prelude, command_pool, offset, vertices)?,
+        }
+
+        Ok(())
+    }
+
+    /// Bring this frame's buffers up to date with the shadow copy. Only call this once the frame's
+    /// previous submission has finished executing.
+    pub fn sync(&mut self, prelude: &SharedCore, frame_idx: usize) -> Result<()> {
+        let shadow = match &self.shadow {
+            Some(shadow) => shadow,
+            None => return Ok(()),
+        };
+        let buffers = &mut self.buffers[frame_idx];
+
+        buffers.reserve(prelude, shadow.vertices.len(), shadow.indices.len(), true)?;
+
+        if let Some(range) = buffers.dirty_vertices.take() {
+            write_mapped(
+                prelude,
+                &mut buffers.vertices,
+                range.start,
+                &shadow.vertices[range],
+            )?;
+        }
+        if let Some(range) = buffers.dirty_indices.take() {
+            write_mapped(
+                prelude,
+                &mut buffers.indices,
+                range.start,
+                &shadow.indices[range],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Release this mesh's buffers. None of them may be in use.
+    pub fn free(self, prelude: &SharedCore) -> Result<()> {
+        for buffers in self.buffers {
+            buffers.vertices.free(prelude)?;
+            buffers.indices.free(prelude)?;
+        }
+        Ok(())
+    }
+}
+
+impl MeshBuffers {
+    fn new(
+        prelude: &SharedCore,
+        vertex_capacity: u64,
+        index_capacity: u64,
+        device_local: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            vertices: new_buffer(
+                prelude,
+                vertex_capacity,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                device_local,
+            )?,
+            indices: new_buffer(
+                prelude,
+                index_capacity,
+                vk::BufferUsageFlags::INDEX_BUFFER,
+                device_local,
+            )?,
+            vertex_capacity,
+            index_capacity,
+            device_local,
+            dirty_vertices: None,
+            dirty_indices: None,
+        })
+    }
+
+    fn write_vertices(
+        &mut self,
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<()> {
+        if self.device_local {
+            staging::upload_buffer(
+                prelude,
+                command_pool,
+                self.vertices.buffer,
+                offset as u64,
+                data,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+            )
+        } else {
+            write_mapped(prelude, &mut self.vertices, offset, data)
+        }
+    }
+
+    fn write_indices(
+        &mut self,
+        prelude: &SharedCore,
+        command_pool: vk::CommandPool,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<()> {
+        if self.device_local {
+            staging::upload_buffer(
+                prelude,
+                command_pool,
+                self.indices.buffer,
+                offset as u64,
+                data,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::AccessFlags::INDEX_READ,
+            )
+        } else {
+            write_mapped(prelude, &mut self.indices, offset, data)
+        }
+    }
+
+    /// Reallocate if the given number of bytes don't fit. New buffers are marked entirely dirty.
+    /// Growable buffers are rounded up to a power of two, so that meshes which grow a little each
+    /// frame don't reallocate each frame.
+    fn reserve(
+        &mut self,
+        prelude: &SharedCore,
+        vertex_bytes: usize,
+        index_bytes: usize,
+        growable: bool,
+    ) -> Result<()> {
+        let capacity = |bytes: usize| match growable {
+            true => buffer_size(bytes).next_power_of_two(),
+            false => buffer_size(bytes),
+        };
+
+        if vertex_bytes as u64 > self.vertex_capacity {
+            self.vertex_capacity = capacity(vertex_bytes);
+            let old = std::mem::replace(
+                &mut self.vertices,
+                new_buffer(
+                    prelude,
+                    self.vertex_capacity,
+                    vk::BufferUsageFlags::VERTEX_BUFFER,
+                    self.device_local,
+                )?,
+            );
+            old.free(prelude)?;
+            self.dirty_vertices = Some(0..vertex_bytes);
+        }
+
+        if index_bytes as u64 > self.index_capacity {
+            self.index_capacity = capacity(index_bytes);
+            let old = std::mem::replace(
+                &mut self.indices,
+                new_buffer(
+                    prelude,
+                    self.index_capacity,
+                    vk::BufferUsageFlags::INDEX_BUFFER,
+                    self.device_local,
+                )?,
+            );
+            old.free(prelude)?;
+            self.dirty_indices = Some(0..index_bytes);
+        }
+
+        Ok(())
+    }
+}
+
+/// Buffers may not be empty, so round up empty meshes to something small
+fn buffer_size(bytes: usize) -> u64 {
+    (bytes as u64).max(4)
+}
+
+fn new_buffer(
+    prelude: &SharedCore,
+    size: u64,
+    usage: vk::BufferUsageFlags,
+    device_local: bool,
+) -> Result<AllocatedBuffer> {
+    if device_local {
+        AllocatedBuffer::new(
+            prelude,
+            size,
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+            UF::FAST_DEVICE_ACCESS,
+        )
+    } else {
+        AllocatedBuffer::new(prelude, size, usage, UF::UPLOAD | UF::HOST_ACCESS)
+    }
+}
+
+/// Vertex data must be a whole number of vertices
+fn check_vertices(layout: &VertexLayout, vertices: &[u8]) -> Result<()> {
+    ensure!(
+        layout.stride > 0 && vertices.len().is_multiple_of(layout.stride as usize),
+        "Vertex data is {} bytes, which is not a multiple of the vertex size ({} bytes)",
+        vertices.len(),
+        layout.stride
+    );
+    Ok(())
+}
+
+/// Extend a dirty range to cover `range`
+fn mark_dirty(dirty: &mut Option<Range<usize>>, range: Range<usize>) {
+    *dirty = Some(match dirty.take() {
+        Some(old) => old.start.min(range.start)..old.end.max(range.end),
+        None => range,
+    });
+}
+
+fn write_mapped(
+    prelude: &SharedCore,
+    buffer: &mut AllocatedBuffer,
+    offset: usize,
+    data: &[u8],
+) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    unsafe {
+        buffer.memory.write_bytes(
+            EruptMemoryDevice::wrap(&prelude.device),
+            offset as u64,
+            data,
+        )?;
+    }
+    Ok(())
+}
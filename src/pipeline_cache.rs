@@ -0,0 +1,313 @@
+use anyhow::{ensure, Context, Result};
+use erupt::vk1_0 as vk;
+use std::path::{Path, PathBuf};
+use vk_core::SharedCore;
+
+/// Marks files written by `PipelineCache::save()`
+const MAGIC: &[u8; 8] = b"KLYPCACH";
+
+/// Length of the header written before the driver's data: magic, driver version, data length
+/// and checksum
+const FILE_HEADER_LEN: usize = 8 + 4 + 4 + 8;
+
+/// Length of the header the driver puts at the start of its data: header length, header
+/// version, vendor ID, device ID and pipeline cache UUID
+const VK_HEADER_LEN: usize = 16 + 16;
+
+/// `VK_PIPELINE_CACHE_HEADER_VERSION_ONE`
+const VK_HEADER_VERSION_ONE: u32 = 1;
+
+/// Pipeline cache shared by every pipeline the engine builds, persisted between runs in a file
+/// named after the device's pipeline cache UUID and driver version.
+pub struct PipelineCache {
+    pub cache: vk::PipelineCache,
+    path: Option<PathBuf>,
+    properties: vk::PhysicalDeviceProperties,
+    prelude: SharedCore,
+}
+
+impl PipelineCache {
+    /// Create the cache, seeded from the cache file if there is a valid one. Files which are
+    /// corrupt or were written for another device or driver are ignored.
+    pub fn new(prelude: SharedCore, physical_device: vk::PhysicalDevice) -> Result<Self> {
+        let properties = unsafe {
+            prelude
+                .instance
+                .get_physical_device_properties(physical_device)
+        };
+        let path = cache_dir().map(|dir| dir.join(file_name(&properties)));
+
+        let initial_data = match &path {
+            Some(path) if path.exists() => match read_cache_file(path, &properties) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("Discarding pipeline cache {}: {:#}", path.display(), e);
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+
+        let create_info = vk::PipelineCacheCreateInfoBuilder::new()
+            .initial_data_size(initial_data.len())
+            .initial_data(initial_data.as_ptr() as _);
+        let cache =
+            match unsafe { prelude.device.create_pipeline_cache(&create_info, None) }.result() {
+                Ok(cache) => cache,
+                // Drivers may still reject data which looks valid; start over without it
+                Err(e) if !initial_data.is_empty() => {
+                    log::warn!("Driver rejected the pipeline cache ({}), starting empty", e);
+                    let create_info = vk::PipelineCacheCreateInfoBuilder::new();
+                    unsafe { prelude.device.create_pipeline_cache(&create_info, None) }.result()?
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+        Ok(Self {
+            cache,
+            path,
+            properties,
+            prelude,
+        })
+    }
+
+    /// Write the cache to its file, replacing the previous one
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let data = unsafe {
+            let mut size = 0;
+            self.prelude
+                .device
+                .get_pipeline_cache_data(self.cache, &mut size, std::ptr::null_mut())
+                .result()?;
+            let mut data = vec![0u8; size];
+            self.prelude
+                .device
+                .get_pipeline_cache_data(self.cache, &mut size, data.as_mut_ptr() as _)
+                .result()?;
+            data.truncate(size);
+            data
+        };
+
+        let contents = cache_file(&data, &self.properties);
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        // Write next to the file then rename, so a crash never leaves half a cache behind
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, &contents)
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        std::fs::rename(&partial, path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl Drop for PipelineCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            log::warn!("Failed to save the pipeline cache: {:#}", e);
+        }
+        unsafe {
+            self.prelude
+                .device
+                .destroy_pipeline_cache(Some(self.cache), None);
+        }
+    }
+}
+
+/// Directory cache files are kept in: `KLYSTRON_CACHE_DIR` if it is set, otherwise the user's
+/// cache directory. None if there is neither, in which case nothing is persisted.
+fn cache_dir() -> Option<PathBuf> {
+    let var = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    if let Some(dir) = var("KLYSTRON_CACHE_DIR") {
+        return Some(dir);
+    }
+    let base = if cfg!(windows) {
+        var("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library").join("Caches"))
+    } else {
+        var("XDG_CACHE_HOME").or_else(|| var("HOME").map(|home| home.join(".cache")))
+    };
+    base.map(|dir| dir.join("klystron"))
+}
+
+fn file_name(properties: &vk::PhysicalDeviceProperties) -> String {
+    let uuid: String = properties
+        .pipeline_cache_uuid
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("pipelines-{}-{:08x}.bin", uuid, properties.driver_version)
+}
+
+/// Contents of a cache file holding the driver's data
+fn cache_file(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(FILE_HEADER_LEN + data.len());
+    contents.extend_from_slice(MAGIC);
+    contents.extend_from_slice(&properties.driver_version.to_le_bytes());
+    contents.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    contents.extend_from_slice(&checksum(data).to_le_bytes());
+    contents.extend_from_slice(data);
+    contents
+}
+
+/// Driver data from a cache file, checked against the device before it is handed to the driver
+fn read_cache_file(path: &Path, properties: &vk::PhysicalDeviceProperties) -> Result<Vec<u8>> {
+    let contents = std::fs::read(path).context("Failed to read")?;
+    ensure!(
+        contents.len() >= FILE_HEADER_LEN && &contents[..8] == MAGIC,
+        "Not a pipeline cache file"
+    );
+    let u32_at = |bytes: &[u8], at: usize| {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[at..at + 4]);
+        u32::from_le_bytes(word)
+    };
+    let mut sum = [0; 8];
+    sum.copy_from_slice(&contents[16..24]);
+
+    let data = &contents[FILE_HEADER_LEN..];
+    ensure!(
+        u32_at(&contents, 8) == properties.driver_version,
+        "Written by another driver version"
+    );
+    ensure!(u32_at(&contents, 12) as usize == data.len(), "Truncated");
+    ensure!(
+        checksum(data) == u64::from_le_bytes(sum),
+        "Checksum mismatch"
+    );
+
+    // Header the driver wrote, which is always little-endian
+    ensure!(data.len() >= VK_HEADER_LEN, "Truncated");
+    ensure!(
+        u32_at(data, 0) as usize >= VK_HEADER_LEN
+            && u32_at(data, 4) == VK_HEADER_VERSION_ONE
+            && u32_at(data, 8) == properties.vendor_id
+            && u32_at(data, 12) == properties.device_id
+            && data[16..VK_HEADER_LEN] == properties.pipeline_cache_uuid[..],
+        "Written for another device"
+    );
+    Ok(data.to_vec())
+}
+
+/// FNV-1a hash, to catch files which were corrupted on disk
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            driver_version: 7,
+            vendor_id: 0x1002,
+            device_id: 0x73bf,
+            pipeline_cache_uuid: [3; 16],
+            ..Default::default()
+        }
+    }
+
+    /// Driver data as the device from `properties()` would write it
+    fn driver_data() -> Vec<u8> {
+        let properties = properties();
+        let mut data = Vec::new();
+        data.extend_from_slice(&(VK_HEADER_LEN as u32).to_le_bytes());
+        data.extend_from_slice(&VK_HEADER_VERSION_ONE.to_le_bytes());
+        data.extend_from_slice(&properties.vendor_id.to_le_bytes());
+        data.extend_from_slice(&properties.device_id.to_le_bytes());
+        data.extend_from_slice(&properties.pipeline_cache_uuid);
+        data.extend_from_slice(b"pipelines");
+        data
+    }
+
+    /// Write `contents` to a file of its own, then read it back as `properties()`
+    fn read(name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!(
+            "klystron-pipelines-{}-{}.bin",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let result = read_cache_file(&path, &properties());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn error(name: &str, contents: &[u8]) -> String {
+        read(name, contents).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = driver_data();
+        let contents = cache_file(&data, &properties());
+        assert_eq!(read("round_trip", &contents).unwrap(), data);
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let contents = cache_file(&driver_data(), &properties());
+
+        let mut corrupt = contents.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(error("corrupt", &corrupt), "Checksum mismatch");
+
+        let truncated = &contents[..contents.len() - 1];
+        assert_eq!(error("truncated", truncated), "Truncated");
+
+        assert_eq!(error("empty", &[]), "Not a pipeline cache file");
+        assert_eq!(error("magic", &driver_data()), "Not a pipeline cache file");
+
+        // Consistent, but too short to hold the driver's header
+        let short = cache_file(&driver_data()[..VK_HEADER_LEN - 1], &properties());
+        assert_eq!(error("short", &short), "Truncated");
+    }
+
+    #[test]
+    fn other_drivers_and_devices_are_rejected() {
+        let driver = vk::PhysicalDeviceProperties {
+            driver_version: 8,
+            ..properties()
+        };
+        let contents = cache_file(&driver_data(), &driver);
+        assert_eq!(
+            error("driver", &contents),
+            "Written by another driver version"
+        );
+
+        for offset in [8, 12, 16, VK_HEADER_LEN - 1].iter() {
+            let mut data = driver_data();
+            data[*offset] ^= 1;
+            let contents = cache_file(&data, &properties());
+            assert_eq!(error("device", &contents), "Written for another device");
+        }
+
+        let mut data = driver_data();
+        data[4] = 2;
+        let contents = cache_file(&data, &properties());
+        assert_eq!(error("version", &contents), "Written for another device");
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let path = std::env::temp_dir().join("klystron-pipelines-missing.bin");
+        let e = read_cache_file(&path, &properties()).unwrap_err();
+        assert!(e.to_string().starts_with("Failed to read"), "{:#}", e);
+    }
+}
@@ -0,0 +1,676 @@
+use crate::core::{RenderTarget, COLOR_FORMAT};
+use crate::material::create_pipeline;
+use crate::swapchain_images::{Attachment, SwapChainImage};
+use crate::texture::Samplers;
+use crate::vertex::VertexLayout;
+use crate::{Filter, MaterialDesc, Sampling, Wrap};
+use anyhow::Result;
+use erupt::{vk1_0 as vk, vk1_1, DeviceLoader};
+use slotmap::SlotMap;
+use vk_core::SharedCore;
+
+const POST_VERT: &[u8] = include_bytes!("../shaders/post.vert.spv");
+const DEPTH_RESOLVE_FRAG: &[u8] = include_bytes!("../shaders/depth_resolve.frag.spv");
+
+/// Format of the image objects are drawn into while post-processing is enabled, and of the images
+/// passed between effects. Floating point, so that effects such as tone mapping see values above 1.
+pub(crate) const INTERMEDIATE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Format of the single-sampled copy of a multisampled depth buffer
+pub(crate) const RESOLVED_DEPTH_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// Push constants of every post-processing effect
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct PostConstants {
+    pub params: crate::ObjectParams,
+    pub time: f32,
+}
+
+unsafe impl bytemuck::Zeroable for PostConstants {}
+unsafe impl bytemuck::Pod for PostConstants {}
+
+/// A full-screen pass supplied as fragment shader SPIR-V
+pub struct PostEffect {
+    /// Writes an intermediate image, for every effect but the last
+    intermediate_pipeline: vk::Pipeline,
+    /// Writes the target image, for the last effect
+    final_pipeline: vk::Pipeline,
+    pub params: crate::ObjectParams,
+    prelude: SharedCore,
+}
+
+/// Post-processing effects in the order they run, and the render passes they run in. Effects
+/// only depend on the render target, so unlike materials they survive changes to MSAA.
+pub struct PostChain {
+    effects: SlotMap<crate::PostEffect, PostEffect>,
+    order: Vec<crate::PostEffect>,
+    pub set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pub resolve_set_layout: vk::DescriptorSetLayout,
+    resolve_pipeline_layout: vk::PipelineLayout,
+    resolve_pipeline: vk::Pipeline,
+    /// Writes an intermediate image, which the next effect samples
+    pub intermediate_pass: vk::RenderPass,
+    /// Writes the target image
+    pub final_pass: vk::RenderPass,
+    /// Copies the first sample of a multisampled depth buffer into a single-sampled image
+    pub resolve_pass: vk::RenderPass,
+    cache: vk::PipelineCache,
+    prelude: SharedCore,
+}
+
+impl PostChain {
+    pub fn new(
+        prelude: SharedCore,
+        cache: vk::PipelineCache,
+        target: RenderTarget,
+    ) -> Result<Self> {
+        let device = &prelude.device;
+
+        let sampled = |binding: u32, ty: vk::DescriptorType| {
+            vk::DescriptorSetLayoutBindingBuilder::new()
+                .binding(binding)
+                .descriptor_type(ty)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        };
+
+        // Color, depth, linear sampler, nearest sampler
+        let bindings = [
+            sampled(0, vk::DescriptorType::SAMPLED_IMAGE),
+            sampled(1, vk::DescriptorType::SAMPLED_IMAGE),
+            sampled(2, vk::DescriptorType::SAMPLER),
+            sampled(3, vk::DescriptorType::SAMPLER),
+        ];
+        let create_info = vk::DescriptorSetLayoutCreateInfoBuilder::new().bindings(&bindings);
+        let set_layout =
+            unsafe { device.create_descriptor_set_layout(&create_info, None) }.result()?;
+
+        // Multisampled depth, nearest sampler
+        let bindings = [
+            sampled(0, vk::DescriptorType::SAMPLED_IMAGE),
+            sampled(1, vk::DescriptorType::SAMPLER),
+        ];
+        let create_info = vk::DescriptorSetLayoutCreateInfoBuilder::new().bindings(&bindings);
+        let resolve_set_layout =
+            unsafe { device.create_descriptor_set_layout(&create_info, None) }.result()?;
+
+        let set_layouts = [set_layout];
+        let push_constant_ranges = [vk::PushConstantRangeBuilder::new()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(std::mem::size_of::<PostConstants>() as u32)];
+        let create_info = vk::PipelineLayoutCreateInfoBuilder::new()
+            .push_constant_ranges(&push_constant_ranges)
+            .set_layouts(&set_layouts);
+        let pipeline_layout =
+            unsafe { device.create_pipeline_layout(&create_info, None) }.result()?;
+
+        let set_layouts = [resolve_set_layout];
+        let create_info = vk::PipelineLayoutCreateInfoBuilder::new().set_layouts(&set_layouts);
+        let resolve_pipeline_layout =
+            unsafe { device.create_pipeline_layout(&create_info, None) }.result()?;
+
+        let intermediate_pass = create_post_pass(
+            device,
+            target,
+            INTERMEDIATE_FORMAT,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )?;
+        let final_pass = create_post_pass(device, target, COLOR_FORMAT, target.final_layout())?;
+        let resolve_pass = create_post_pass(
+            device,
+            target,
+            RESOLVED_DEPTH_FORMAT,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )?;
+
+        let resolve_pipeline = create_pipeline(
+            &prelude,
+            cache,
+            POST_VERT,
+            DEPTH_RESOLVE_FRAG,
+            &full_screen_desc(),
+            resolve_pipeline_layout,
+            resolve_pass,
+            vk::SampleCountFlagBits::_1,
+        )?;
+
+        Ok(Self {
+            effects: SlotMap::with_key(),
+            order: Vec::new(),
+            set_layout,
+            pipeline_layout,
+            resolve_set_layout,
+            resolve_pipeline_layout,
+            resolve_pipeline,
+            intermediate_pass,
+            final_pass,
+            resolve_pass,
+            cache,
+            prelude,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Append an effect to the end of the chain
+    pub fn add(&mut self, fragment: &[u8]) -> Result<crate::PostEffect> {
+        let desc = full_screen_desc();
+        let pipeline = |render_pass| {
+            create_pipeline(
+                &self.prelude,
+                self.cache,
+                POST_VERT,
+                fragment,
+                &desc,
+                self.pipeline_layout,
+                render_pass,
+                vk::SampleCountFlagBits::_1,
+            )
+        };
+        let intermediate_pipeline = pipeline(self.intermediate_pass)?;
+        let final_pipeline = match pipeline(self.final_pass) {
+            Ok(p) => p,
+            Err(e) => {
+                unsafe {
+                    self.prelude
+                        .device
+                        .destroy_pipeline(Some(intermediate_pipeline), None);
+                }
+                return Err(e);
+            }
+        };
+
+        let effect = self.effects.insert(PostEffect {
+            intermediate_pipeline,
+            final_pipeline,
+            params: [0.0; 16],
+            prelude: self.prelude.clone(),
+        });
+        self.order.push(effect);
+        Ok(effect)
+    }
+
+    /// Remove an effect from the chain. Its pipelines are destroyed when it is dropped, which
+    /// must wait until no frame in flight uses it.
+    pub fn remove(&mut self, effect: crate::PostEffect) -> Option<PostEffect> {
+        let removed = self.effects.remove(effect)?;
+        self.order.retain(|e| *e != effect);
+        Some(removed)
+    }
+
+    pub fn set_params(
+        &mut self,
+        effect: crate::PostEffect,
+        params: crate::ObjectParams,
+    ) -> Result<()> {
+        match self.effects.get_mut(effect) {
+            Some(effect) => {
+                effect.params = params;
+                Ok(())
+            }
+            None => Err(anyhow::format_err!("Post-processing effect does not exist")),
+        }
+    }
+
+    /// Record the depth resolve, if the depth buffer is multisampled, then every effect in order.
+    /// Objects must already have been drawn into the scene image of `targets`.
+    pub fn record(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        image: &SwapChainImage,
+        targets: &PostTargets,
+        time: f32,
+    ) {
+        if let Some((framebuffer, set)) = targets.resolve {
+            self.full_screen_pass(
+                command_buffer,
+                image.extent,
+                self.resolve_pass,
+                framebuffer,
+                self.resolve_pipeline,
+                self.resolve_pipeline_layout,
+                set,
+                None,
+            );
+        }
+
+        // Effects ping-pong between the two intermediate images, and the last writes the target
+        for (idx, id) in self.order.iter().enumerate() {
+            let effect = &self.effects[*id];
+            let input = match idx {
+                0 => targets.scene_set,
+                _ => targets.intermediate_sets[(idx - 1) % 2],
+            };
+            let (render_pass, framebuffer, pipeline) = match idx + 1 == self.order.len() {
+                true => (
+                    self.final_pass,
+                    image.post_framebuffer,
+                    effect.final_pipeline,
+                ),
+                false => (
+                    self.intermediate_pass,
+                    targets.intermediate_framebuffers[idx % 2],
+                    effect.intermediate_pipeline,
+                ),
+            };
+            let constants = PostConstants {
+                params: effect.params,
+                time,
+            };
+            self.full_screen_pass(
+                command_buffer,
+                image.extent,
+                render_pass,
+                framebuffer,
+                pipeline,
+                self.pipeline_layout,
+                input,
+                Some(&constants),
+            );
+        }
+    }
+
+    /// Draw a single full-screen triangle in a render pass of its own
+    fn full_screen_pass(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        pipeline: vk::Pipeline,
+        pipeline_layout: vk::PipelineLayout,
+        descriptor_set: vk::DescriptorSet,
+        constants: Option<&PostConstants>,
+    ) {
+        let device = &self.prelude.device;
+
+        // Every pixel is overwritten, so nothing is cleared
+        let begin_info = vk::RenderPassBeginInfoBuilder::new()
+            .framebuffer(framebuffer)
+            .render_pass(render_pass)
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            });
+
+        let viewports = [vk::ViewportBuilder::new()
+            .x(0.0)
+            .y(0.0)
+            .width(extent.width as f32)
+            .height(extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+
+        let scissors = [vk::Rect2DBuilder::new()
+            .offset(vk::Offset2D { x: 0, y: 0 })
+            .extent(extent)];
+
+        unsafe {
+            device.cmd_begin_render_pass(command_buffer, &begin_info, vk::SubpassContents::INLINE);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+            device.cmd_set_viewport(command_buffer, 0, &viewports);
+            device.cmd_set_scissor(command_buffer, 0, &scissors);
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline_layout,
+                0,
+                &[descriptor_set],
+                &[],
+            );
+            if let Some(constants) = constants {
+                device.cmd_push_constants(
+                    command_buffer,
+                    pipeline_layout,
+                    vk::ShaderStageFlags::FRAGMENT,
+                    0,
+                    std::mem::size_of::<PostConstants>() as u32,
+                    constants as *const PostConstants as _,
+                );
+            }
+            device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            device.cmd_end_render_pass(command_buffer);
+        }
+    }
+}
+
+impl Drop for PostChain {
+    fn drop(&mut self) {
+        self.effects.clear();
+        unsafe {
+            let device = &self.prelude.device;
+            device.destroy_pipeline(Some(self.resolve_pipeline), None);
+            device.destroy_pipeline_layout(Some(self.resolve_pipeline_layout), None);
+            device.destroy_pipeline_layout(Some(self.pipeline_layout), None);
+            device.destroy_descriptor_set_layout(Some(self.resolve_set_layout), None);
+            device.destroy_descriptor_set_layout(Some(self.set_layout), None);
+            device.destroy_render_pass(Some(self.intermediate_pass), None);
+            device.destroy_render_pass(Some(self.final_pass), None);
+            device.destroy_render_pass(Some(self.resolve_pass), None);
+        }
+    }
+}
+
+impl Drop for PostEffect {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude
+                .device
+                .destroy_pipeline(Some(self.intermediate_pipeline), None);
+            self.prelude
+                .device
+                .destroy_pipeline(Some(self.final_pipeline), None);
+        }
+    }
+}
+
+/// Images and descriptor sets post-processing reads and writes, which live as long as the
+/// swapchain images
+pub struct PostTargets {
+    /// Objects are drawn, or resolved, into this image
+    pub scene: Attachment,
+    /// Effects write these in turn, each reading the one written before
+    intermediate: [Attachment; 2],
+    intermediate_framebuffers: [vk::Framebuffer; 2],
+    /// Single-sampled copy of a multisampled depth buffer
+    resolved_depth: Option<Attachment>,
+    /// Framebuffer and descriptor set of the depth resolve
+    resolve: Option<(vk::Framebuffer, vk::DescriptorSet)>,
+    /// Reads the scene image
+    scene_set: vk::DescriptorSet,
+    /// Read the intermediate images
+    intermediate_sets: [vk::DescriptorSet; 2],
+    descriptor_pool: vk::DescriptorPool,
+    prelude: SharedCore,
+}
+
+impl PostTargets {
+    /// Create targets of the given size. `depth` is the depth buffer objects are drawn with, which
+    /// must have been created with `SAMPLED` usage.
+    pub fn new(
+        prelude: SharedCore,
+        chain: &PostChain,
+        extent: vk::Extent2D,
+        layers: u32,
+        depth: &Attachment,
+        msaa: bool,
+        samplers: &mut Samplers,
+    ) -> Result<Self> {
+        let color_image = |format| {
+            Attachment::new(
+                &prelude,
+                extent,
+                layers,
+                format,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                vk::ImageAspectFlags::COLOR,
+                vk::SampleCountFlagBits::_1,
+            )
+        };
+        let scene = color_image(INTERMEDIATE_FORMAT)?;
+        let intermediate = [
+            color_image(INTERMEDIATE_FORMAT)?,
+            color_image(INTERMEDIATE_FORMAT)?,
+        ];
+        let resolved_depth = match msaa {
+            true => Some(color_image(RESOLVED_DEPTH_FORMAT)?),
+            false => None,
+        };
+
+        let device = &prelude.device;
+        let framebuffer = |render_pass, view| {
+            let attachments = [view];
+            let create_info = vk::FramebufferCreateInfoBuilder::new()
+                .render_pass(render_pass)
+                .attachments(&attachments)
+                .width(extent.width)
+                .height(extent.height)
+                .layers(1);
+            unsafe { device.create_framebuffer(&create_info, None) }.result()
+        };
+        let intermediate_framebuffers = [
+            framebuffer(chain.intermediate_pass, intermediate[0].view)?,
+            framebuffer(chain.intermediate_pass, intermediate[1].view)?,
+        ];
+
+        // One set per image an effect may read, and one for the depth resolve
+        let pool_sizes = [
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(3 * 2 + 1),
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(3 * 2 + 1),
+        ];
+        let create_info = vk::DescriptorPoolCreateInfoBuilder::new()
+            .pool_sizes(&pool_sizes)
+            .max_sets(4);
+        let descriptor_pool =
+            unsafe { device.create_descriptor_pool(&create_info, None) }.result()?;
+
+        let layouts = [chain.set_layout; 3];
+        let create_info = vk::DescriptorSetAllocateInfoBuilder::new()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { device.allocate_descriptor_sets(&create_info) }.result()?;
+
+        let linear = samplers.get(Sampling {
+            filter: Filter::Linear,
+            wrap: Wrap::ClampToEdge,
+        })?;
+        let nearest = samplers.get(Sampling {
+            filter: Filter::Nearest,
+            wrap: Wrap::ClampToEdge,
+        })?;
+
+        // Effects read depth as a single-sampled image either way
+        let depth_info = match &resolved_depth {
+            Some(resolved) => [vk::DescriptorImageInfoBuilder::new()
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(resolved.view)],
+            None => [vk::DescriptorImageInfoBuilder::new()
+                .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .image_view(depth.view)],
+        };
+        let linear_info = [vk::DescriptorImageInfoBuilder::new().sampler(linear)];
+        let nearest_info = [vk::DescriptorImageInfoBuilder::new().sampler(nearest)];
+
+        let inputs = [&scene, &intermediate[0], &intermediate[1]];
+        for (set, input) in sets.iter().zip(inputs.iter()) {
+            let color_info = [vk::DescriptorImageInfoBuilder::new()
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .image_view(input.view)];
+            let writes = [
+                vk::WriteDescriptorSetBuilder::new()
+                    .image_info(&color_info)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .dst_array_element(0),
+                vk::WriteDescriptorSetBuilder::new()
+                    .image_info(&depth_info)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .dst_array_element(0),
+                vk::WriteDescriptorSetBuilder::new()
+                    .image_info(&linear_info)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .dst_set(*set)
+                    .dst_binding(2)
+                    .dst_array_element(0),
+                vk::WriteDescriptorSetBuilder::new()
+                    .image_info(&nearest_info)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .dst_set(*set)
+                    .dst_binding(3)
+                    .dst_array_element(0),
+            ];
+            unsafe {
+                device.update_descriptor_sets(&writes, &[]);
+            }
+        }
+
+        let resolve = match &resolved_depth {
+            Some(resolved) => {
+                let layouts = [chain.resolve_set_layout];
+                let create_info = vk::DescriptorSetAllocateInfoBuilder::new()
+                    .descriptor_pool(descriptor_pool)
+                    .set_layouts(&layouts);
+                let set = unsafe { device.allocate_descriptor_sets(&create_info) }.result()?[0];
+
+                let samples_info = [vk::DescriptorImageInfoBuilder::new()
+                    .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                    .image_view(depth.view)];
+                let writes = [
+                    vk::WriteDescriptorSetBuilder::new()
+                        .image_info(&samples_info)
+                        .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                        .dst_set(set)
+                        .dst_binding(0)
+                        .dst_array_element(0),
+                    vk::WriteDescriptorSetBuilder::new()
+                        .image_info(&nearest_info)
+                        .descriptor_type(vk::DescriptorType::SAMPLER)
+                        .dst_set(set)
+                        .dst_binding(1)
+                        .dst_array_element(0),
+                ];
+                unsafe {
+                    device.update_descriptor_sets(&writes, &[]);
+                }
+
+                Some((framebuffer(chain.resolve_pass, resolved.view)?, set))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            scene,
+            intermediate,
+            intermediate_framebuffers,
+            resolved_depth,
+            resolve,
+            scene_set: sets[0],
+            intermediate_sets: [sets[1], sets[2]],
+            descriptor_pool,
+            prelude,
+        })
+    }
+}
+
+impl Drop for PostTargets {
+    fn drop(&mut self) {
+        unsafe {
+            let device = &self.prelude.device;
+            for framebuffer in &self.intermediate_framebuffers {
+                device.destroy_framebuffer(Some(*framebuffer), None);
+            }
+            if let Some((framebuffer, _)) = self.resolve {
+                device.destroy_framebuffer(Some(framebuffer), None);
+            }
+            device.destroy_descriptor_pool(Some(self.descriptor_pool), None);
+        }
+
+        self.scene.free(&self.prelude);
+        for image in &mut self.intermediate {
+            image.free(&self.prelude);
+        }
+        if let Some(mut resolved) = self.resolved_depth.take() {
+            resolved.free(&self.prelude);
+        }
+    }
+}
+
+/// Pipeline state of a full-screen triangle, generated from gl_VertexIndex
+fn full_screen_desc() -> MaterialDesc {
+    MaterialDesc {
+        vertex_layout: VertexLayout {
+            stride: 0,
+            attributes: Vec::new(),
+        },
+        cull: crate::Cull::None,
+        depth_test: false,
+        depth_write: false,
+        ..Default::default()
+    }
+}
+
+/// Create a render pass with a single color attachment, which is overwritten entirely
+fn create_post_pass(
+    device: &DeviceLoader,
+    target: RenderTarget,
+    format: vk::Format,
+    final_layout: vk::ImageLayout,
+) -> Result<vk::RenderPass> {
+    let attachments = [vk::AttachmentDescriptionBuilder::new()
+        .format(format)
+        .samples(vk::SampleCountFlagBits::_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(final_layout)];
+
+    let color_attachment_refs = [vk::AttachmentReferenceBuilder::new()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+
+    let subpasses = [vk::SubpassDescriptionBuilder::new()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&color_attachment_refs)];
+
+    // Inputs were written as attachments by earlier passes, and the output may still be read by
+    // the pass before. Later passes read the output in their fragment shaders.
+    let dependencies = [
+        vk::SubpassDependencyBuilder::new()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_stage_mask(
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            ),
+        vk::SubpassDependencyBuilder::new()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ),
+    ];
+
+    let mut create_info = vk::RenderPassCreateInfoBuilder::new()
+        .attachments(&attachments)
+        .subpasses(&subpasses)
+        .dependencies(&dependencies);
+
+    // Each layer of the multiview images is processed separately, like in the main pass
+    let views = if target.is_vr() { 2 } else { 1 };
+    let view_mask = [!(!0 << views)];
+    let mut multiview = vk1_1::RenderPassMultiviewCreateInfoBuilder::new()
+        .view_masks(&view_mask)
+        .correlation_masks(&view_mask)
+        .build();
+
+    create_info.p_next = &mut multiview as *mut _ as _;
+
+    Ok(unsafe { device.create_render_pass(&create_info, None) }.result()?)
+}
@@ -0,0 +1,274 @@
+use crate::core::{AllocatedBuffer, FRAMES_IN_FLIGHT};
+use crate::swapchain_images::SwapChainImage;
+use anyhow::{bail, Result};
+use erupt::vk1_0 as vk;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use vk_core::SharedCore;
+
+/// A rendered frame of video, copied back to host memory
+#[derive(Clone, Debug)]
+pub struct Capture {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed sRGB RGBA pixels, one byte per channel, row by row starting at the top
+    pub rgba: Vec<u8>,
+}
+
+impl Capture {
+    /// Write this capture to a PNG file
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgba)?;
+        Ok(())
+    }
+}
+
+/// Copies finished color images into host-visible buffers, one per frame in flight
+pub struct Readback {
+    command_pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    /// Each frame's buffer, with the size and format of the image last copied into it
+    buffers: Vec<Option<(AllocatedBuffer, vk::Extent2D, vk::Format)>>,
+    prelude: SharedCore,
+}
+
+impl Readback {
+    pub fn new(prelude: SharedCore, command_pool: vk::CommandPool) -> Result<Self> {
+        let allocate_info = vk::CommandBufferAllocateInfoBuilder::new()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(FRAMES_IN_FLIGHT as u32);
+
+        let command_buffers =
+            unsafe { prelude.device.allocate_command_buffers(&allocate_info) }.result()?;
+
+        Ok(Self {
+            command_pool,
+            command_buffers,
+            buffers: (0..FRAMES_IN_FLIGHT).map(|_| None).collect(),
+            prelude,
+        })
+    }
+
+    /// Record a copy of `image` (currently in `layout`, and of the given `format`) into this
+    /// frame's readback buffer. The image is returned to `layout` afterwards. Must be submitted
+    /// after the command buffer which renders the image, and only once the previous submission
+    /// for this frame has finished.
+    pub fn record(
+        &mut self,
+        frame_idx: usize,
+        image: &SwapChainImage,
+        layout: vk::ImageLayout,
+        format: vk::Format,
+    ) -> Result<vk::CommandBuffer> {
+        // (Re)allocate the readback buffer if the image size changed
+        let size = image.extent.width as u64 * image.extent.height as u64 * 4;
+        let reusable =
+            matches!(&self.buffers[frame_idx], Some((_, extent, _)) if *extent == image.extent);
+        if !reusable {
+            if let Some((buffer, ..)) = self.buffers[frame_idx].take() {
+                buffer.free(&self.prelude)?;
+            }
+            use gpu_alloc::UsageFlags as UF;
+            let buffer = AllocatedBuffer::new(
+                &self.prelude,
+                size,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                UF::DOWNLOAD | UF::HOST_ACCESS,
+            )?;
+            self.buffers[frame_idx] = Some((buffer, image.extent, format));
+        }
+        let copied = self.buffers[frame_idx].as_mut().unwrap();
+        copied.2 = format;
+        let buffer = copied.0.buffer;
+
+        let command_buffer = self.command_buffers[frame_idx];
+        let subresource_range = vk::ImageSubresourceRangeBuilder::new()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        unsafe {
+            self.prelude
+                .device
+                .reset_command_buffer(command_buffer, None)
+                .result()?;
+
+            let begin_info = vk::CommandBufferBeginInfoBuilder::new()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.prelude
+                .device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .result()?;
+
+            // Wait for rendering to finish, and make the image a transfer source
+            let to_transfer = [vk::ImageMemoryBarrierBuilder::new()
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(layout)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.image)
+                .subresource_range(subresource_range)];
+            self.prelude.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                None,
+                &[],
+                &[],
+                &to_transfer,
+            );
+
+            let regions = [vk::BufferImageCopyBuilder::new()
+                .buffer_offset(0)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(
+                    vk::ImageSubresourceLayersBuilder::new()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .mip_level(0)
+                        .base_array_layer(0)
+                        .layer_count(1)
+                        .build(),
+                )
+                .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+                .image_extent(vk::Extent3D {
+                    width: image.extent.width,
+                    height: image.extent.height,
+                    depth: 1,
+                })];
+            self.prelude.device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                buffer,
+                &regions,
+            );
+
+            // Put the image back the way we found it, and make the copy visible to the host
+            let from_transfer = [vk::ImageMemoryBarrierBuilder::new()
+                .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .dst_access_mask(vk::AccessFlags::empty())
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.image)
+                .subresource_range(subresource_range)];
+            let to_host = [vk::BufferMemoryBarrierBuilder::new()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE)];
+            self.prelude.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::HOST,
+                None,
+                &[],
+                &to_host,
+                &from_transfer,
+            );
+
+            self.prelude
+                .device
+                .end_command_buffer(command_buffer)
+                .result()?;
+        }
+
+        Ok(command_buffer)
+    }
+
+    /// Read back the image copied by the last `record()` for this frame, converted to RGBA from
+    /// the format it was recorded with. The frame's fence must have signaled.
+    pub fn read(&mut self, frame_idx: usize) -> Result<Capture> {
+        let (buffer, extent, format) = self.buffers[frame_idx]
+            .as_mut()
+            .ok_or_else(|| anyhow::format_err!("No image was copied for this frame"))?;
+
+        let mut rgba = vec![0u8; extent.width as usize * extent.height as usize * 4];
+        unsafe {
+            buffer.memory.read_bytes(
+                EruptMemoryDevice::wrap(&self.prelude.device),
+                0,
+                &mut rgba,
+            )?;
+        }
+
+        to_rgba(*format, &mut rgba)?;
+
+        Ok(Capture {
+            width: extent.width,
+            height: extent.height,
+            rgba,
+        })
+    }
+}
+
+impl Drop for Readback {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude.device.device_wait_idle().result().unwrap();
+            self.prelude
+                .device
+                .free_command_buffers(self.command_pool, &self.command_buffers);
+        }
+        for (buffer, ..) in self.buffers.drain(..).flatten() {
+            buffer.free(&self.prelude).unwrap();
+        }
+    }
+}
+
+/// Reorder the channels of tightly packed pixels of a 4-byte format to RGBA, in place
+fn to_rgba(format: vk::Format, pixels: &mut [u8]) -> Result<()> {
+    match format {
+        vk::Format::R8G8B8A8_SRGB | vk::Format::R8G8B8A8_UNORM => {}
+        vk::Format::B8G8R8A8_SRGB | vk::Format::B8G8R8A8_UNORM => {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        other => bail!("Can't convert images of format {:?} to RGBA", other),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bgra_is_swizzled() {
+        let mut pixels = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        to_rgba(vk::Format::B8G8R8A8_SRGB, &mut pixels).unwrap();
+        assert_eq!(pixels, [3, 2, 1, 4, 7, 6, 5, 8]);
+    }
+
+    #[test]
+    fn rgba_is_untouched() {
+        let mut pixels = vec![1, 2, 3, 4];
+        to_rgba(vk::Format::R8G8B8A8_UNORM, &mut pixels).unwrap();
+        assert_eq!(pixels, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn other_formats_are_rejected() {
+        let mut pixels = vec![0; 8];
+        assert!(to_rgba(vk::Format::R16G16B16A16_SFLOAT, &mut pixels).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use erupt::vk1_0 as vk;
+use vk_core::SharedCore;
+
+/// Command pool of one recording thread, with a secondary command buffer for each frame in
+/// flight. Command pools can't be used from two threads at once, so each thread has its own.
+pub struct Recorder {
+    command_pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    prelude: SharedCore,
+}
+
+impl Recorder {
+    pub fn new(
+        prelude: SharedCore,
+        queue_family_index: u32,
+        frames_in_flight: usize,
+    ) -> Result<Self> {
+        let create_info = vk::CommandPoolCreateInfoBuilder::new()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(queue_family_index);
+        let command_pool =
+            unsafe { prelude.device.create_command_pool(&create_info, None) }.result()?;
+
+        let allocate_info = vk::CommandBufferAllocateInfoBuilder::new()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::SECONDARY)
+            .command_buffer_count(frames_in_flight as u32);
+        let command_buffers =
+            unsafe { prelude.device.allocate_command_buffers(&allocate_info) }.result()?;
+
+        Ok(Self {
+            command_pool,
+            command_buffers,
+            prelude,
+        })
+    }
+
+    /// Reset this frame's command buffer and begin recording it, to be executed inside the
+    /// first subpass of the given render pass
+    pub fn begin(
+        &self,
+        frame_idx: usize,
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+    ) -> Result<vk::CommandBuffer> {
+        let command_buffer = self.command_buffers[frame_idx];
+        let inheritance_info = vk::CommandBufferInheritanceInfoBuilder::new()
+            .render_pass(render_pass)
+            .subpass(0)
+            .framebuffer(framebuffer);
+        let begin_info = vk::CommandBufferBeginInfoBuilder::new()
+            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
+            .inheritance_info(&inheritance_info);
+        unsafe {
+            self.prelude
+                .device
+                .reset_command_buffer(command_buffer, None)
+                .result()?;
+            self.prelude
+                .device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .result()?;
+        }
+        Ok(command_buffer)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude
+                .device
+                .free_command_buffers(self.command_pool, &self.command_buffers);
+            self.prelude
+                .device
+                .destroy_command_pool(Some(self.command_pool), None);
+        }
+    }
+}
@@ -0,0 +1,741 @@
+use crate::core::CameraUbo;
+use crate::instances::INSTANCE_BINDING;
+use crate::material::PushConstants;
+use crate::vertex::VertexFormat;
+use crate::{MaterialDesc, MATERIAL_PARAMS_BINDING, MAX_MATERIAL_TEXTURES};
+use anyhow::{bail, ensure, format_err, Result};
+use std::collections::{HashMap, HashSet};
+
+const MAGIC: u32 = 0x0723_0203;
+
+// Opcodes
+const OP_NAME: u32 = 5;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_FUNCTION_CALL: u32 = 57;
+const OP_VARIABLE: u32 = 59;
+const OP_LOAD: u32 = 61;
+const OP_ACCESS_CHAIN: u32 = 65;
+const OP_IN_BOUNDS_ACCESS_CHAIN: u32 = 66;
+const OP_ARRAY_LENGTH: u32 = 68;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// Decorations
+const BUFFER_BLOCK: u32 = 3;
+const ARRAY_STRIDE: u32 = 6;
+const MATRIX_STRIDE: u32 = 7;
+const BUILT_IN: u32 = 11;
+const LOCATION: u32 = 30;
+const BINDING: u32 = 33;
+const DESCRIPTOR_SET: u32 = 34;
+const OFFSET: u32 = 35;
+
+// Storage classes
+const UNIFORM_CONSTANT: u32 = 0;
+const INPUT: u32 = 1;
+const UNIFORM: u32 = 2;
+const OUTPUT: u32 = 3;
+const PUSH_CONSTANT: u32 = 9;
+const STORAGE_BUFFER: u32 = 12;
+
+/// Kind of the components of a numeric type
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Float,
+    Int,
+    UInt,
+}
+
+#[derive(Clone, Debug)]
+enum Type {
+    Scalar {
+        kind: Kind,
+        width: u32,
+    },
+    Vector {
+        component: u32,
+        count: u32,
+    },
+    Matrix {
+        column: u32,
+        columns: u32,
+    },
+    Image,
+    Sampler,
+    SampledImage,
+    /// `length` is None for runtime arrays
+    Array {
+        element: u32,
+        length: Option<u32>,
+    },
+    Struct {
+        members: Vec<u32>,
+    },
+    Pointer {
+        pointee: u32,
+    },
+}
+
+/// What a descriptor binding holds, as declared by a shader
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Descriptor {
+    /// Uniform block of this many bytes
+    Uniform(u32),
+    Storage,
+    CombinedImageSampler,
+    Image,
+    Sampler,
+    /// Arrays of descriptors, and anything unrecognized
+    Other,
+}
+
+impl Type {
+    /// Types this one is made of. Pointers are left out, as they may point ahead.
+    fn parts(&self) -> &[u32] {
+        match self {
+            Type::Vector { component, .. } => std::slice::from_ref(component),
+            Type::Matrix { column, .. } => std::slice::from_ref(column),
+            Type::Array { element, .. } => std::slice::from_ref(element),
+            Type::Struct { members } => members,
+            _ => &[],
+        }
+    }
+}
+
+impl std::fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Descriptor::Uniform(size) => write!(f, "a {} byte uniform block", size),
+            Descriptor::Storage => write!(f, "a storage buffer"),
+            Descriptor::CombinedImageSampler => write!(f, "a combined image sampler"),
+            Descriptor::Image => write!(f, "a separate image"),
+            Descriptor::Sampler => write!(f, "a separate sampler"),
+            Descriptor::Other => write!(f, "an array or an unsupported type"),
+        }
+    }
+}
+
+/// The parts of a SPIR-V module which make up its interface: variables, their decorations, and
+/// the types needed to size them
+#[derive(Default)]
+struct Module {
+    names: HashMap<u32, String>,
+    decorations: HashMap<(u32, u32), u32>,
+    flags: Vec<(u32, u32)>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    /// Id, pointer type, and storage class of each global variable
+    variables: Vec<(u32, u32, u32)>,
+    /// Pointers which are loaded, indexed or passed to a function somewhere
+    used: HashSet<u32>,
+}
+
+impl Module {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len().is_multiple_of(4) && bytes.len() >= 20,
+            "Not SPIR-V: {} bytes is not a whole number of words",
+            bytes.len()
+        );
+        let mut words = bytes
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect::<Vec<_>>();
+        if words[0] == MAGIC.swap_bytes() {
+            words.iter_mut().for_each(|w| *w = w.swap_bytes());
+        }
+        ensure!(words[0] == MAGIC, "Not SPIR-V: bad magic number");
+
+        let mut module = Module::default();
+        let mut rest = &words[5..];
+        while !rest.is_empty() {
+            let count = (rest[0] >> 16) as usize;
+            let opcode = rest[0] & 0xffff;
+            ensure!(
+                count > 0 && count <= rest.len(),
+                "Malformed SPIR-V instruction"
+            );
+            module.instruction(opcode, &rest[1..count])?;
+            rest = &rest[count..];
+        }
+        Ok(module)
+    }
+
+    fn instruction(&mut self, opcode: u32, ops: &[u32]) -> Result<()> {
+        let needed = min_operands(opcode);
+        ensure!(
+            ops.len() >= needed,
+            "Malformed SPIR-V: opcode {} has {} operands, but needs at least {}",
+            opcode,
+            ops.len(),
+            needed
+        );
+        let op = |i: usize| ops.get(i).copied().unwrap_or(0);
+        let ty = match opcode {
+            OP_NAME => {
+                self.names.insert(op(0), string(&ops[1..]));
+                return Ok(());
+            }
+            OP_DECORATE if ops.len() >= 3 => {
+                self.decorations.insert((op(0), op(1)), op(2));
+                return Ok(());
+            }
+            OP_DECORATE => {
+                self.flags.push((op(0), op(1)));
+                return Ok(());
+            }
+            OP_MEMBER_DECORATE if ops.len() >= 4 => {
+                self.member_decorations.insert((op(0), op(1), op(2)), op(3));
+                return Ok(());
+            }
+            OP_CONSTANT => {
+                self.constants.insert(op(1), op(2));
+                return Ok(());
+            }
+            OP_VARIABLE => {
+                self.variables.push((op(1), op(0), op(2)));
+                return Ok(());
+            }
+            OP_LOAD | OP_ACCESS_CHAIN | OP_IN_BOUNDS_ACCESS_CHAIN | OP_ARRAY_LENGTH => {
+                self.used.insert(op(2));
+                return Ok(());
+            }
+            OP_FUNCTION_CALL => {
+                self.used.extend(ops.iter().skip(3));
+                return Ok(());
+            }
+            OP_TYPE_INT => Type::Scalar {
+                kind: if op(2) == 1 { Kind::Int } else { Kind::UInt },
+                width: op(1),
+            },
+            OP_TYPE_FLOAT => Type::Scalar {
+                kind: Kind::Float,
+                width: op(1),
+            },
+            OP_TYPE_VECTOR => Type::Vector {
+                component: op(1),
+                count: op(2),
+            },
+            OP_TYPE_MATRIX => Type::Matrix {
+                column: op(1),
+                columns: op(2),
+            },
+            OP_TYPE_IMAGE => Type::Image,
+            OP_TYPE_SAMPLER => Type::Sampler,
+            OP_TYPE_SAMPLED_IMAGE => Type::SampledImage,
+            OP_TYPE_ARRAY => Type::Array {
+                element: op(1),
+                length: Some(self.constants.get(&op(2)).copied().unwrap_or(0)),
+            },
+            OP_TYPE_RUNTIME_ARRAY => Type::Array {
+                element: op(1),
+                length: None,
+            },
+            OP_TYPE_STRUCT => Type::Struct {
+                members: ops[1..].to_vec(),
+            },
+            OP_TYPE_POINTER => Type::Pointer { pointee: op(2) },
+            _ => return Ok(()),
+        };
+
+        // Types must be declared before they're used, which also keeps sizing from looping
+        let id = op(0);
+        ensure!(
+            !self.types.contains_key(&id),
+            "Malformed SPIR-V: type %{} is declared twice",
+            id
+        );
+        let undeclared = ty
+            .parts()
+            .iter()
+            .find(|part| !self.types.contains_key(part));
+        if let Some(part) = undeclared {
+            bail!(
+                "Malformed SPIR-V: type %{} uses %{} before it is declared",
+                id,
+                part
+            );
+        }
+        self.types.insert(id, ty);
+        Ok(())
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied()
+    }
+
+    fn has_flag(&self, id: u32, decoration: u32) -> bool {
+        self.flags.contains(&(id, decoration))
+    }
+
+    /// Name of a variable, falling back to the name of its block type for unnamed blocks
+    fn name(&self, var: u32, pointer: u32) -> String {
+        let block = self.pointee(pointer);
+        self.names
+            .get(&var)
+            .filter(|name| !name.is_empty())
+            .or_else(|| block.and_then(|block| self.names.get(&block)))
+            .cloned()
+            .unwrap_or_else(|| format!("%{}", var))
+    }
+
+    fn pointee(&self, pointer: u32) -> Option<u32> {
+        match self.types.get(&pointer) {
+            Some(Type::Pointer { pointee }) => Some(*pointee),
+            _ => None,
+        }
+    }
+
+    /// Component kind and count of a scalar or vector type
+    fn numeric(&self, ty: u32) -> Option<(Kind, u32)> {
+        match self.types.get(&ty)? {
+            Type::Scalar { kind, .. } => Some((*kind, 1)),
+            Type::Vector { component, count } => Some((self.numeric(*component)?.0, *count)),
+            _ => None,
+        }
+    }
+
+    /// Size in bytes of a type inside a block, counting runtime arrays as empty
+    fn size(&self, ty: u32) -> u32 {
+        match self.types.get(&ty) {
+            Some(Type::Scalar { width, .. }) => width / 8,
+            Some(Type::Vector { component, count }) => self.size(*component).saturating_mul(*count),
+            Some(Type::Matrix { column, columns }) => self.size(*column).saturating_mul(*columns),
+            Some(Type::Array {
+                element,
+                length: Some(length),
+            }) => {
+                let stride = self.decoration(ty, ARRAY_STRIDE);
+                stride
+                    .unwrap_or_else(|| self.size(*element))
+                    .saturating_mul(*length)
+            }
+            Some(Type::Struct { members }) => (0..members.len() as u32)
+                .map(|member| {
+                    let offset = self.member_decorations.get(&(ty, member, OFFSET));
+                    let size = match (
+                        self.types.get(&members[member as usize]),
+                        self.member_decorations.get(&(ty, member, MATRIX_STRIDE)),
+                    ) {
+                        (Some(Type::Matrix { columns, .. }), Some(stride)) => {
+                            columns.saturating_mul(*stride)
+                        }
+                        _ => self.size(members[member as usize]),
+                    };
+                    offset.copied().unwrap_or(0).saturating_add(size)
+                })
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn descriptor(&self, pointer: u32, storage: u32) -> Descriptor {
+        let ty = match self.pointee(pointer) {
+            Some(ty) => ty,
+            None => return Descriptor::Other,
+        };
+        match (storage, self.types.get(&ty)) {
+            (UNIFORM, _) if self.has_flag(ty, BUFFER_BLOCK) => Descriptor::Storage,
+            (UNIFORM, _) => Descriptor::Uniform(self.size(ty)),
+            (STORAGE_BUFFER, _) => Descriptor::Storage,
+            (UNIFORM_CONSTANT, Some(Type::SampledImage)) => Descriptor::CombinedImageSampler,
+            (UNIFORM_CONSTANT, Some(Type::Image)) => Descriptor::Image,
+            (UNIFORM_CONSTANT, Some(Type::Sampler)) => Descriptor::Sampler,
+            _ => Descriptor::Other,
+        }
+    }
+}
+
+/// Fewest operands each parsed instruction can have, so that the ones read are always present
+fn min_operands(opcode: u32) -> usize {
+    match opcode {
+        OP_TYPE_SAMPLER | OP_TYPE_STRUCT => 1,
+        OP_NAME | OP_DECORATE | OP_TYPE_FLOAT | OP_TYPE_SAMPLED_IMAGE | OP_TYPE_RUNTIME_ARRAY => 2,
+        OP_TYPE_IMAGE => 8,
+        OP_ARRAY_LENGTH => 4,
+        OP_MEMBER_DECORATE
+        | OP_CONSTANT
+        | OP_VARIABLE
+        | OP_LOAD
+        | OP_ACCESS_CHAIN
+        | OP_IN_BOUNDS_ACCESS_CHAIN
+        | OP_FUNCTION_CALL
+        | OP_TYPE_INT
+        | OP_TYPE_VECTOR
+        | OP_TYPE_MATRIX
+        | OP_TYPE_ARRAY
+        | OP_TYPE_POINTER => 3,
+        _ => 0,
+    }
+}
+
+/// Decode a null-terminated string packed into words
+fn string(words: &[u32]) -> String {
+    let bytes = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .take_while(|&b| b != 0)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Check a material's shaders against the interface the engine provides: the descriptor sets,
+/// the push constant block, the vertex attributes in the material's layout, and the single color
+/// attachment
+pub fn check_material(vertex: &[u8], fragment: &[u8], desc: &MaterialDesc) -> Result<()> {
+    check_vertex(vertex, desc).map_err(|e| format_err!("Vertex shader: {}", e))?;
+    check_fragment(fragment, desc).map_err(|e| format_err!("Fragment shader: {}", e))
+}
+
+/// Whether a vertex shader reads the instance buffer, and so takes its transforms from there
+/// rather than the push constants
+pub fn reads_instances(vertex: &[u8]) -> Result<bool> {
+    let module = Module::parse(vertex)?;
+    Ok(module.variables.iter().any(|&(var, _, storage)| {
+        [UNIFORM, STORAGE_BUFFER].contains(&storage)
+            && module.decoration(var, DESCRIPTOR_SET).unwrap_or(0) == 0
+            && module.decoration(var, BINDING) == Some(INSTANCE_BINDING)
+            && module.used.contains(&var)
+    }))
+}
+
+fn check_vertex(spirv: &[u8], desc: &MaterialDesc) -> Result<()> {
+    let module = Module::parse(spirv)?;
+    check_common(&module, desc)?;
+    check_vertex_inputs(&module, desc)
+}
+
+fn check_fragment(spirv: &[u8], desc: &MaterialDesc) -> Result<()> {
+    let module = Module::parse(spirv)?;
+    check_common(&module, desc)?;
+    check_fragment_outputs(&module)
+}
+
+/// Descriptor bindings and push constants, which both stages share
+fn check_common(module: &Module, desc: &MaterialDesc) -> Result<()> {
+    let camera_size = std::mem::size_of::<CameraUbo>() as u32;
+    let push_size = std::mem::size_of::<PushConstants>() as u32;
+
+    for &(var, pointer, storage) in &module.variables {
+        let name = module.name(var, pointer);
+
+        if storage == PUSH_CONSTANT {
+            let size = module.pointee(pointer).map_or(0, |ty| module.size(ty));
+            ensure!(
+                size <= push_size,
+                "Push constant block `{}` is {} bytes, but the engine pushes {} \
+                 (mat4 model; vec4 params[4])",
+                name,
+                size,
+                push_size
+            );
+            continue;
+        }
+
+        if ![UNIFORM, UNIFORM_CONSTANT, STORAGE_BUFFER].contains(&storage) {
+            continue;
+        }
+        let set = module.decoration(var, DESCRIPTOR_SET).unwrap_or(0);
+        let binding = module.decoration(var, BINDING).unwrap_or(0);
+        let found = module.descriptor(pointer, storage);
+
+        let (expected, what) = match (set, binding) {
+            (0, 0) => (
+                Descriptor::Uniform(camera_size),
+                "the camera matrices (mat4 camera[2])",
+            ),
+            (0, 1) => (Descriptor::Uniform(4), "the animation value (float anim)"),
+            (0, INSTANCE_BINDING) => (Descriptor::Storage, "the instance data"),
+            (1, b) if (b as usize) < MAX_MATERIAL_TEXTURES => {
+                (Descriptor::CombinedImageSampler, "a material texture")
+            }
+            (1, MATERIAL_PARAMS_BINDING) => (
+                Descriptor::Uniform(desc.params_size as u32),
+                "the material parameter block (see MaterialDesc::params_size)",
+            ),
+            (0, _) | (1, _) => bail!(
+                "`{}` is declared at set {} binding {}, which the engine does not provide",
+                name,
+                set,
+                binding
+            ),
+            _ => bail!(
+                "`{}` is declared in descriptor set {}, but materials only have sets 0 and 1",
+                name,
+                set
+            ),
+        };
+
+        let matches = match (found, expected) {
+            (Descriptor::Uniform(size), Descriptor::Uniform(max)) => size <= max,
+            (found, expected) => found == expected,
+        };
+        ensure!(
+            matches,
+            "`{}` at set {} binding {} is {}, but the engine binds {} there, as {}",
+            name,
+            set,
+            binding,
+            found,
+            what,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Every vertex input must be an attribute of the material's vertex layout, of the same kind
+fn check_vertex_inputs(module: &Module, desc: &MaterialDesc) -> Result<()> {
+    let attributes = &desc.vertex_layout.attributes;
+    for &(var, pointer, storage) in &module.variables {
+        if storage != INPUT || module.decoration(var, BUILT_IN).is_some() {
+            continue;
+        }
+        let location = match module.decoration(var, LOCATION) {
+            Some(l) => l,
+            None => continue,
+        };
+        let name = module.name(var, pointer);
+
+        let attribute = attributes.iter().find(|a| a.location == location);
+        let attribute = match attribute {
+            Some(a) => a,
+            None => bail!(
+                "Input `{}` at location {} is not in the vertex layout, which has locations {:?}",
+                name,
+                location,
+                attributes.iter().map(|a| a.location).collect::<Vec<_>>()
+            ),
+        };
+
+        let expected = match attribute.format {
+            VertexFormat::UInt => Kind::UInt,
+            _ => Kind::Float,
+        };
+        let found = module.pointee(pointer).and_then(|ty| module.numeric(ty));
+        if let Some((kind, _)) = found {
+            ensure!(
+                kind == expected,
+                "Input `{}` at location {} is {:?}, but the vertex layout supplies {:?} ({:?})",
+                name,
+                location,
+                kind,
+                expected,
+                attribute.format
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Render passes have a single color attachment
+fn check_fragment_outputs(module: &Module) -> Result<()> {
+    for &(var, pointer, storage) in &module.variables {
+        if storage != OUTPUT {
+            continue;
+        }
+        if let Some(location) = module.decoration(var, LOCATION) {
+            ensure!(
+                location == 0,
+                "Output `{}` is at location {}, but there is only a color attachment at 0",
+                module.name(var, pointer),
+                location
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::{VertexAttribute, VertexLayout};
+    use crate::{INSTANCED_VERT, UNLIT_FRAG, UNLIT_VERT};
+
+    /// Assemble a module from instructions, each an opcode and its operands
+    fn assemble(instructions: &[(u32, &[u32])]) -> Vec<u8> {
+        let mut words = vec![MAGIC, 0x0001_0000, 0, 100, 0];
+        for (opcode, ops) in instructions {
+            words.push((ops.len() as u32 + 1) << 16 | opcode);
+            words.extend_from_slice(ops);
+        }
+        words
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn error(result: Result<Module>) -> String {
+        match result {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn truncated_instructions_are_errors() {
+        let name = assemble(&[(OP_NAME, &[])]);
+        assert!(error(Module::parse(&name)).contains("opcode 5 has 0 operands"));
+
+        let structure = assemble(&[(OP_TYPE_STRUCT, &[])]);
+        assert!(error(Module::parse(&structure)).contains("opcode 30 has 0 operands"));
+
+        // Word count runs past the end of the module
+        let mut overrun = assemble(&[(OP_NAME, &[1, 0])]);
+        overrun[22] = 9;
+        assert!(error(Module::parse(&overrun)).contains("Malformed SPIR-V instruction"));
+
+        assert!(error(Module::parse(&[0; 20])).contains("bad magic number"));
+        assert!(error(Module::parse(&[0; 22])).contains("not a whole number of words"));
+    }
+
+    #[test]
+    fn types_must_be_declared_first() {
+        let recursive = assemble(&[(OP_TYPE_STRUCT, &[1, 1])]);
+        assert!(error(Module::parse(&recursive)).contains("type %1 uses %1"));
+
+        let twice = assemble(&[
+            (OP_TYPE_FLOAT, &[1, 32]),
+            (OP_TYPE_VECTOR, &[2, 1, 4]),
+            (OP_TYPE_VECTOR, &[1, 2, 4]),
+        ]);
+        assert!(error(Module::parse(&twice)).contains("type %1 is declared twice"));
+    }
+
+    #[test]
+    fn struct_sizes_follow_offsets() {
+        // struct { float a; vec4 b; mat4 c; float d[3]; }
+        let spirv = assemble(&[
+            (OP_TYPE_FLOAT, &[1, 32]),
+            (OP_TYPE_VECTOR, &[2, 1, 4]),
+            (OP_TYPE_MATRIX, &[3, 2, 4]),
+            (OP_TYPE_INT, &[4, 32, 0]),
+            (OP_CONSTANT, &[4, 5, 3]),
+            (OP_TYPE_ARRAY, &[6, 1, 5]),
+            (OP_DECORATE, &[6, ARRAY_STRIDE, 16]),
+            (OP_TYPE_STRUCT, &[7, 1, 2, 3, 6]),
+            (OP_MEMBER_DECORATE, &[7, 0, OFFSET, 0]),
+            (OP_MEMBER_DECORATE, &[7, 1, OFFSET, 16]),
+            (OP_MEMBER_DECORATE, &[7, 2, OFFSET, 32]),
+            (OP_MEMBER_DECORATE, &[7, 2, MATRIX_STRIDE, 16]),
+            (OP_MEMBER_DECORATE, &[7, 3, OFFSET, 96]),
+        ]);
+        let module = Module::parse(&spirv).unwrap();
+        assert_eq!(module.size(2), 16);
+        assert_eq!(module.size(3), 64);
+        assert_eq!(module.size(6), 48);
+        assert_eq!(module.size(7), 144);
+    }
+
+    /// A float block of `bytes` bytes in the given storage class, at set 0 binding 0
+    fn block(storage: u32, bytes: u32, set: u32) -> Vec<u8> {
+        assemble(&[
+            (OP_NAME, &[5, u32::from_le_bytes(*b"blk\0")]),
+            (OP_TYPE_FLOAT, &[1, 32]),
+            (OP_TYPE_INT, &[2, 32, 0]),
+            (OP_CONSTANT, &[2, 3, bytes / 4]),
+            (OP_TYPE_ARRAY, &[4, 1, 3]),
+            (OP_TYPE_STRUCT, &[5, 4]),
+            (OP_TYPE_POINTER, &[6, storage, 5]),
+            (OP_VARIABLE, &[6, 7, storage]),
+            (OP_DECORATE, &[7, DESCRIPTOR_SET, set]),
+            (OP_DECORATE, &[7, BINDING, 0]),
+        ])
+    }
+
+    #[test]
+    fn shipped_shaders_match() {
+        check_material(UNLIT_VERT, UNLIT_FRAG, &MaterialDesc::default()).unwrap();
+        check_material(INSTANCED_VERT, UNLIT_FRAG, &MaterialDesc::default()).unwrap();
+        assert!(reads_instances(INSTANCED_VERT).unwrap());
+        assert!(!reads_instances(UNLIT_VERT).unwrap());
+    }
+
+    #[test]
+    fn missing_attribute_is_reported() {
+        let desc = MaterialDesc {
+            vertex_layout: VertexLayout {
+                stride: 12,
+                attributes: vec![VertexAttribute {
+                    location: 0,
+                    format: VertexFormat::Vec3,
+                    offset: 0,
+                }],
+            },
+            ..Default::default()
+        };
+        let e = check_material(UNLIT_VERT, UNLIT_FRAG, &desc).unwrap_err();
+        assert!(e.to_string().starts_with("Vertex shader: Input `"), "{}", e);
+        assert!(
+            e.to_string()
+                .ends_with("at location 1 is not in the vertex layout, which has locations [0]"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn push_constants_are_sized() {
+        let module = Module::parse(&block(PUSH_CONSTANT, 128, 0)).unwrap();
+        check_common(&module, &MaterialDesc::default()).unwrap();
+
+        let module = Module::parse(&block(PUSH_CONSTANT, 256, 0)).unwrap();
+        let e = check_common(&module, &MaterialDesc::default()).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("Push constant block `blk` is 256 bytes, but the engine pushes 128"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn descriptors_are_checked() {
+        let module = Module::parse(&block(UNIFORM, 16, 2)).unwrap();
+        let e = check_common(&module, &MaterialDesc::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "`blk` is declared in descriptor set 2, but materials only have sets 0 and 1"
+        );
+
+        // Set 0 binding 0 is the camera block
+        let module = Module::parse(&block(UNIFORM, 4096, 0)).unwrap();
+        let e = check_common(&module, &MaterialDesc::default()).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("`blk` at set 0 binding 0 is a 4096 byte uniform block"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn fragment_outputs_are_checked() {
+        let spirv = assemble(&[
+            (OP_NAME, &[4, u32::from_le_bytes(*b"out\0")]),
+            (OP_TYPE_FLOAT, &[2, 32]),
+            (OP_TYPE_POINTER, &[3, OUTPUT, 2]),
+            (OP_VARIABLE, &[3, 4, OUTPUT]),
+            (OP_DECORATE, &[4, LOCATION, 1]),
+        ]);
+        let e = check_material(UNLIT_VERT, &spirv, &MaterialDesc::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Fragment shader: Output `out` is at location 1, but there is only a color \
+             attachment at 0"
+        );
+    }
+}
@@ -5,17 +5,18 @@
 
 use super::mouse_camera::MouseCamera;
 use super::target_time::TargetTime;
-use crate::{Engine, FramePacket, OpenXrBackend, PerspectiveCamera, WinitBackend};
+use crate::{Engine, FramePacket, HeadlessBackend, OpenXrBackend, PerspectiveCamera, WinitBackend};
 use anyhow::Result;
 use log::info;
 use openxr as xr;
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use std::time::Duration;
 use winit::{
-    event::{Event, StartCause, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, StartCause, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
@@ -62,24 +63,102 @@ pub fn windowed_backend<A: App + 'static>(args: A::Args) -> Result<()> {
 
     let mut mouse_camera = MouseCamera::new(PerspectiveCamera::default(), 0.001, 0.004);
     let mut target_time = TargetTime::default();
+    let mut screenshot_idx = 0;
     event_loop.run(move |event, _, control_flow| match event {
         Event::NewEvents(StartCause::Init) => {
             *control_flow = ControlFlow::Poll;
         }
         Event::WindowEvent { event, .. } => match event {
             WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+            // F12 saves a screenshot of the next frame
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::F12),
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => engine.request_capture(),
             _ => mouse_camera.handle_events(&event),
         },
         Event::MainEventsCleared => {
             target_time.start_frame();
             let packet = app.next_frame(&mut engine).unwrap();
             engine.next_frame(&packet, &mouse_camera.inner).unwrap();
+            if let Some(capture) = engine.take_capture().unwrap() {
+                let path = format!("{}_{}.png", A::NAME, screenshot_idx);
+                match capture.save_png(&path) {
+                    Ok(()) => info!("Saved screenshot to {}", path),
+                    Err(e) => log::error!("Failed to save screenshot: {}", e),
+                }
+                screenshot_idx += 1;
+            }
             target_time.end_frame();
         }
         _ => (),
     })
 }
 
+/// Settings for rendering an `App` to a sequence of images with `offline_backend`
+#[derive(Clone)]
+pub struct OfflineSettings {
+    /// Number of frames to render
+    pub frames: usize,
+    /// Amount the engine's time value advances each frame
+    pub time_step: f32,
+    /// Width of each image in pixels
+    pub width: u32,
+    /// Height of each image in pixels
+    pub height: u32,
+    /// Directory which numbered PNG files are written into. Created if it doesn't exist.
+    pub output_dir: PathBuf,
+    /// Fixed camera to render from
+    pub camera: PerspectiveCamera,
+}
+
+impl Default for OfflineSettings {
+    fn default() -> Self {
+        Self {
+            frames: 600,
+            time_step: 1. / 60.,
+            width: 1920,
+            height: 1080,
+            output_dir: PathBuf::from("frames"),
+            camera: PerspectiveCamera::default(),
+        }
+    }
+}
+
+/// Launch an `App` without a window, rendering a fixed number of frames as fast as possible and
+/// writing each one to `output_dir/000000.png`, `output_dir/000001.png`, and so on. The engine's
+/// time value is set to `frame index * time_step` for each frame, replacing any value the app
+/// sets, so runs are repeatable.
+pub fn offline_backend<A: App>(args: A::Args, settings: OfflineSettings) -> Result<()> {
+    std::fs::create_dir_all(&settings.output_dir)?;
+
+    let mut engine = HeadlessBackend::new(A::NAME, settings.width, settings.height)?;
+    let mut app = A::new(&mut engine, args)?;
+
+    for frame in 0..settings.frames {
+        let packet = app.next_frame(&mut engine)?;
+        // Set after the app has run, overriding any time value it set itself
+        engine.update_time_value(frame as f32 * settings.time_step)?;
+        engine.next_frame(&packet, &settings.camera)?;
+
+        let path = settings.output_dir.join(format!("{:06}.png", frame));
+        engine.read_frame()?.save_png(&path)?;
+        info!(
+            "Wrote frame {}/{} to {}",
+            frame + 1,
+            settings.frames,
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
 /// Launch an `App` using OpenXR as a surface and input mechanism for VR
 pub fn vr_backend<A: App>(args: A::Args) -> Result<()> {
     // Handle interrupts gracefully
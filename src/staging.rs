@@ -0,0 +1,141 @@
+use crate::core::AllocatedBuffer;
+use anyhow::Result;
+use erupt::vk1_0 as vk;
+use gpu_alloc::UsageFlags as UF;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use vk_core::SharedCore;
+
+/// Record commands into a temporary command buffer, submit them, and wait for them to complete
+pub fn one_shot(
+    prelude: &SharedCore,
+    command_pool: vk::CommandPool,
+    record: impl FnOnce(vk::CommandBuffer),
+) -> Result<()> {
+    let allocate_info = vk::CommandBufferAllocateInfoBuilder::new()
+        .command_pool(command_pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(1);
+    let command_buffer =
+        unsafe { prelude.device.allocate_command_buffers(&allocate_info) }.result()?[0];
+
+    let create_info = vk::FenceCreateInfoBuilder::new();
+    let fence = unsafe { prelude.device.create_fence(&create_info, None) }.result()?;
+
+    let result = unsafe {
+        let begin_info = vk::CommandBufferBeginInfoBuilder::new()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        prelude
+            .device
+            .begin_command_buffer(command_buffer, &begin_info)
+            .result()
+            .and_then(|_| {
+                record(command_buffer);
+                prelude.device.end_command_buffer(command_buffer).result()
+            })
+            .and_then(|_| {
+                let command_buffers = [command_buffer];
+                let submit_info = vk::SubmitInfoBuilder::new().command_buffers(&command_buffers);
+                prelude
+                    .device
+                    .queue_submit(prelude.queue, &[submit_info], Some(fence))
+                    .result()
+            })
+            .and_then(|_| {
+                prelude
+                    .device
+                    .wait_for_fences(&[fence], true, u64::MAX)
+                    .result()
+            })
+    };
+
+    unsafe {
+        prelude.device.destroy_fence(Some(fence), None);
+        prelude
+            .device
+            .free_command_buffers(command_pool, &[command_buffer]);
+    }
+
+    Ok(result?)
+}
+
+/// Copy `data` into a temporary host-visible buffer, and run `record` in a one-shot command
+/// buffer with that buffer as the transfer source. Returns once the commands have completed.
+pub fn with_staging(
+    prelude: &SharedCore,
+    command_pool: vk::CommandPool,
+    data: &[u8],
+    record: impl FnOnce(vk::CommandBuffer, vk::Buffer),
+) -> Result<()> {
+    let mut staging = AllocatedBuffer::new(
+        prelude,
+        data.len() as u64,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        UF::UPLOAD | UF::HOST_ACCESS | UF::TRANSIENT,
+    )?;
+
+    let result = unsafe {
+        staging
+            .memory
+            .write_bytes(EruptMemoryDevice::wrap(&prelude.device), 0, data)
+            .map_err(anyhow::Error::from)
+    }
+    .and_then(|_| {
+        let buffer = staging.buffer;
+        one_shot(prelude, command_pool, |command_buffer| {
+            record(command_buffer, buffer)
+        })
+    });
+
+    staging.free(prelude)?;
+    result
+}
+
+/// Copy `data` into `dst` at `offset` through a temporary host-visible buffer, for buffers which
+/// live in device-local memory. Returns once the copy has completed.
+pub fn upload_buffer(
+    prelude: &SharedCore,
+    command_pool: vk::CommandPool,
+    dst: vk::Buffer,
+    offset: u64,
+    data: &[u8],
+    dst_stage: vk::PipelineStageFlags,
+    dst_access: vk::AccessFlags,
+) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    with_staging(
+        prelude,
+        command_pool,
+        data,
+        |command_buffer, staging| unsafe {
+            let regions = [vk::BufferCopyBuilder::new()
+                .src_offset(0)
+                .dst_offset(offset)
+                .size(data.len() as u64)];
+            prelude
+                .device
+                .cmd_copy_buffer(command_buffer, staging, dst, &regions);
+
+            // Make the copy visible to whatever reads the buffer later
+            let barriers = [vk::BufferMemoryBarrierBuilder::new()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(dst)
+                .offset(offset)
+                .size(data.len() as u64)];
+            prelude.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                dst_stage,
+                None,
+                &[],
+                &barriers,
+                &[],
+            );
+        },
+    )
+}
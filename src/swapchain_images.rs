@@ -1,25 +1,53 @@
 use vk_core::SharedCore;
+use crate::core::PassConfig;
 use crate::frame_sync::Frame;
+use crate::post::{PostChain, PostTargets};
+use crate::texture::Samplers;
 use anyhow::Result;
 use erupt::{vk1_0 as vk, DeviceLoader};
 use gpu_alloc_erupt::EruptMemoryDevice;
 
 pub struct SwapchainImages {
     pub extent: vk::Extent2D,
-    pub depth_image: vk::Image,
-    pub depth_image_mem: Option<gpu_alloc::MemoryBlock<vk::DeviceMemory>>,
-    pub depth_image_view: vk::ImageView,
+    pub depth: Attachment,
+    /// Color image owned by the engine. With MSAA it is multisampled and resolved into the
+    /// swapchain image; otherwise it only exists to keep its contents between frames, and is
+    /// copied into the swapchain image.
+    pub color: Option<Attachment>,
+    /// Images post-processing reads and writes, while any effects exist
+    pub post: Option<PostTargets>,
+    /// Whether the color image is kept between frames
+    keep_color: bool,
+    /// Whether a frame has been drawn into the color image yet
+    color_written: bool,
     images: Vec<SwapChainImage>,
     prelude: SharedCore,
 }
 
+/// An image owned by the engine, used as a framebuffer attachment alongside each swapchain image
+pub struct Attachment {
+    pub image: vk::Image,
+    pub memory: Option<gpu_alloc::MemoryBlock<vk::DeviceMemory>>,
+    pub view: vk::ImageView,
+}
+
 #[derive(Copy, Clone)]
 pub struct SwapChainImage {
+    pub image: vk::Image,
     pub framebuffer: vk::Framebuffer,
+    /// Framebuffer of the last post-processing effect, which writes this image. Null without
+    /// post-processing.
+    pub post_framebuffer: vk::Framebuffer,
     pub image_view: vk::ImageView,
     /// Whether or not the frame which this swapchain image is dependent on is in flight or not
     pub extent: vk::Extent2D,
     pub in_flight: vk::Fence,
+    /// Array layers; two for VR
+    pub layers: u32,
+    /// Persistent color image to copy into this image once the render pass is done, if any
+    pub copy_from: Option<vk::Image>,
+    /// Whether the color attachment still holds the previous frame, so that it may be loaded
+    pub previous_contents: bool,
 }
 
 impl SwapchainImages {
@@ -42,6 +70,10 @@ impl SwapchainImages {
         // swapchain image will know (see above) when this image is rendered.
         image.in_flight = frame.in_flight_fence;
 
+        // A persistent color image holds the previous frame once anything has been drawn into it
+        image.previous_contents = self.keep_color && self.color_written;
+        self.color_written = true;
+
         Ok(*image)
     }
 
@@ -50,11 +82,145 @@ impl SwapchainImages {
         extent: vk::Extent2D,
         render_pass: vk::RenderPass,
         swapchain_images: Vec<vk::Image>,
-        vr: bool,
+        config: PassConfig,
+        post_chain: &PostChain,
+        samplers: &mut Samplers,
     ) -> Result<Self> {
-        let layers = if vr { 2 } else { 1 };
+        let layers = config.layers();
+        let msaa = config.msaa();
+
+        // Post-processing samples the depth buffer
+        let depth_usage = match config.post {
+            true => vk::ImageUsageFlags::SAMPLED,
+            false => vk::ImageUsageFlags::empty(),
+        };
+        let depth = Attachment::new(
+            &prelude,
+            extent,
+            layers,
+            crate::core::DEPTH_FORMAT,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | depth_usage,
+            vk::ImageAspectFlags::DEPTH,
+            config.samples,
+        )?;
+
+        // Multisampled color is only needed until it is resolved, so unless it is kept between
+        // frames it may be lazily allocated. Persistent single-sampled color is copied out, unless
+        // objects are drawn into the image post-processing reads instead.
+        let color_usage = match (msaa, config.keep_color) {
+            (true, false) => Some(vk::ImageUsageFlags::TRANSIENT_ATTACHMENT),
+            (true, true) => Some(vk::ImageUsageFlags::empty()),
+            (false, _) if config.copy_to_target() => Some(vk::ImageUsageFlags::TRANSFER_SRC),
+            (false, _) => None,
+        };
+        let color = match color_usage {
+            Some(usage) => Some(Attachment::new(
+                &prelude,
+                extent,
+                layers,
+                config.scene_format(),
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | usage,
+                vk::ImageAspectFlags::COLOR,
+                config.samples,
+            )?),
+            None => None,
+        };
+
+        let post = match config.post {
+            true => Some(PostTargets::new(
+                prelude.clone(),
+                post_chain,
+                extent,
+                layers,
+                &depth,
+                msaa,
+                samplers,
+            )?),
+            false => None,
+        };
+
+        // Attachment order must match `create_render_pass`. The scene ends up in the swapchain
+        // image (None here), or in the image post-processing reads.
+        let output = post.as_ref().map(|post| post.scene.view);
+        let attachments = match &color {
+            Some(color) if msaa => vec![Some(color.view), Some(depth.view), output],
+            Some(color) => vec![Some(color.view), Some(depth.view)],
+            None => vec![output, Some(depth.view)],
+        };
+        let copy_from = match config.copy_to_target() {
+            true => color.as_ref().map(|color| color.image),
+            false => None,
+        };
+        let post_pass = post.as_ref().map(|_| post_chain.final_pass);
+
+        // Build swapchain image views and buffers
+        let images = swapchain_images
+            .iter()
+            .map(|&image| {
+                SwapChainImage::new(
+                    &prelude.device,
+                    render_pass,
+                    post_pass,
+                    image,
+                    extent,
+                    &attachments,
+                    copy_from,
+                    layers,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        // Create depth image
+        Ok(Self {
+            extent,
+            images,
+            depth,
+            color,
+            post,
+            keep_color: config.keep_color,
+            color_written: false,
+            prelude,
+        })
+    }
+}
+
+impl Drop for SwapchainImages {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude.device.device_wait_idle().result().unwrap();
+            for image in self.images.drain(..) {
+                self.prelude
+                    .device
+                    .destroy_framebuffer(Some(image.framebuffer), None);
+                if !image.post_framebuffer.is_null() {
+                    self.prelude
+                        .device
+                        .destroy_framebuffer(Some(image.post_framebuffer), None);
+                }
+                self.prelude
+                    .device
+                    .destroy_image_view(Some(image.image_view), None);
+            }
+        }
+
+        // Post-processing descriptors reference the depth buffer
+        drop(self.post.take());
+        self.depth.free(&self.prelude);
+        if let Some(mut color) = self.color.take() {
+            color.free(&self.prelude);
+        }
+    }
+}
+
+impl Attachment {
+    pub fn new(
+        prelude: &SharedCore,
+        extent: vk::Extent2D,
+        layers: u32,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        aspect: vk::ImageAspectFlags,
+        samples: vk::SampleCountFlagBits,
+    ) -> Result<Self> {
         let create_info = vk::ImageCreateInfoBuilder::new()
             .image_type(vk::ImageType::_2D)
             .extent(
@@ -66,96 +232,73 @@ impl SwapchainImages {
             )
             .mip_levels(1)
             .array_layers(layers)
-            .format(crate::core::DEPTH_FORMAT)
+            .format(format)
             .tiling(vk::ImageTiling::OPTIMAL)
             .initial_layout(vk::ImageLayout::UNDEFINED)
-            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-            .samples(vk::SampleCountFlagBits::_1)
+            .usage(usage)
+            .samples(samples)
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
-        let depth_image =
-            unsafe { prelude.device.create_image(&create_info, None) }.result()?;
+        let image = unsafe { prelude.device.create_image(&create_info, None) }.result()?;
 
-        let requirements = unsafe { prelude.device.get_image_memory_requirements(depth_image) };
+        let requirements = unsafe { prelude.device.get_image_memory_requirements(image) };
 
         use gpu_alloc::UsageFlags as UF;
+        let mut usage_flags = UF::FAST_DEVICE_ACCESS;
+        if usage.contains(vk::ImageUsageFlags::TRANSIENT_ATTACHMENT) {
+            usage_flags |= UF::TRANSIENT;
+        }
         let request = gpu_alloc::Request {
             size: requirements.size,
             align_mask: requirements.alignment,
-            usage: UF::FAST_DEVICE_ACCESS,
+            usage: usage_flags,
             memory_types: requirements.memory_type_bits,
         };
 
-        let depth_image_mem = unsafe { prelude.allocator()?
-            .alloc(EruptMemoryDevice::wrap(&prelude.device), request)? };
+        let memory = unsafe {
+            prelude
+                .allocator()?
+                .alloc(EruptMemoryDevice::wrap(&prelude.device), request)?
+        };
 
         unsafe {
-            prelude.device.bind_image_memory(depth_image, *depth_image_mem.memory(), depth_image_mem.offset()).result()?;
+            prelude
+                .device
+                .bind_image_memory(image, *memory.memory(), memory.offset())
+                .result()?;
         }
 
         let create_info = vk::ImageViewCreateInfoBuilder::new()
-            .image(depth_image)
-            .view_type(vk::ImageViewType::_2D)
-            .format(crate::core::DEPTH_FORMAT)
+            .image(image)
+            .view_type(vk::ImageViewType::_2D_ARRAY)
+            .format(format)
             .subresource_range(
                 vk::ImageSubresourceRangeBuilder::new()
-                    .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                    .aspect_mask(aspect)
                     .base_mip_level(0)
                     .level_count(1)
                     .base_array_layer(0)
                     .layer_count(layers)
                     .build(),
             );
-        let depth_image_view =
-            unsafe { prelude.device.create_image_view(&create_info, None) }.result()?;
-
-        // Build swapchain image views and buffers
-        let images = swapchain_images
-            .iter()
-            .map(|&image| {
-                SwapChainImage::new(
-                    &prelude.device,
-                    render_pass,
-                    image,
-                    extent,
-                    depth_image_view,
-                    vr,
-                )
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let view = unsafe { prelude.device.create_image_view(&create_info, None) }.result()?;
 
         Ok(Self {
-            extent,
-            images,
-            depth_image,
-            depth_image_mem: Some(depth_image_mem),
-            depth_image_view,
-            prelude,
+            image,
+            memory: Some(memory),
+            view,
         })
     }
-}
 
-impl Drop for SwapchainImages {
-    fn drop(&mut self) {
+    pub fn free(&mut self, prelude: &SharedCore) {
         unsafe {
-            self.prelude.device.device_wait_idle().result().unwrap();
-            for image in self.images.drain(..) {
-                self.prelude
-                    .device
-                    .destroy_framebuffer(Some(image.framebuffer), None);
-                self.prelude
-                    .device
-                    .destroy_image_view(Some(image.image_view), None);
+            prelude.device.destroy_image_view(Some(self.view), None);
+            prelude.device.destroy_image(Some(self.image), None);
+            if let Some(memory) = self.memory.take() {
+                prelude
+                    .allocator()
+                    .unwrap()
+                    .dealloc(EruptMemoryDevice::wrap(&prelude.device), memory);
             }
-            self.prelude
-                .device
-                .destroy_image_view(Some(self.depth_image_view), None);
-            self.prelude
-                .device
-                .destroy_image(Some(self.depth_image), None);
-        }
-
-        unsafe {
-            self.prelude.allocator().as_mut().unwrap().dealloc(EruptMemoryDevice::wrap(&self.prelude.device), self.depth_image_mem.take().unwrap());
         }
     }
 }
@@ -164,10 +307,12 @@ impl SwapChainImage {
     pub fn new(
         device: &DeviceLoader,
         render_pass: vk::RenderPass,
+        post_pass: Option<vk::RenderPass>,
         swapchain_image: vk::Image,
         extent: vk::Extent2D,
-        depth_image_view: vk::ImageView,
-        vr: bool,
+        attachments: &[Option<vk::ImageView>],
+        copy_from: Option<vk::Image>,
+        layers: u32,
     ) -> Result<Self> {
         let in_flight = vk::Fence::null();
 
@@ -187,13 +332,17 @@ impl SwapChainImage {
                     .base_mip_level(0)
                     .level_count(1)
                     .base_array_layer(0)
-                    .layer_count(if vr { 2 } else { 1 })
+                    .layer_count(layers)
                     .build(),
             );
 
         let image_view = unsafe { device.create_image_view(&create_info, None) }.result()?;
 
-        let attachments = [image_view, depth_image_view];
+        // None stands for this image
+        let attachments = attachments
+            .iter()
+            .map(|view| view.unwrap_or(image_view))
+            .collect::<Vec<_>>();
         let create_info = vk::FramebufferCreateInfoBuilder::new()
             .render_pass(render_pass)
             .attachments(&attachments)
@@ -204,11 +353,30 @@ impl SwapChainImage {
         let framebuffer =
             unsafe { device.create_framebuffer(&create_info, None) }.result()?;
 
+        let post_framebuffer = match post_pass {
+            Some(post_pass) => {
+                let attachments = [image_view];
+                let create_info = vk::FramebufferCreateInfoBuilder::new()
+                    .render_pass(post_pass)
+                    .attachments(&attachments)
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1);
+                unsafe { device.create_framebuffer(&create_info, None) }.result()?
+            }
+            None => vk::Framebuffer::null(),
+        };
+
         Ok(Self {
+            image: swapchain_image,
             framebuffer,
+            post_framebuffer,
             image_view,
             in_flight,
             extent,
+            layers,
+            copy_from,
+            previous_contents: false,
         })
     }
 }
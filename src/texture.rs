@@ -0,0 +1,389 @@
+use crate::staging;
+use crate::{Filter, Sampling, TextureFormat, Wrap};
+use anyhow::{ensure, Result};
+use erupt::vk1_0 as vk;
+use gpu_alloc::UsageFlags as UF;
+use gpu_alloc_erupt::EruptMemoryDevice;
+use std::collections::HashMap;
+use vk_core::SharedCore;
+
+/// A sampled image in device-local memory, with a full mip chain where the format allows it
+pub struct Texture {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    memory: Option<gpu_alloc::MemoryBlock<vk::DeviceMemory>>,
+    prelude: SharedCore,
+}
+
+impl TextureFormat {
+    fn vk_format(self) -> vk::Format {
+        match self {
+            TextureFormat::Rgba8Srgb => vk::Format::R8G8B8A8_SRGB,
+            TextureFormat::Rgba8Unorm => vk::Format::R8G8B8A8_UNORM,
+            TextureFormat::R8Unorm => vk::Format::R8_UNORM,
+        }
+    }
+
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            TextureFormat::Rgba8Srgb | TextureFormat::Rgba8Unorm => 4,
+            TextureFormat::R8Unorm => 1,
+        }
+    }
+}
+
+impl Texture {
+    pub fn new(
+        prelude: SharedCore,
+        physical_device: vk::PhysicalDevice,
+        command_pool: vk::CommandPool,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: &[u8],
+    ) -> Result<Self> {
+        ensure!(width > 0 && height > 0, "Textures must not be empty");
+        ensure!(
+            data.len() == width as usize * height as usize * format.bytes_per_pixel(),
+            "Texture data is {} bytes, but a {}x{} {:?} texture needs {}",
+            data.len(),
+            width,
+            height,
+            format,
+            width as usize * height as usize * format.bytes_per_pixel()
+        );
+
+        // Mips are generated by blitting each level into the next, which not every format supports
+        let vk_format = format.vk_format();
+        let format_properties = unsafe {
+            prelude
+                .instance
+                .get_physical_device_format_properties(physical_device, vk_format)
+        };
+        let blit_features = vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+        let mip_levels = if format_properties
+            .optimal_tiling_features
+            .contains(blit_features)
+        {
+            32 - width.max(height).leading_zeros()
+        } else {
+            1
+        };
+
+        // Image
+        let create_info = vk::ImageCreateInfoBuilder::new()
+            .image_type(vk::ImageType::_2D)
+            .extent(vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .format(vk_format)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(
+                vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+            )
+            .samples(vk::SampleCountFlagBits::_1)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let image = unsafe { prelude.device.create_image(&create_info, None) }.result()?;
+
+        let requirements = unsafe { prelude.device.get_image_memory_requirements(image) };
+        let request = gpu_alloc::Request {
+            size: requirements.size,
+            align_mask: requirements.alignment,
+            usage: UF::FAST_DEVICE_ACCESS,
+            memory_types: requirements.memory_type_bits,
+        };
+        let memory = unsafe {
+            prelude
+                .allocator()?
+                .alloc(EruptMemoryDevice::wrap(&prelude.device), request)?
+        };
+        unsafe {
+            prelude
+                .device
+                .bind_image_memory(image, *memory.memory(), memory.offset())
+                .result()?;
+        }
+
+        // From here on, Drop cleans up after errors
+        let mut texture = Self {
+            image,
+            view: vk::ImageView::null(),
+            memory: Some(memory),
+            prelude,
+        };
+
+        texture.upload(command_pool, width, height, mip_levels, data)?;
+
+        // View
+        let create_info = vk::ImageViewCreateInfoBuilder::new()
+            .image(image)
+            .view_type(vk::ImageViewType::_2D)
+            .format(vk_format)
+            .subresource_range(
+                vk::ImageSubresourceRangeBuilder::new()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .base_mip_level(0)
+                    .level_count(mip_levels)
+                    .base_array_layer(0)
+                    .layer_count(1)
+                    .build(),
+            );
+        texture.view =
+            unsafe { texture.prelude.device.create_image_view(&create_info, None) }.result()?;
+
+        Ok(texture)
+    }
+
+    /// Copy `data` into mip level 0, then blit it down into the rest of the chain. Leaves every
+    /// level in SHADER_READ_ONLY_OPTIMAL.
+    fn upload(
+        &self,
+        command_pool: vk::CommandPool,
+        width: u32,
+        height: u32,
+        mip_levels: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let device = &self.prelude.device;
+        let image = self.image;
+        let barrier = |level: u32,
+                       old_layout: vk::ImageLayout,
+                       new_layout: vk::ImageLayout,
+                       src_access: vk::AccessFlags,
+                       dst_access: vk::AccessFlags| {
+            vk::ImageMemoryBarrierBuilder::new()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(
+                    vk::ImageSubresourceRangeBuilder::new()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .base_mip_level(level)
+                        .level_count(1)
+                        .base_array_layer(0)
+                        .layer_count(1)
+                        .build(),
+                )
+        };
+        let layers = |level: u32| {
+            vk::ImageSubresourceLayersBuilder::new()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(level)
+                .base_array_layer(0)
+                .layer_count(1)
+                .build()
+        };
+
+        staging::with_staging(
+            &self.prelude,
+            command_pool,
+            data,
+            |command_buffer, staging| unsafe {
+                // Every level starts out as a transfer destination
+                let to_transfer = (0..mip_levels)
+                    .map(|level| {
+                        barrier(
+                            level,
+                            vk::ImageLayout::UNDEFINED,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            vk::AccessFlags::empty(),
+                            vk::AccessFlags::TRANSFER_WRITE,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    None,
+                    &[],
+                    &[],
+                    &to_transfer,
+                );
+
+                let regions = [vk::BufferImageCopyBuilder::new()
+                    .buffer_offset(0)
+                    .buffer_row_length(0)
+                    .buffer_image_height(0)
+                    .image_subresource(layers(0))
+                    .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+                    .image_extent(vk::Extent3D {
+                        width,
+                        height,
+                        depth: 1,
+                    })];
+                device.cmd_copy_buffer_to_image(
+                    command_buffer,
+                    staging,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &regions,
+                );
+
+                // Each level is blitted from the one before it, which is then done with
+                let mip_extent = |level: u32| vk::Offset3D {
+                    x: (width >> level).max(1) as i32,
+                    y: (height >> level).max(1) as i32,
+                    z: 1,
+                };
+                for level in 1..mip_levels {
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::TRANSFER,
+                        None,
+                        &[],
+                        &[],
+                        &[barrier(
+                            level - 1,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                            vk::AccessFlags::TRANSFER_WRITE,
+                            vk::AccessFlags::TRANSFER_READ,
+                        )],
+                    );
+
+                    let origin = vk::Offset3D { x: 0, y: 0, z: 0 };
+                    let blits = [vk::ImageBlitBuilder::new()
+                        .src_subresource(layers(level - 1))
+                        .src_offsets([origin, mip_extent(level - 1)])
+                        .dst_subresource(layers(level))
+                        .dst_offsets([origin, mip_extent(level)])];
+                    device.cmd_blit_image(
+                        command_buffer,
+                        image,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &blits,
+                        vk::Filter::LINEAR,
+                    );
+
+                    device.cmd_pipeline_barrier(
+                        command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::VERTEX_SHADER
+                            | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                        None,
+                        &[],
+                        &[],
+                        &[barrier(
+                            level - 1,
+                            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                            vk::AccessFlags::TRANSFER_READ,
+                            vk::AccessFlags::SHADER_READ,
+                        )],
+                    );
+                }
+
+                // The last level was only ever written to
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    None,
+                    &[],
+                    &[],
+                    &[barrier(
+                        mip_levels - 1,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        vk::AccessFlags::TRANSFER_WRITE,
+                        vk::AccessFlags::SHADER_READ,
+                    )],
+                );
+            },
+        )
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe {
+            self.prelude
+                .device
+                .destroy_image_view(Some(self.view), None);
+            self.prelude.device.destroy_image(Some(self.image), None);
+            if let Some(memory) = self.memory.take() {
+                self.prelude
+                    .allocator()
+                    .unwrap()
+                    .dealloc(EruptMemoryDevice::wrap(&self.prelude.device), memory);
+            }
+        }
+    }
+}
+
+/// Samplers are created on first use and shared between all textures with the same sampling
+pub struct Samplers {
+    samplers: HashMap<Sampling, vk::Sampler>,
+    prelude: SharedCore,
+}
+
+impl Samplers {
+    pub fn new(prelude: SharedCore) -> Self {
+        Self {
+            samplers: HashMap::new(),
+            prelude,
+        }
+    }
+
+    pub fn get(&mut self, sampling: Sampling) -> Result<vk::Sampler> {
+        if let Some(sampler) = self.samplers.get(&sampling) {
+            return Ok(*sampler);
+        }
+
+        let (filter, mipmap_mode) = match sampling.filter {
+            Filter::Nearest => (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST),
+            Filter::Linear => (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR),
+        };
+        let address_mode = match sampling.wrap {
+            Wrap::Repeat => vk::SamplerAddressMode::REPEAT,
+            Wrap::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+            Wrap::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        };
+
+        let create_info = vk::SamplerCreateInfoBuilder::new()
+            .mag_filter(filter)
+            .min_filter(filter)
+            .mipmap_mode(mipmap_mode)
+            .address_mode_u(address_mode)
+            .address_mode_v(address_mode)
+            .address_mode_w(address_mode)
+            .mip_lod_bias(0.0)
+            .anisotropy_enable(false)
+            .compare_enable(false)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .border_color(vk::BorderColor::FLOAT_TRANSPARENT_BLACK)
+            .unnormalized_coordinates(false);
+        let sampler = unsafe { self.prelude.device.create_sampler(&create_info, None) }.result()?;
+
+        self.samplers.insert(sampling, sampler);
+        Ok(sampler)
+    }
+}
+
+impl Drop for Samplers {
+    fn drop(&mut self) {
+        for (_, sampler) in self.samplers.drain() {
+            unsafe {
+                self.prelude.device.destroy_sampler(Some(sampler), None);
+            }
+        }
+    }
+}
@@ -2,6 +2,161 @@ use bytemuck::offset_of;
 use erupt::vk1_0 as vk;
 use nalgebra::Point3;
 
+/// Format of a single vertex attribute, as seen by vertex shaders
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VertexFormat {
+    /// `float`
+    Float,
+    /// `vec2`
+    Vec2,
+    /// `vec3`
+    Vec3,
+    /// `vec4`
+    Vec4,
+    /// `uint`
+    UInt,
+    /// `vec4`, from four normalized bytes
+    Rgba8Unorm,
+}
+
+impl VertexFormat {
+    /// Size in bytes
+    pub fn size(self) -> u32 {
+        match self {
+            VertexFormat::Float | VertexFormat::UInt | VertexFormat::Rgba8Unorm => 4,
+            VertexFormat::Vec2 => 8,
+            VertexFormat::Vec3 => 12,
+            VertexFormat::Vec4 => 16,
+        }
+    }
+
+    pub(crate) fn vk_format(self) -> vk::Format {
+        match self {
+            VertexFormat::Float => vk::Format::R32_SFLOAT,
+            VertexFormat::Vec2 => vk::Format::R32G32_SFLOAT,
+            VertexFormat::Vec3 => vk::Format::R32G32B32_SFLOAT,
+            VertexFormat::Vec4 => vk::Format::R32G32B32A32_SFLOAT,
+            VertexFormat::UInt => vk::Format::R32_UINT,
+            VertexFormat::Rgba8Unorm => vk::Format::R8G8B8A8_UNORM,
+        }
+    }
+}
+
+/// A vertex attribute, read by vertex shaders at `layout(location = ...)`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub format: VertexFormat,
+    /// Offset in bytes from the start of the vertex
+    pub offset: u32,
+}
+
+impl VertexAttribute {
+    /// Whether the attribute lies within a vertex of `stride` bytes
+    pub fn fits_in(&self, stride: u32) -> bool {
+        self.offset
+            .checked_add(self.format.size())
+            .is_some_and(|end| end <= stride)
+    }
+}
+
+/// Memory layout of a vertex type. Meshes and materials each have a layout, and only matching
+/// pairs are drawn.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    /// Size of one vertex in bytes
+    pub stride: u32,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    /// Check that every attribute lies within the stride, and that locations aren't repeated
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (idx, attribute) in self.attributes.iter().enumerate() {
+            anyhow::ensure!(
+                attribute.fits_in(self.stride),
+                "Vertex attribute at location {} extends past the end of the vertex",
+                attribute.location
+            );
+            anyhow::ensure!(
+                self.attributes[..idx]
+                    .iter()
+                    .all(|other| other.location != attribute.location),
+                "Vertex attribute location {} is used more than once",
+                attribute.location
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn binding_description(&self) -> vk::VertexInputBindingDescriptionBuilder<'static> {
+        vk::VertexInputBindingDescriptionBuilder::new()
+            .binding(0)
+            .stride(self.stride)
+            .input_rate(vk::VertexInputRate::VERTEX)
+    }
+
+    pub(crate) fn attribute_descriptions(
+        &self,
+    ) -> Vec<vk::VertexInputAttributeDescriptionBuilder<'static>> {
+        self.attributes
+            .iter()
+            .map(|attribute| {
+                vk::VertexInputAttributeDescriptionBuilder::new()
+                    .binding(0)
+                    .location(attribute.location)
+                    .format(attribute.format.vk_format())
+                    .offset(attribute.offset)
+            })
+            .collect()
+    }
+}
+
+impl Default for VertexLayout {
+    fn default() -> Self {
+        Vertex::layout()
+    }
+}
+
+/// A type which can be used as mesh vertices. For example:
+/// ```
+/// use klystron::{VertexAttribute, VertexFormat, VertexLayout, VertexType};
+/// use bytemuck::offset_of;
+///
+/// #[repr(C)]
+/// #[derive(Copy, Clone, Default)]
+/// struct Sample {
+///     pos: [f32; 2],
+///     value: f32,
+/// }
+///
+/// unsafe impl bytemuck::Zeroable for Sample {}
+/// unsafe impl bytemuck::Pod for Sample {}
+///
+/// impl VertexType for Sample {
+///     fn layout() -> VertexLayout {
+///         VertexLayout {
+///             stride: std::mem::size_of::<Self>() as u32,
+///             attributes: vec![
+///                 VertexAttribute {
+///                     location: 0,
+///                     format: VertexFormat::Vec2,
+///                     offset: offset_of!(Self, pos) as u32,
+///                 },
+///                 VertexAttribute {
+///                     location: 1,
+///                     format: VertexFormat::Float,
+///                     offset: offset_of!(Self, value) as u32,
+///                 },
+///             ],
+///         }
+///     }
+/// }
+/// ```
+pub trait VertexType: bytemuck::Pod {
+    fn layout() -> VertexLayout;
+}
+
 /// Vertex suitable for use from vertex shaders
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
@@ -13,6 +168,26 @@ pub struct Vertex {
 unsafe impl bytemuck::Zeroable for Vertex {}
 unsafe impl bytemuck::Pod for Vertex {}
 
+impl VertexType for Vertex {
+    fn layout() -> VertexLayout {
+        VertexLayout {
+            stride: std::mem::size_of::<Self>() as u32,
+            attributes: vec![
+                VertexAttribute {
+                    location: 0,
+                    format: VertexFormat::Vec3,
+                    offset: offset_of!(Self, pos) as u32,
+                },
+                VertexAttribute {
+                    location: 1,
+                    format: VertexFormat::Vec3,
+                    offset: offset_of!(Self, color) as u32,
+                },
+            ],
+        }
+    }
+}
+
 impl Vertex {
     pub fn new(pos: [f32; 3], color: [f32; 3]) -> Self {
         Self {
@@ -25,27 +200,4 @@ impl Vertex {
             color: *color.coords.as_ref(),
         }
     }
-
-    pub fn binding_description() -> vk::VertexInputBindingDescriptionBuilder<'static> {
-        vk::VertexInputBindingDescriptionBuilder::new()
-            .binding(0)
-            .stride(std::mem::size_of::<Self>() as u32)
-            .input_rate(vk::VertexInputRate::VERTEX)
-    }
-
-    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescriptionBuilder<'static>; 2]
-    {
-        [
-            vk::VertexInputAttributeDescriptionBuilder::new()
-                .binding(0)
-                .location(0)
-                .format(vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(Self, pos) as u32),
-            vk::VertexInputAttributeDescriptionBuilder::new()
-                .binding(0)
-                .location(1)
-                .format(vk::Format::R32G32B32_SFLOAT)
-                .offset(offset_of!(Self, color) as u32),
-        ]
-    }
 }
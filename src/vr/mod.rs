@@ -1,12 +1,16 @@
 use vk_core::SharedCore;
-use crate::core::Core;
-use crate::swapchain_images::SwapchainImages;
-use crate::{DrawType, Engine, FramePacket, Material, Mesh, Vertex};
+use crate::core::{Core, RenderTarget};
+use crate::{
+    ComputeMaterial, Engine, EnvironmentBlend, FramePacket, FrameStats, Indices, Material,
+    MaterialDesc, Mesh, MeshUsage, ObjectParams, PostEffect, StorageBuffer, Texture,
+    TextureBinding, TextureFormat, VertexLayout,
+};
 use anyhow::{bail, ensure, Context, Result};
 use erupt::{vk1_0 as vk, DeviceLoader, EntryLoader, InstanceLoader};
 use log::info;
 use nalgebra::{Matrix4, Unit, Vector3};
 use std::ffi::{CString, CStr};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use gpu_alloc::{self, GpuAllocator};
 
@@ -16,6 +20,8 @@ pub struct OpenXrBackend {
     frame_stream: xr::FrameStream<xr::Vulkan>,
     stage: xr::Space,
     swapchain: Option<xr::Swapchain<xr::Vulkan>>,
+    /// Environment blend modes supported by the runtime, in its order of preference
+    blend_modes: Vec<xr::EnvironmentBlendMode>,
     openxr: Arc<XrPrelude>,
     prelude: SharedCore,
     core: Core,
@@ -164,8 +170,11 @@ impl OpenXrBackend {
         let queues = [vk::DeviceQueueCreateInfoBuilder::new()
             .queue_family_index(queue_family_index)
             .queue_priorities(&priorities)];
+        let physical_device_features =
+            crate::extensions::device_features(&vk_instance, vk_physical_device);
         let mut create_info = vk::DeviceCreateInfoBuilder::new()
             .queue_create_infos(&queues)
+            .enabled_features(&physical_device_features)
             .enabled_layer_names(&vk_device_layers_ptrs)
             .enabled_extension_names(&vk_device_ext_ptrs)
             .build();
@@ -235,7 +244,10 @@ impl OpenXrBackend {
             queue_family_index,
         };
 
-        let core = Core::new(prelude.clone(), meta, true)?;
+        let core = Core::new(prelude.clone(), meta, RenderTarget::Vr)?;
+
+        let blend_modes = xr_instance
+            .enumerate_environment_blend_modes(system, xr::ViewConfigurationType::PRIMARY_STEREO)?;
 
         let openxr = Arc::new(XrPrelude {
             instance: xr_instance,
@@ -248,6 +260,7 @@ impl OpenXrBackend {
             frame_stream,
             stage,
             swapchain: None,
+            blend_modes,
             openxr: openxr.clone(),
             prelude,
             core,
@@ -263,15 +276,21 @@ impl OpenXrBackend {
         let xr_frame_state = self.frame_wait.wait()?;
         self.frame_stream.begin()?;
 
+        let blend_mode = self.environment_blend_mode(packet.environment_blend);
+
         if !xr_frame_state.should_render {
-            self.frame_stream.end(
-                xr_frame_state.predicted_display_time,
-                xr::EnvironmentBlendMode::OPAQUE,
-                &[],
-            )?;
+            self.frame_stream
+                .end(xr_frame_state.predicted_display_time, blend_mode, &[])?;
             return Ok(());
         }
 
+        self.core.prepare_background(packet.background)?;
+
+        // The core frees its attachments whenever its configuration changes
+        if self.core.swapchain_images.is_none() {
+            self.swapchain = None;
+        }
+
         if self.swapchain.is_none() {
             self.recreate_swapchain()?;
         }
@@ -293,9 +312,6 @@ impl OpenXrBackend {
                 .next_image(image_index, &frame)?
         };
 
-        // Write command buffers
-        let command_buffer = self.core.write_command_buffers(frame_idx, packet, &image)?;
-
         // Get views
         let (_, views) = self.openxr.session.locate_views(
             xr::ViewConfigurationType::PRIMARY_STEREO,
@@ -305,6 +321,11 @@ impl OpenXrBackend {
 
         let left = matrix_from_view(&views[0]);
         let right = matrix_from_view(&views[1]);
+
+        // Write command buffers, sorting transparent objects for both eyes
+        let command_buffer =
+            self.core
+                .write_command_buffers(frame_idx, packet, &image, &[left, right])?;
         let both = left.iter().chain(right.iter()).copied().collect::<Vec<_>>();
         let mut data = [0.0; 32];
         data.copy_from_slice(&both);
@@ -341,7 +362,7 @@ impl OpenXrBackend {
         };
         self.frame_stream.end(
             xr_frame_state.predicted_display_time,
-            xr::EnvironmentBlendMode::OPAQUE,
+            blend_mode,
             &[&xr::CompositionLayerProjection::new()
                 .space(&self.stage)
                 .views(&[
@@ -387,13 +408,21 @@ impl OpenXrBackend {
             height: views[0].recommended_image_rect_height,
         };
 
+        let mut usage_flags =
+            xr::SwapchainUsageFlags::COLOR_ATTACHMENT | xr::SwapchainUsageFlags::SAMPLED;
+
+        // Without MSAA or post-processing, a persistent color image is copied into the swapchain
+        // images
+        if self.core.config.copy_to_target() {
+            usage_flags |= xr::SwapchainUsageFlags::TRANSFER_DST;
+        }
+
         let swapchain = self
             .openxr
             .session
             .create_swapchain(&xr::SwapchainCreateInfo {
                 create_flags: xr::SwapchainCreateFlags::EMPTY,
-                usage_flags: xr::SwapchainUsageFlags::COLOR_ATTACHMENT
-                    | xr::SwapchainUsageFlags::SAMPLED,
+                usage_flags,
                 format: crate::core::COLOR_FORMAT.0 as _,
                 sample_count: 1,
                 width: extent.width,
@@ -413,15 +442,24 @@ impl OpenXrBackend {
         // TODO: Coagulate these two into one object?
         self.swapchain = Some(swapchain);
 
-        self.core.swapchain_images = Some(SwapchainImages::new(
-            self.prelude.clone(),
-            extent,
-            self.core.render_pass,
-            swapchain_images,
-            true,
-        )?);
+        self.core.create_swapchain_images(extent, swapchain_images)
+    }
 
-        Ok(())
+    /// The runtime's equivalent of `blend`, or its preferred mode if `blend` is unsupported
+    fn environment_blend_mode(&self, blend: EnvironmentBlend) -> xr::EnvironmentBlendMode {
+        let mode = match blend {
+            EnvironmentBlend::Opaque => xr::EnvironmentBlendMode::OPAQUE,
+            EnvironmentBlend::Additive => xr::EnvironmentBlendMode::ADDITIVE,
+            EnvironmentBlend::AlphaBlend => xr::EnvironmentBlendMode::ALPHA_BLEND,
+        };
+        if self.blend_modes.contains(&mode) {
+            mode
+        } else {
+            self.blend_modes
+                .first()
+                .copied()
+                .unwrap_or(xr::EnvironmentBlendMode::OPAQUE)
+        }
     }
 }
 
@@ -431,12 +469,66 @@ impl Engine for OpenXrBackend {
         &mut self,
         vertex: &[u8],
         fragment: &[u8],
-        draw_type: DrawType,
+        desc: MaterialDesc,
     ) -> Result<Material> {
-        self.core.add_material(vertex, fragment, draw_type)
+        self.core.add_material(vertex, fragment, desc)
+    }
+    fn add_material_from_files(
+        &mut self,
+        vertex: &Path,
+        fragment: &Path,
+        desc: MaterialDesc,
+    ) -> Result<Material> {
+        self.core.add_material_from_files(vertex, fragment, desc)
+    }
+    fn add_mesh_raw(
+        &mut self,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+        usage: MeshUsage,
+    ) -> Result<Mesh> {
+        self.core.add_mesh(layout, vertices, indices, usage)
+    }
+    fn update_mesh_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<()> {
+        self.core.update_mesh(mesh, layout, vertices, indices)
+    }
+    fn update_mesh_vertices_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        first_vertex: usize,
+        vertices: &[u8],
+    ) -> Result<()> {
+        self.core.update_mesh_vertices(mesh, layout, first_vertex, vertices)
+    }
+    fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: &[u8],
+    ) -> Result<Texture> {
+        self.core.add_texture(width, height, format, data)
     }
-    fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u16]) -> Result<Mesh> {
-        self.core.add_mesh(vertices, indices)
+    fn remove_texture(&mut self, texture: Texture) -> Result<()> {
+        self.core.remove_texture(texture)
+    }
+    fn set_material_textures(
+        &mut self,
+        material: Material,
+        textures: &[TextureBinding],
+    ) -> Result<()> {
+        self.core.set_material_textures(material, textures)
+    }
+    fn set_material_params(&mut self, material: Material, data: &[u8]) -> Result<()> {
+        self.core.set_material_params(material, data)
     }
     fn remove_material(&mut self, material: Material) -> Result<()> {
         self.core.remove_material(material)
@@ -447,6 +539,64 @@ impl Engine for OpenXrBackend {
     fn update_time_value(&mut self, data: f32) -> Result<()> {
         self.core.update_time_value(data)
     }
+    fn set_msaa_samples(&mut self, samples: u32) -> Result<()> {
+        self.core.set_msaa_samples(samples)
+    }
+    fn max_msaa_samples(&self) -> u32 {
+        self.core.max_msaa_samples()
+    }
+    fn frame_stats(&self) -> FrameStats {
+        self.core.stats
+    }
+    fn set_recording_threads(&mut self, threads: usize) -> Result<()> {
+        self.core.set_recording_threads(threads)
+    }
+    fn set_draw_batching(&mut self, enabled: bool) {
+        self.core.batch_draws = enabled;
+    }
+    fn add_post_effect(&mut self, fragment: &[u8]) -> Result<PostEffect> {
+        self.core.add_post_effect(fragment)
+    }
+    fn set_post_effect_params(&mut self, effect: PostEffect, params: ObjectParams) -> Result<()> {
+        self.core.set_post_effect_params(effect, params)
+    }
+    fn remove_post_effect(&mut self, effect: PostEffect) -> Result<()> {
+        self.core.remove_post_effect(effect)
+    }
+
+    fn add_compute_material(&mut self, compute: &[u8]) -> Result<ComputeMaterial> {
+        self.core.add_compute_material(compute)
+    }
+
+    fn remove_compute_material(&mut self, material: ComputeMaterial) -> Result<()> {
+        self.core.remove_compute_material(material)
+    }
+
+    fn add_storage_buffer(&mut self, data: &[u8]) -> Result<StorageBuffer> {
+        self.core.add_storage_buffer(data)
+    }
+
+    fn update_storage_buffer(
+        &mut self,
+        buffer: StorageBuffer,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        self.core.update_storage_buffer(buffer, offset, data)
+    }
+
+    fn remove_storage_buffer(&mut self, buffer: StorageBuffer) -> Result<()> {
+        self.core.remove_storage_buffer(buffer)
+    }
+
+    fn add_storage_mesh(
+        &mut self,
+        layout: &VertexLayout,
+        buffer: StorageBuffer,
+        indices: Indices,
+    ) -> Result<Mesh> {
+        self.core.add_storage_mesh(layout, buffer, indices)
+    }
 }
 
 fn matrix_from_view(view: &xr::View) -> Matrix4<f32> {
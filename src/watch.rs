@@ -0,0 +1,135 @@
+use crate::compile::{compile_shader, ShaderLanguage, ShaderSource, ShaderStage};
+use anyhow::{Context, Result};
+use slotmap::SecondaryMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often shader files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shader files a material was loaded from, and when they were last seen to change
+struct ShaderFiles {
+    vertex: PathBuf,
+    fragment: PathBuf,
+    modified: [Option<SystemTime>; 2],
+}
+
+/// Watches the shader files behind file-backed materials
+pub struct ShaderWatcher {
+    materials: SecondaryMap<crate::Material, ShaderFiles>,
+    last_poll: Instant,
+}
+
+/// New SPIR-V for a material whose shader files changed
+pub struct Reload {
+    pub material: crate::Material,
+    pub vertex: Vec<u8>,
+    pub fragment: Vec<u8>,
+}
+
+impl Default for ShaderWatcher {
+    fn default() -> Self {
+        Self {
+            materials: SecondaryMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+}
+
+impl ShaderWatcher {
+    /// Watch the files a material was just loaded from
+    pub fn watch(&mut self, material: crate::Material, vertex: &Path, fragment: &Path) {
+        self.materials.insert(
+            material,
+            ShaderFiles {
+                vertex: vertex.to_path_buf(),
+                fragment: fragment.to_path_buf(),
+                modified: [modified(vertex), modified(fragment)],
+            },
+        );
+    }
+
+    pub fn unwatch(&mut self, material: crate::Material) {
+        self.materials.remove(material);
+    }
+
+    /// Load the shaders of every material whose files changed since the last poll. Shaders which
+    /// fail to load are logged and skipped until their files change again.
+    pub fn poll(&mut self) -> Vec<Reload> {
+        if self.materials.is_empty() || self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut reloads = Vec::new();
+        for (material, files) in &mut self.materials {
+            let now = [modified(&files.vertex), modified(&files.fragment)];
+            if now == files.modified {
+                continue;
+            }
+            files.modified = now;
+
+            match (
+                load_shader(&files.vertex, ShaderStage::Vertex),
+                load_shader(&files.fragment, ShaderStage::Fragment),
+            ) {
+                (Ok(vertex), Ok(fragment)) => reloads.push(Reload {
+                    material,
+                    vertex,
+                    fragment,
+                }),
+                (Err(e), _) | (_, Err(e)) => log::error!("Keeping the previous shaders: {:#}", e),
+            }
+        }
+        reloads
+    }
+}
+
+/// Load SPIR-V for the given stage from a file. `.spv` files are read as they are, `.wgsl` files
+/// are compiled as WGSL, and anything else as GLSL.
+pub fn load_shader(path: &Path, stage: ShaderStage) -> Result<Vec<u8>> {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    if extension == Some("spv") {
+        return std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()));
+    }
+
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let name = path.display().to_string();
+    let language = match extension {
+        Some("wgsl") => ShaderLanguage::Wgsl,
+        _ => ShaderLanguage::Glsl,
+    };
+    compile_shader(
+        ShaderSource {
+            name: &name,
+            language,
+            source: &source,
+        },
+        stage,
+    )
+}
+
+/// Modification time of a file, if it can be read. Files which are briefly missing while an
+/// editor replaces them count as changed once they reappear.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shaders_including_the_header_load() {
+        let shaders = Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders");
+        for file in ["instanced.vert", "scalar.vert", "unlit.vert"].iter() {
+            if let Err(e) = load_shader(&shaders.join(file), ShaderStage::Vertex) {
+                panic!("{:#}", e);
+            }
+        }
+
+        let spirv = load_shader(&shaders.join("unlit.vert.spv"), ShaderStage::Vertex).unwrap();
+        assert_eq!(spirv, crate::UNLIT_VERT);
+    }
+}
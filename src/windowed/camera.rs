@@ -5,6 +5,7 @@ pub trait Camera {
 }
 
 /// An arcball camera
+#[derive(Clone, Debug)]
 pub struct PerspectiveCamera {
     pub pivot: Point3<f32>,
     pub distance: f32,
@@ -1,10 +1,15 @@
 mod camera;
-use crate::core::Core;
+use crate::core::{Core, RenderTarget};
 use vk_core::SharedCore;
+use crate::frame_sync::Frame;
 use crate::hardware_query::HardwareSelection;
-use crate::swapchain_images::SwapchainImages;
-use crate::{DrawType, Engine, FramePacket, Material, Mesh, Vertex};
-use anyhow::Result;
+use crate::readback::{Capture, Readback};
+use crate::{
+    ComputeMaterial, Engine, FramePacket, FrameStats, Indices, Material, MaterialDesc, Mesh,
+    MeshUsage, ObjectParams, PostEffect, StorageBuffer, Texture, TextureBinding, TextureFormat,
+    VertexLayout,
+};
+use anyhow::{ensure, Result};
 pub use camera::*;
 use erupt::{
     extensions::{khr_surface, khr_swapchain},
@@ -12,6 +17,7 @@ use erupt::{
     vk1_0 as vk, DeviceLoader, EntryLoader, InstanceLoader,
 };
 use std::ffi::CString;
+use std::path::Path;
 use winit::window::Window;
 use std::sync::Mutex;
 use gpu_alloc::GpuAllocator;
@@ -22,6 +28,12 @@ pub struct WinitBackend {
     image_available_semaphores: Vec<vk::Semaphore>,
     surface: khr_surface::SurfaceKHR,
     hardware: HardwareSelection,
+    readback: Readback,
+    /// Whether the swapchain images can be copied from, which captures require
+    capture_supported: bool,
+    capture_requested: bool,
+    /// Frame whose image was copied for capture, but not yet read
+    pending_capture: Option<(usize, Frame)>,
     prelude: SharedCore,
     core: Core,
 }
@@ -76,7 +88,8 @@ impl WinitBackend {
             .queue_family_index(hardware.queue_family)
             .queue_priorities(&[1.0])];
 
-        let physical_device_features = vk::PhysicalDeviceFeaturesBuilder::new();
+        let physical_device_features =
+            crate::extensions::device_features(&instance, hardware.physical_device);
         let create_info = vk::DeviceCreateInfoBuilder::new()
             .queue_create_infos(&create_info)
             .enabled_features(&physical_device_features)
@@ -106,7 +119,9 @@ impl WinitBackend {
             physical_device: hardware.physical_device,
         };
 
-        let core = Core::new(prelude.clone(), meta, false)?;
+        let core = Core::new(prelude.clone(), meta, RenderTarget::Windowed)?;
+
+        let readback = Readback::new(prelude.clone(), core.command_pool)?;
 
         let image_available_semaphores = (0..crate::core::FRAMES_IN_FLIGHT)
             .map(|_| {
@@ -125,14 +140,47 @@ impl WinitBackend {
             image_available_semaphores,
             hardware,
             surface,
+            readback,
+            capture_supported: false,
+            capture_requested: false,
+            pending_capture: None,
             prelude,
             core,
         })
     }
 
+    /// Request a capture of the next frame presented. Retrieve it with `take_capture()`
+    /// after the next call to `next_frame()`.
+    pub fn request_capture(&mut self) {
+        self.capture_requested = true;
+    }
+
+    /// Wait for the frame requested by `request_capture()` to finish rendering, and return its
+    /// contents. Returns `None` if no capture has been rendered since the last call.
+    pub fn take_capture(&mut self) -> Result<Option<Capture>> {
+        let (frame_idx, frame) = match self.pending_capture.take() {
+            Some(pending) => pending,
+            None => return Ok(None),
+        };
+        unsafe {
+            self.prelude
+                .device
+                .wait_for_fences(&[frame.in_flight_fence], true, u64::MAX)
+                .result()?;
+        }
+        Ok(Some(self.readback.read(frame_idx)?))
+    }
+
     // TODO: camera position should be driven by something external
     // Winit keypresses used to move camera.
     pub fn next_frame(&mut self, packet: &FramePacket, camera: &dyn camera::Camera) -> Result<()> {
+        self.core.prepare_background(packet.background)?;
+
+        // The core frees its attachments whenever its configuration changes
+        if self.core.swapchain_images.is_none() {
+            self.free_swapchain()?;
+        }
+
         if self.swapchain.is_none() {
             self.create_swapchain()?;
         }
@@ -168,27 +216,43 @@ impl WinitBackend {
         };
 
         // Write command buffers
-        let command_buffer = self.core.write_command_buffers(frame_idx, packet, &image)?;
+        let camera_matrix = camera.matrix(image.extent.width, image.extent.height);
+        let command_buffer =
+            self.core
+                .write_command_buffers(frame_idx, packet, &image, &[camera_matrix])?;
+        let mut command_buffers = vec![command_buffer];
+
+        // Copy the image out after rendering, if a capture was requested
+        if self.capture_requested && !self.capture_supported {
+            log::error!("Swapchain images on this surface cannot be used as a transfer source");
+            self.capture_requested = false;
+        }
+        let capture = self.capture_requested;
+        if capture {
+            command_buffers.push(self.readback.record(
+                frame_idx,
+                &image,
+                RenderTarget::Windowed.final_layout(),
+                crate::core::COLOR_FORMAT,
+            )?);
+        }
 
         // Upload camera matrix and time
         let mut data = [0.0; 32];
         data.iter_mut()
-            .zip(
-                camera
-                    .matrix(image.extent.width, image.extent.height)
-                    .as_slice()
-                    .iter(),
-            )
+            .zip(camera_matrix.as_slice().iter())
             .for_each(|(o, i)| *o = *i);
         self.core.update_camera_data(frame_idx, &data)?;
 
         // Submit to the queue
-        let command_buffers = [command_buffer];
         let wait_semaphores = [image_available];
+        // Copies from a persistent color image write the swapchain image in the transfer stage
+        let wait_stages =
+            [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER];
         let signal_semaphores = [frame.render_finished];
         let submit_info = vk::SubmitInfoBuilder::new()
             .wait_semaphores(&wait_semaphores)
-            .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
+            .wait_dst_stage_mask(&wait_stages)
             .command_buffers(&command_buffers)
             .signal_semaphores(&signal_semaphores);
         unsafe {
@@ -206,6 +270,11 @@ impl WinitBackend {
                 .result()?;
         }
 
+        if capture {
+            self.capture_requested = false;
+            self.pending_capture = Some((frame_idx, frame));
+        }
+
         // Present to swapchain
         let swapchains = [swapchain];
         let image_indices = [image_index];
@@ -253,6 +322,27 @@ impl WinitBackend {
         }
         .result()?;
 
+        // Captures copy straight out of the swapchain images
+        self.capture_supported = surface_caps
+            .supported_usage_flags
+            .contains(vk::ImageUsageFlags::TRANSFER_SRC);
+        let mut image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+        if self.capture_supported {
+            image_usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
+
+        // Without MSAA or post-processing, a persistent color image is copied into the swapchain
+        // images
+        if self.core.config.copy_to_target() {
+            ensure!(
+                surface_caps
+                    .supported_usage_flags
+                    .contains(vk::ImageUsageFlags::TRANSFER_DST),
+                "This surface can't keep the previous frame without MSAA"
+            );
+            image_usage |= vk::ImageUsageFlags::TRANSFER_DST;
+        }
+
         let mut image_count = surface_caps.min_image_count + 1;
         if surface_caps.max_image_count > 0 && image_count > surface_caps.max_image_count {
             image_count = surface_caps.max_image_count;
@@ -266,7 +356,7 @@ impl WinitBackend {
             .image_color_space(self.hardware.format.color_space)
             .image_extent(surface_caps.current_extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(image_usage)
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .pre_transform(surface_caps.current_transform)
             .composite_alpha(khr_surface::CompositeAlphaFlagBitsKHR::OPAQUE_KHR)
@@ -292,15 +382,8 @@ impl WinitBackend {
         // TODO: Coagulate these two into one object?
         self.swapchain = Some(swapchain);
 
-        self.core.swapchain_images = Some(SwapchainImages::new(
-            self.prelude.clone(),
-            surface_caps.current_extent,
-            self.core.render_pass,
-            swapchain_images,
-            false,
-        )?);
-
-        Ok(())
+        self.core
+            .create_swapchain_images(surface_caps.current_extent, swapchain_images)
     }
 }
 
@@ -310,12 +393,66 @@ impl Engine for WinitBackend {
         &mut self,
         vertex: &[u8],
         fragment: &[u8],
-        draw_type: DrawType,
+        desc: MaterialDesc,
     ) -> Result<Material> {
-        self.core.add_material(vertex, fragment, draw_type)
+        self.core.add_material(vertex, fragment, desc)
     }
-    fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u16]) -> Result<Mesh> {
-        self.core.add_mesh(vertices, indices)
+    fn add_material_from_files(
+        &mut self,
+        vertex: &Path,
+        fragment: &Path,
+        desc: MaterialDesc,
+    ) -> Result<Material> {
+        self.core.add_material_from_files(vertex, fragment, desc)
+    }
+    fn add_mesh_raw(
+        &mut self,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+        usage: MeshUsage,
+    ) -> Result<Mesh> {
+        self.core.add_mesh(layout, vertices, indices, usage)
+    }
+    fn update_mesh_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        vertices: &[u8],
+        indices: Indices,
+    ) -> Result<()> {
+        self.core.update_mesh(mesh, layout, vertices, indices)
+    }
+    fn update_mesh_vertices_raw(
+        &mut self,
+        mesh: Mesh,
+        layout: &VertexLayout,
+        first_vertex: usize,
+        vertices: &[u8],
+    ) -> Result<()> {
+        self.core.update_mesh_vertices(mesh, layout, first_vertex, vertices)
+    }
+    fn add_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: &[u8],
+    ) -> Result<Texture> {
+        self.core.add_texture(width, height, format, data)
+    }
+    fn remove_texture(&mut self, texture: Texture) -> Result<()> {
+        self.core.remove_texture(texture)
+    }
+    fn set_material_textures(
+        &mut self,
+        material: Material,
+        textures: &[TextureBinding],
+    ) -> Result<()> {
+        self.core.set_material_textures(material, textures)
+    }
+    fn set_material_params(&mut self, material: Material, data: &[u8]) -> Result<()> {
+        self.core.set_material_params(material, data)
     }
     fn remove_material(&mut self, material: Material) -> Result<()> {
         self.core.remove_material(material)
@@ -326,6 +463,64 @@ impl Engine for WinitBackend {
     fn update_time_value(&mut self, data: f32) -> Result<()> {
         self.core.update_time_value(data)
     }
+    fn set_msaa_samples(&mut self, samples: u32) -> Result<()> {
+        self.core.set_msaa_samples(samples)
+    }
+    fn max_msaa_samples(&self) -> u32 {
+        self.core.max_msaa_samples()
+    }
+    fn frame_stats(&self) -> FrameStats {
+        self.core.stats
+    }
+    fn set_recording_threads(&mut self, threads: usize) -> Result<()> {
+        self.core.set_recording_threads(threads)
+    }
+    fn set_draw_batching(&mut self, enabled: bool) {
+        self.core.batch_draws = enabled;
+    }
+    fn add_post_effect(&mut self, fragment: &[u8]) -> Result<PostEffect> {
+        self.core.add_post_effect(fragment)
+    }
+    fn set_post_effect_params(&mut self, effect: PostEffect, params: ObjectParams) -> Result<()> {
+        self.core.set_post_effect_params(effect, params)
+    }
+    fn remove_post_effect(&mut self, effect: PostEffect) -> Result<()> {
+        self.core.remove_post_effect(effect)
+    }
+
+    fn add_compute_material(&mut self, compute: &[u8]) -> Result<ComputeMaterial> {
+        self.core.add_compute_material(compute)
+    }
+
+    fn remove_compute_material(&mut self, material: ComputeMaterial) -> Result<()> {
+        self.core.remove_compute_material(material)
+    }
+
+    fn add_storage_buffer(&mut self, data: &[u8]) -> Result<StorageBuffer> {
+        self.core.add_storage_buffer(data)
+    }
+
+    fn update_storage_buffer(
+        &mut self,
+        buffer: StorageBuffer,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        self.core.update_storage_buffer(buffer, offset, data)
+    }
+
+    fn remove_storage_buffer(&mut self, buffer: StorageBuffer) -> Result<()> {
+        self.core.remove_storage_buffer(buffer)
+    }
+
+    fn add_storage_mesh(
+        &mut self,
+        layout: &VertexLayout,
+        buffer: StorageBuffer,
+        indices: Indices,
+    ) -> Result<Mesh> {
+        self.core.add_storage_mesh(layout, buffer, indices)
+    }
 }
 
 impl Drop for WinitBackend {